@@ -0,0 +1,26 @@
+use crate::pubsub::Subscriptions;
+use crate::transaction::{Propagation, Transaction};
+
+/// State kept for each connected client between commands.
+#[derive(Debug, Default)]
+pub struct Client {
+    /// The database selected with `SELECT`.
+    pub db: usize,
+    /// The port a replica says it listens on, sent with `REPLCONF listening-port`.
+    pub listening_port: Option<u16>,
+    /// Whether a replica can take a snapshot streamed without its length up
+    /// front, sent with `REPLCONF capa eof`.
+    pub capa_eof: bool,
+    /// Whether this is the link to our master, whose writes are passed on
+    /// to replicas as they came rather than as they are run.
+    pub master: bool,
+    /// What the client subscribed to with `SUBSCRIBE` and `PSUBSCRIBE`.
+    pub subscriptions: Subscriptions,
+    /// The replication offset right after the client's last write, which
+    /// `WAIT` waits for replicas to acknowledge.
+    pub woff: u64,
+    /// The commands queued since `MULTI`, while in a transaction.
+    pub transaction: Option<Transaction>,
+    /// How writes are passed on, which differs while `EXEC` runs.
+    pub propagation: Propagation,
+}
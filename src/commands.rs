@@ -0,0 +1,913 @@
+pub mod bitmap;
+pub mod geo;
+pub mod hyperloglog;
+pub mod keyspace;
+
+use std::time::UNIX_EPOCH;
+
+use crate::client::Client;
+use crate::message::{CommandError, RespMessage};
+use crate::parser::resp::{Array, BulkString, Value};
+use crate::persistence::parse_save_rules;
+use crate::pubsub::{notify, EventClass};
+use crate::replication;
+use crate::server::Server;
+use crate::store::{Databases, DurableValue, Expiration, Object, Store};
+use crate::transaction::{self, Propagation, Transaction};
+use crate::CONFIG;
+
+fn db_index(databases: &Databases, index: usize) -> Result<usize, Value> {
+    if index < databases.len() {
+        Ok(index)
+    } else {
+        Err(CommandError::Invalid("DB index is out of range".into()).into())
+    }
+}
+
+/// How a write command is logged: as it was sent, except for relative
+/// expirations which are made absolute so replaying them later is exact.
+fn propagated(message: &RespMessage, raw: &[u8]) -> Vec<u8> {
+    match message {
+        RespMessage::Set {
+            key,
+            val,
+            expiration,
+        } => match expiration.deadline() {
+            Some(deadline) => {
+                let millis = deadline
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let command: Value = vec![
+                    BulkString::from("SET").into(),
+                    BulkString::from(key.as_str()).into(),
+                    BulkString::from(val.clone()).into(),
+                    BulkString::from("PXAT").into(),
+                    BulkString::from(millis.to_string()).into(),
+                ]
+                .into();
+                command.to_bytes()
+            }
+            None => raw.to_vec(),
+        },
+        _ => raw.to_vec(),
+    }
+}
+
+/// Runs `message` for `client`, against the database it has selected unless
+/// the command spans several or is about the server as a whole. `raw` is the
+/// command as the client sent it, logged if it turns out to be a write.
+pub fn execute(
+    server: &mut Server,
+    client: &mut Client,
+    message: RespMessage,
+    raw: &[u8],
+) -> Value {
+    if let Some(refusal) = refusal(server, client, &message) {
+        // a transaction missing one of its commands runs none of them
+        if let Some(transaction) = &mut client.transaction {
+            transaction.abort();
+        }
+        return refusal;
+    }
+    if let Some(transaction) = &mut client.transaction {
+        if matches!(message, RespMessage::Psync { .. }) {
+            transaction.abort();
+            return CommandError::Invalid("Command not allowed inside a transaction".into()).into();
+        }
+        if !message.is_transaction_control() {
+            transaction.queue(message, raw);
+            return Value::String("QUEUED".into());
+        }
+    }
+    if let RespMessage::Exec = message {
+        return transaction::exec(server, client);
+    }
+    let write = message.is_write().then(|| propagated(&message, raw));
+    // these change whole databases, which no key event stands for
+    let rewrites_databases = matches!(
+        message,
+        RespMessage::SwapDb(..) | RespMessage::FlushDb { .. } | RespMessage::FlushAll { .. }
+    );
+    let db = client.db;
+    let databases = &mut server.databases;
+    let persistence = &mut server.persistence;
+    let replication = &mut server.replication;
+    let pubsub = &mut server.pubsub;
+
+    let result = match message {
+        RespMessage::Ping if client.subscriptions.count() > 0 => {
+            Ok(vec![BulkString::from("pong").into(), BulkString::Empty.into()].into())
+        }
+        RespMessage::Publish { channel, message } => {
+            let receivers = pubsub.publish(&channel, &message);
+            // so subscribers on replicas hear of it too
+            if !client.master {
+                replication.feed(db, raw);
+            }
+            Ok(Value::Int(receivers as isize))
+        }
+        RespMessage::SPublish { channel, message } => {
+            let receivers = pubsub.spublish(&channel, &message);
+            if !client.master {
+                replication.feed(db, raw);
+            }
+            Ok(Value::Int(receivers as isize))
+        }
+        RespMessage::PubSubChannels(pattern) => {
+            Ok(channel_list(pubsub.channels(pattern.as_deref())))
+        }
+        RespMessage::PubSubShardChannels(pattern) => {
+            Ok(channel_list(pubsub.shard_channels(pattern.as_deref())))
+        }
+        RespMessage::PubSubNumSub(channels) => Ok(subscriber_counts(&channels, |channel| {
+            pubsub.numsub(channel)
+        })),
+        RespMessage::PubSubShardNumSub(channels) => Ok(subscriber_counts(&channels, |channel| {
+            pubsub.shard_numsub(channel)
+        })),
+        RespMessage::PubSubNumPat => Ok(Value::Int(pubsub.numpat() as isize)),
+        RespMessage::Quit => Ok(Value::ok()),
+        RespMessage::Multi if client.transaction.is_some() => {
+            Err(CommandError::Invalid("MULTI calls can not be nested".into()).into())
+        }
+        RespMessage::Multi => {
+            client.transaction = Some(Transaction::default());
+            Ok(Value::ok())
+        }
+        RespMessage::Discard => match client.transaction.take() {
+            Some(_) => Ok(Value::ok()),
+            None => Err(CommandError::Invalid("DISCARD without MULTI".into()).into()),
+        },
+        RespMessage::Exec => unreachable!("run before any command is"),
+        RespMessage::Reset => {
+            client.transaction = None;
+            pubsub.unsubscribe_all(client);
+            client.db = 0;
+            Ok(Value::String("RESET".into()))
+        }
+        // they reply more than once, so connections run them
+        RespMessage::Subscribe(_)
+        | RespMessage::Unsubscribe(_)
+        | RespMessage::PSubscribe(_)
+        | RespMessage::PUnsubscribe(_)
+        | RespMessage::SSubscribe(_)
+        | RespMessage::SUnsubscribe(_) => {
+            Err(CommandError::Invalid("SUBSCRIBE is not allowed in this context".into()).into())
+        }
+        RespMessage::Select(index) => db_index(databases, index).map(|index| {
+            client.db = index;
+            Value::ok()
+        }),
+        RespMessage::Move { key, db } => db_index(databases, db).map(|db| {
+            if db == client.db {
+                return CommandError::Invalid("source and destination objects are the same".into())
+                    .into();
+            }
+            let (source, target) = databases.pair(client.db, db);
+            if !source.contains(&key) || target.contains(&key) {
+                return Value::Int(0);
+            }
+            if let Some(value) = source.remove(&key) {
+                source.notify(EventClass::Generic, "move_from", &key);
+                target.notify(EventClass::Generic, "move_to", &key);
+                target.insert(key, value);
+            }
+            Value::Int(1)
+        }),
+        RespMessage::SwapDb(a, b) => db_index(databases, a)
+            .and_then(|a| Ok((a, db_index(databases, b)?)))
+            .map(|(a, b)| {
+                databases.swap(a, b);
+                Value::ok()
+            }),
+        RespMessage::FlushDb { lazy } => {
+            databases.flush(client.db, lazy);
+            Ok(Value::ok())
+        }
+        RespMessage::FlushAll { lazy } => {
+            databases.flush_all(lazy);
+            Ok(Value::ok())
+        }
+        RespMessage::Copy {
+            source,
+            dest,
+            db: Some(db),
+            replace,
+        } if db != client.db => db_index(databases, db).map(|db| {
+            let (store, target) = databases.pair(client.db, db);
+            keyspace::copy(store, Some(target), &source, &dest, replace)
+        }),
+        RespMessage::Save if persistence.in_progress() => {
+            Err(CommandError::Invalid("Background save already in progress".into()).into())
+        }
+        RespMessage::Save => {
+            let path = CONFIG.get().unwrap().rdb_path();
+            match persistence.save(databases, &path) {
+                Ok(()) => Ok(Value::ok()),
+                Err(err) => {
+                    eprintln!("could not save {}: {err}", path.display());
+                    Err(CommandError::Invalid(format!("error saving the dataset: {err}")).into())
+                }
+            }
+        }
+        RespMessage::BgSave { .. } if persistence.in_progress() => {
+            Err(CommandError::Invalid("Background save already in progress".into()).into())
+        }
+        RespMessage::BgSave { schedule } => match (persistence.aof_rewriting(), schedule) {
+            (true, true) => {
+                persistence.schedule();
+                Ok(Value::String("Background saving scheduled".into()))
+            }
+            (true, false) => Err(CommandError::Invalid(
+                "Another child process is active (AOF?): can't BGSAVE right now. \
+                 Use BGSAVE SCHEDULE in order to schedule a BGSAVE whenever possible."
+                    .into(),
+            )
+            .into()),
+            (false, _) => {
+                persistence.bgsave(databases, CONFIG.get().unwrap().rdb_path());
+                Ok(Value::String("Background saving started".into()))
+            }
+        },
+        RespMessage::BgRewriteAof if persistence.aof_rewriting() => Err(CommandError::Invalid(
+            "Background append only file rewriting already in progress".into(),
+        )
+        .into()),
+        RespMessage::BgRewriteAof => match persistence.bgrewriteaof(databases) {
+            Ok(true) => Ok(Value::String(
+                "Background append only file rewriting scheduled".into(),
+            )),
+            Ok(false) => Ok(Value::String(
+                "Background append only file rewriting started".into(),
+            )),
+            Err(err) => Err(CommandError::Invalid(format!(
+                "Can't execute an AOF background rewriting: {err}"
+            ))
+            .into()),
+        },
+        RespMessage::LastSave => Ok(Value::Int(persistence.lastsave() as isize)),
+        RespMessage::Info(sections) => Ok(info(server, &sections)),
+        RespMessage::ConfigGet(key) if key == "save" => {
+            let rules = persistence
+                .rules()
+                .iter()
+                .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+                .collect::<Vec<_>>();
+            Ok(vec![
+                BulkString::from("save").into(),
+                BulkString::from(rules.join(" ")).into(),
+            ]
+            .into())
+        }
+        RespMessage::ConfigGet(key) if key == "rdbcompression" => Ok(vec![
+            BulkString::from("rdbcompression").into(),
+            BulkString::from(if persistence.compression() {
+                "yes"
+            } else {
+                "no"
+            })
+            .into(),
+        ]
+        .into()),
+        RespMessage::ConfigGet(key) if key == "notify-keyspace-events" => Ok(vec![
+            BulkString::from("notify-keyspace-events").into(),
+            BulkString::from(notify::flags_to_string(pubsub.notify_flags())).into(),
+        ]
+        .into()),
+        RespMessage::ConfigGet(key) if key == "replica-serve-stale-data" => Ok(vec![
+            BulkString::from("replica-serve-stale-data").into(),
+            BulkString::from(if replication.serve_stale_data() {
+                "yes"
+            } else {
+                "no"
+            })
+            .into(),
+        ]
+        .into()),
+        RespMessage::ConfigSet(params) => config_set(server, params),
+        RespMessage::ReplicaOf(master) => {
+            if master.is_some()
+                && replication.master() == master.as_ref().map(|(host, port)| (&host[..], *port))
+            {
+                Ok(Value::String(
+                    "OK Already connected to specified master".into(),
+                ))
+            } else {
+                replication.set_master(master);
+                Ok(Value::ok())
+            }
+        }
+        RespMessage::ReplConf(params) => params
+            .iter()
+            .try_for_each(|(option, value)| match &option[..] {
+                "listening-port" => {
+                    let port = value
+                        .parse()
+                        .map_err(|_| CommandError::Invalid("Invalid listening-port".into()))?;
+                    client.listening_port = Some(port);
+                    Ok(())
+                }
+                "capa" => {
+                    client.capa_eof |= value.eq_ignore_ascii_case("eof");
+                    Ok(())
+                }
+                "ip-address" | "getack" | "ack" | "fack" => Ok(()),
+                _ => Err(CommandError::Invalid(format!(
+                    "Unrecognized REPLCONF option: {option}"
+                ))),
+            })
+            .map(|()| Value::ok())
+            .map_err(Value::from),
+        // it takes the connection over, so it never gets here from one
+        RespMessage::Psync { .. } => {
+            Err(CommandError::Invalid("PSYNC is not allowed in this context".into()).into())
+        }
+        // they block, so connections answer them outside the lock
+        RespMessage::Wait { .. } | RespMessage::WaitAof { .. } => {
+            Err(CommandError::Invalid("WAIT is not allowed in this context".into()).into())
+        }
+        message => Ok(execute_in(databases.db(client.db), message)),
+    };
+
+    let reply = result.unwrap_or_else(|err| err);
+    let events = server.databases.take_events();
+    // a write that changed nothing, like DEL of a missing key, is neither
+    // counted nor passed on
+    let changed = rewrites_databases
+        || events
+            .iter()
+            .any(|(_, event)| event.class != EventClass::Expired);
+    if let Some(command) = write.filter(|_| changed && !matches!(reply, Value::Error(_))) {
+        if client.propagation == Propagation::Pending {
+            client.propagation = Propagation::Wrapped;
+            let multi = replication::command(&["MULTI"]);
+            propagate(server, client, db, &multi, false);
+        }
+        propagate(server, client, db, &command, true);
+    }
+    server.pubsub.notify(&events);
+    reply
+}
+
+/// Logs `command`, run against database `db`, and passes it on to replicas
+/// unless it came from our master, which does that itself. Only `counted`
+/// commands count towards the `save` rules.
+pub fn propagate(
+    server: &mut Server,
+    client: &mut Client,
+    db: usize,
+    command: &[u8],
+    counted: bool,
+) {
+    if counted {
+        server.persistence.feed(db, command);
+    } else {
+        server.persistence.log(db, command);
+    }
+    if !client.master {
+        server.replication.feed(db, command);
+        client.woff = server.replication.offset();
+        server.persistence.aof_written(client.woff);
+    }
+}
+
+/// Why `message` cannot run for `client` right now, if it cannot.
+pub fn refusal(server: &Server, client: &Client, message: &RespMessage) -> Option<Value> {
+    if client.subscriptions.count() > 0 && !message.is_allowed_while_subscribed() {
+        return Some(
+            CommandError::Invalid(format!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET \
+                 are allowed in this context",
+                message.name()
+            ))
+            .into(),
+        );
+    }
+    // the master's link is how a replica's data changes at all
+    if server.replication.is_replica() && !client.master {
+        if message.is_write() {
+            return Some(Value::error(
+                "READONLY",
+                "You can't write against a read only replica.",
+            ));
+        }
+        if server.replication.is_stale()
+            && !server.replication.serve_stale_data()
+            && !message.is_allowed_while_stale()
+        {
+            return Some(Value::error(
+                "MASTERDOWN",
+                "Link with MASTER is down and replica-serve-stale-data is set to 'no'.",
+            ));
+        }
+    }
+    if server.persistence.loading() && !message.is_allowed_while_loading() {
+        return Some(Value::error(
+            "LOADING",
+            "Redis is loading the dataset in memory",
+        ));
+    }
+    None
+}
+
+fn channel_list(channels: Vec<&str>) -> Value {
+    channels
+        .into_iter()
+        .map(|channel| BulkString::from(channel).into())
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+/// Each of `channels` followed by how many subscribe to it.
+fn subscriber_counts(channels: &[String], count: impl Fn(&str) -> usize) -> Value {
+    channels
+        .iter()
+        .flat_map(|channel| {
+            [
+                BulkString::from(channel.as_str()).into(),
+                Value::Int(count(channel) as isize),
+            ]
+        })
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+/// Applies every parameter or, if any of them is invalid, none of them.
+fn config_set(server: &mut Server, params: Vec<(String, String)>) -> Result<Value, Value> {
+    let failed = |name: &str, reason: &str| -> Value {
+        CommandError::Invalid(format!(
+            "CONFIG SET failed (possibly related to argument '{name}') - {reason}"
+        ))
+        .into()
+    };
+
+    let mut rules = None;
+    let mut compression = None;
+    let mut serve_stale_data = None;
+    let mut notify_flags = None;
+    let yes_or_no = |name: &str, value: &str| match &value.to_lowercase()[..] {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(failed(name, "argument must be 'yes' or 'no'")),
+    };
+    for (name, value) in &params {
+        match &name[..] {
+            "save" => {
+                rules = Some(
+                    parse_save_rules(value)
+                        .ok_or_else(|| failed(name, "Invalid save parameters"))?,
+                )
+            }
+            "rdbcompression" => compression = Some(yes_or_no(name, value)?),
+            "replica-serve-stale-data" => serve_stale_data = Some(yes_or_no(name, value)?),
+            "notify-keyspace-events" => {
+                notify_flags = Some(notify::parse_flags(value).ok_or_else(|| {
+                    failed(
+                        name,
+                        "Invalid event class character. Use 'Ag$lshzxeKEtmdn'.",
+                    )
+                })?)
+            }
+            _ => return Err(failed(name, "Unsupported CONFIG parameter")),
+        }
+    }
+
+    if let Some(rules) = rules {
+        server.persistence.set_rules(rules);
+    }
+    if let Some(compression) = compression {
+        server.persistence.set_compression(compression);
+    }
+    if let Some(serve) = serve_stale_data {
+        server.replication.set_serve_stale_data(serve);
+    }
+    if let Some(flags) = notify_flags {
+        server.pubsub.set_notify_flags(flags);
+    }
+    Ok(Value::ok())
+}
+
+/// The `INFO` text for the requested sections, every section when none are named.
+fn info(server: &mut Server, sections: &[String]) -> Value {
+    let all = sections.is_empty()
+        || sections
+            .iter()
+            .any(|section| matches!(&section[..], "all" | "default" | "everything"));
+    let wanted = |name: &str| all || sections.iter().any(|section| section == name);
+
+    let mut text = Vec::new();
+    if wanted("persistence") {
+        text.push(server.persistence.info());
+    }
+    if wanted("replication") {
+        text.push(server.replication.info());
+    }
+    BulkString::from(text.join("\r\n")).into()
+}
+
+fn execute_in(store: &mut Store, message: RespMessage) -> Value {
+    match message {
+        RespMessage::Ping => Value::String("PONG".into()),
+        RespMessage::Echo(bs) => bs.into(),
+        RespMessage::Set {
+            key,
+            val,
+            expiration,
+        } => {
+            let has_expiration = !matches!(expiration, Expiration::Empty);
+            store.insert(
+                key.clone(),
+                DurableValue {
+                    expiration,
+                    ..DurableValue::new(Object::String(val))
+                },
+            );
+            store.notify(EventClass::String, "set", &key);
+            if has_expiration {
+                store.notify(EventClass::Generic, "expire", &key);
+            }
+            Value::ok()
+        }
+        RespMessage::Get(key) => match store.get_string(&key) {
+            Ok(Some(bytes)) => BulkString::from(bytes.clone()).into(),
+            Ok(None) => BulkString::Null.into(),
+            Err(err) => err.into(),
+        },
+        RespMessage::ConfigGet(key) => match &key[..] {
+            "dir" => CONFIG.get().unwrap().dir_to_value(),
+            "dbfilename" => CONFIG.get().unwrap().filename_to_value(),
+            "databases" => CONFIG.get().unwrap().databases_to_value(),
+            _ => Array::Empty.into(),
+        },
+        RespMessage::Keys(_) => store
+            .keys()
+            .map(|k| BulkString::from(k.as_str()).into())
+            .collect::<Vec<Value>>()
+            .into(),
+        RespMessage::SetBit { key, offset, bit } => bitmap::setbit(store, &key, offset, bit),
+        RespMessage::GetBit { key, offset } => bitmap::getbit(store, &key, offset),
+        RespMessage::BitCount { key, range } => bitmap::bitcount(store, &key, range),
+        RespMessage::BitPos {
+            key,
+            bit,
+            range,
+            end_given,
+        } => bitmap::bitpos(store, &key, bit, range, end_given),
+        RespMessage::BitOp { op, dest, keys } => bitmap::bitop(store, op, &dest, &keys),
+        RespMessage::BitField { key, ops } => bitmap::bitfield(store, &key, &ops),
+        RespMessage::PfAdd { key, elements } => hyperloglog::pfadd(store, &key, &elements),
+        RespMessage::PfCount(keys) => hyperloglog::pfcount(store, &keys),
+        RespMessage::PfMerge { dest, keys } => hyperloglog::pfmerge(store, &dest, &keys),
+        RespMessage::GeoAdd { key, add } => geo::geoadd(store, &key, add),
+        RespMessage::GeoDist {
+            key,
+            from,
+            to,
+            unit,
+        } => geo::geodist(store, &key, &from, &to, unit),
+        RespMessage::GeoPos { key, members } => geo::geopos(store, &key, &members),
+        RespMessage::GeoHash { key, members } => geo::geohash(store, &key, &members),
+        RespMessage::GeoSearch { key, search } => geo::geosearch(store, &key, &search),
+        RespMessage::GeoSearchStore { dest, key, search } => {
+            geo::geosearchstore(store, &dest, &key, &search)
+        }
+        RespMessage::Del(keys) => keyspace::del(store, &keys),
+        RespMessage::Unlink(keys) => keyspace::unlink(store, &keys),
+        RespMessage::Exists(keys) => keyspace::exists(store, &keys),
+        RespMessage::Touch(keys) => keyspace::touch(store, &keys),
+        RespMessage::Type(key) => keyspace::key_type(store, &key),
+        RespMessage::Rename { from, to } => keyspace::rename(store, &from, &to),
+        RespMessage::RenameNx { from, to } => keyspace::renamenx(store, &from, &to),
+        RespMessage::Copy {
+            source,
+            dest,
+            replace,
+            ..
+        } => keyspace::copy(store, None, &source, &dest, replace),
+        RespMessage::RandomKey => keyspace::randomkey(store),
+        RespMessage::DbSize => keyspace::dbsize(store),
+        RespMessage::Select(_)
+        | RespMessage::Move { .. }
+        | RespMessage::SwapDb(..)
+        | RespMessage::FlushDb { .. }
+        | RespMessage::FlushAll { .. }
+        | RespMessage::Save
+        | RespMessage::BgSave { .. }
+        | RespMessage::BgRewriteAof
+        | RespMessage::LastSave
+        | RespMessage::Info(_)
+        | RespMessage::ConfigSet(_)
+        | RespMessage::ReplicaOf(_)
+        | RespMessage::ReplConf(_)
+        | RespMessage::Psync { .. }
+        | RespMessage::Wait { .. }
+        | RespMessage::WaitAof { .. }
+        | RespMessage::Subscribe(_)
+        | RespMessage::Unsubscribe(_)
+        | RespMessage::PSubscribe(_)
+        | RespMessage::PUnsubscribe(_)
+        | RespMessage::SSubscribe(_)
+        | RespMessage::SUnsubscribe(_)
+        | RespMessage::Publish { .. }
+        | RespMessage::SPublish { .. }
+        | RespMessage::PubSubChannels(_)
+        | RespMessage::PubSubNumSub(_)
+        | RespMessage::PubSubNumPat
+        | RespMessage::PubSubShardChannels(_)
+        | RespMessage::PubSubShardNumSub(_)
+        | RespMessage::Quit
+        | RespMessage::Reset
+        | RespMessage::Multi
+        | RespMessage::Exec
+        | RespMessage::Discard => unreachable!("handled by execute"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(server: &mut Server, client: &mut Client, args: &[&str]) -> Value {
+        let value: Value = args
+            .iter()
+            .map(|arg| BulkString::from(*arg).into())
+            .collect::<Vec<Value>>()
+            .into();
+        let raw = value.to_bytes();
+        match RespMessage::try_from(value) {
+            Ok(message) => execute(server, client, message, &raw),
+            Err(err) => err.into(),
+        }
+    }
+
+    #[test]
+    fn select_isolates_databases() {
+        let mut server = Server::new(Databases::new(4));
+        let mut client = Client::default();
+
+        run(&mut server, &mut client, &["set", "k", "zero"]);
+        assert_eq!(run(&mut server, &mut client, &["select", "2"]), Value::ok());
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            BulkString::Null.into()
+        );
+        assert!(matches!(
+            run(&mut server, &mut client, &["select", "4"]),
+            Value::Error(_)
+        ));
+        assert_eq!(client.db, 2);
+    }
+
+    #[test]
+    fn set_refuses_options_it_does_not_support() {
+        let mut server = Server::new(Databases::new(1));
+        let mut client = Client::default();
+
+        for args in [
+            &["set", "k", "v", "nx"][..],
+            &["set", "k", "v", "ex", "10"],
+            &["set", "k", "v", "px", "10", "get"],
+            &["set", "k", "v", "keepttl"],
+            &["set", "k", "v", "px"],
+        ] {
+            assert_eq!(
+                run(&mut server, &mut client, args),
+                CommandError::Syntax.into(),
+                "{args:?}"
+            );
+        }
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            BulkString::Null.into()
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["set", "k", "v", "PX", "10000"]),
+            Value::ok()
+        );
+    }
+
+    #[test]
+    fn refuses_keys_that_are_not_utf8() {
+        let command = |args: [&[u8]; 3]| -> Value {
+            args.iter()
+                .map(|arg| BulkString::from(arg.to_vec()).into())
+                .collect::<Vec<Value>>()
+                .into()
+        };
+        // values can hold any bytes
+        assert!(RespMessage::try_from(command([b"set", b"k", b"\xff"])).is_ok());
+        assert!(matches!(
+            RespMessage::try_from(command([b"set", b"\xff", b"v"])),
+            Err(CommandError::Invalid(_))
+        ));
+        assert!(matches!(
+            RespMessage::try_from(command([b"del", b"k", b"\xfe"])),
+            Err(CommandError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn move_and_copy_between_databases() {
+        let mut server = Server::new(Databases::new(4));
+        let mut client = Client::default();
+        run(&mut server, &mut client, &["set", "k", "v"]);
+
+        assert_eq!(
+            run(&mut server, &mut client, &["copy", "k", "k", "db", "1"]),
+            Value::Int(1)
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["move", "k", "1"]),
+            Value::Int(0)
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["move", "k", "2"]),
+            Value::Int(1)
+        );
+        assert_eq!(server.databases.db(0).len(), 0);
+        assert_eq!(server.databases.db(1).len(), 1);
+        assert_eq!(server.databases.db(2).len(), 1);
+        assert!(matches!(
+            run(&mut server, &mut client, &["move", "k", "0"]),
+            Value::Error(_)
+        ));
+    }
+
+    #[test]
+    fn swapdb_and_flush() {
+        let mut server = Server::new(Databases::new(3));
+        let mut client = Client::default();
+        run(&mut server, &mut client, &["set", "k", "v"]);
+
+        assert_eq!(
+            run(&mut server, &mut client, &["swapdb", "0", "2"]),
+            Value::ok()
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["exists", "k"]),
+            Value::Int(0)
+        );
+        assert!(matches!(
+            run(&mut server, &mut client, &["swapdb", "0", "x"]),
+            Value::Error(_)
+        ));
+
+        run(&mut server, &mut client, &["set", "k", "v"]);
+        assert_eq!(
+            run(&mut server, &mut client, &["flushdb", "async"]),
+            Value::ok()
+        );
+        assert_eq!(server.databases.db(0).len(), 0);
+        assert_eq!(server.databases.db(2).len(), 1);
+        assert_eq!(run(&mut server, &mut client, &["flushall"]), Value::ok());
+        assert_eq!(server.databases.db(2).len(), 0);
+    }
+
+    #[test]
+    fn config_set_save_and_dirty_counter() {
+        let mut server = Server::new(Databases::new(1));
+        let mut client = Client::default();
+
+        assert_eq!(
+            run(
+                &mut server,
+                &mut client,
+                &["config", "set", "save", "10 2 60 5"]
+            ),
+            Value::ok()
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["config", "get", "save"]),
+            vec![
+                BulkString::from("save").into(),
+                BulkString::from("10 2 60 5").into()
+            ]
+            .into()
+        );
+        assert!(matches!(
+            run(&mut server, &mut client, &["config", "set", "save", "10"]),
+            Value::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut server, &mut client, &["config", "set", "nope", "1"]),
+            Value::Error(_)
+        ));
+        assert_eq!(
+            run(
+                &mut server,
+                &mut client,
+                &["config", "set", "rdbcompression", "no"]
+            ),
+            Value::ok()
+        );
+        assert!(!server.persistence.compression());
+
+        run(&mut server, &mut client, &["set", "k", "v"]);
+        run(&mut server, &mut client, &["get", "k"]);
+        run(&mut server, &mut client, &["del", "k"]);
+        run(&mut server, &mut client, &["copy", "k", "k"]);
+        assert!(server
+            .persistence
+            .info()
+            .contains("rdb_changes_since_last_save:2\r\n"));
+
+        // writes that change nothing do not count
+        run(&mut server, &mut client, &["del", "k"]);
+        run(&mut server, &mut client, &["setbit", "bits", "3", "1"]);
+        run(&mut server, &mut client, &["setbit", "bits", "3", "1"]);
+        run(
+            &mut server,
+            &mut client,
+            &["bitfield", "bits", "set", "u4", "0", "1"],
+        );
+        run(&mut server, &mut client, &["flushdb"]);
+        assert!(server
+            .persistence
+            .info()
+            .contains("rdb_changes_since_last_save:4\r\n"));
+    }
+
+    #[test]
+    fn only_info_and_config_run_while_loading() {
+        let mut server = Server::new(Databases::new(1));
+        let mut client = Client::default();
+        let progress = server.persistence.start_loading(200);
+        progress.store(50, std::sync::atomic::Ordering::Relaxed);
+
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            Value::error("LOADING", "Redis is loading the dataset in memory")
+        );
+        let info = run(&mut server, &mut client, &["info", "persistence"]).to_bytes();
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("loading:1\r\n"));
+        assert!(info.contains("loading_loaded_bytes:50\r\n"));
+        assert!(info.contains("loading_loaded_perc:25.00\r\n"));
+
+        server.persistence.finish_loading();
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            BulkString::Null.into()
+        );
+    }
+
+    #[test]
+    fn replicas_only_take_writes_from_their_master() {
+        let mut server = Server::new(Databases::new(1));
+        server
+            .replication
+            .set_master(Some(("127.0.0.1".into(), 6379)));
+        let mut client = Client::default();
+        let mut master = Client {
+            master: true,
+            ..Client::default()
+        };
+
+        assert_eq!(
+            run(&mut server, &mut client, &["set", "k", "v"]),
+            Value::error("READONLY", "You can't write against a read only replica.")
+        );
+        assert_eq!(
+            run(&mut server, &mut master, &["set", "k", "v"]),
+            Value::ok()
+        );
+        // BITFIELD only writes when it sets or increments
+        assert!(!matches!(
+            run(
+                &mut server,
+                &mut client,
+                &["bitfield", "b", "get", "u8", "0"]
+            ),
+            Value::Error(_)
+        ));
+
+        // the link is not up, which is fine until stale data is turned off
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            BulkString::from("v").into()
+        );
+        run(
+            &mut server,
+            &mut client,
+            &["config", "set", "replica-serve-stale-data", "no"],
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            Value::error(
+                "MASTERDOWN",
+                "Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+            )
+        );
+        assert!(!matches!(
+            run(&mut server, &mut client, &["info", "replication"]),
+            Value::Error(_)
+        ));
+
+        run(&mut server, &mut client, &["replicaof", "no", "one"]);
+        assert_eq!(
+            run(&mut server, &mut client, &["set", "k", "w"]),
+            Value::ok()
+        );
+    }
+}
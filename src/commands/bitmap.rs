@@ -0,0 +1,703 @@
+use crate::message::{int, CommandError};
+use crate::parser::resp::{BulkString, Value};
+use crate::pubsub::EventClass;
+use crate::store::{DurableValue, Object, Store};
+
+/// Strings are capped at 512MB, so the highest addressable bit is 2^32 - 1.
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+/// An inclusive `start..=end` range as given to `BITCOUNT` and `BITPOS`,
+/// where negative indices count from the end of the string.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitRange {
+    pub start: i64,
+    pub end: i64,
+    pub unit: BitUnit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+    /// `X AND NOT (Y1 OR Y2 ...)`: bits set in the first key but in none of the others.
+    Diff,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitFieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BitFieldOp {
+    Get {
+        ty: BitFieldType,
+        offset: u64,
+    },
+    Set {
+        ty: BitFieldType,
+        offset: u64,
+        value: i64,
+    },
+    IncrBy {
+        ty: BitFieldType,
+        offset: u64,
+        increment: i64,
+    },
+    Overflow(Overflow),
+}
+
+pub fn bit_offset(arg: &BulkString) -> Result<u64, CommandError> {
+    arg.inner()
+        .parse::<u64>()
+        .ok()
+        .filter(|offset| *offset <= MAX_BIT_OFFSET)
+        .ok_or_else(|| CommandError::Invalid("bit offset is not an integer or out of range".into()))
+}
+
+impl BitOp {
+    pub fn parse(op: &str, sources: usize) -> Result<Self, CommandError> {
+        let op = match &op.to_lowercase()[..] {
+            "and" => BitOp::And,
+            "or" => BitOp::Or,
+            "xor" => BitOp::Xor,
+            "not" => BitOp::Not,
+            "diff" => BitOp::Diff,
+            _ => return Err(CommandError::Syntax),
+        };
+        match op {
+            BitOp::Not if sources != 1 => Err(CommandError::Invalid(
+                "BITOP NOT must be called with a single source key.".into(),
+            )),
+            BitOp::Diff if sources < 2 => Err(CommandError::Invalid(
+                "BITOP DIFF must be called with at least two source keys.".into(),
+            )),
+            op => Ok(op),
+        }
+    }
+}
+
+impl BitFieldType {
+    fn parse(arg: &BulkString) -> Result<Self, CommandError> {
+        let invalid = || {
+            CommandError::Invalid(
+                "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".into(),
+            )
+        };
+        let ty = arg.inner().to_lowercase();
+        let (signed, bits) = match ty.split_at(ty.len().min(1)) {
+            ("i", bits) => (true, bits),
+            ("u", bits) => (false, bits),
+            _ => return Err(invalid()),
+        };
+        let bits = bits.parse::<u32>().map_err(|_| invalid())?;
+        let max = if signed { 64 } else { 63 };
+
+        if bits == 0 || bits > max {
+            return Err(invalid());
+        }
+        Ok(Self { signed, bits })
+    }
+
+    /// The offset of a field, either in bits or, prefixed with `#`, in multiples of the field width.
+    fn offset(&self, arg: &BulkString) -> Result<u64, CommandError> {
+        let err = || CommandError::Invalid("bit offset is not an integer or out of range".into());
+        let arg = arg.inner();
+        let offset = match arg.strip_prefix('#') {
+            Some(index) => index
+                .parse::<u64>()
+                .ok()
+                .and_then(|index| index.checked_mul(self.bits as u64)),
+            None => arg.parse::<u64>().ok(),
+        }
+        .ok_or_else(err)?;
+
+        if offset + self.bits as u64 - 1 > MAX_BIT_OFFSET {
+            return Err(err());
+        }
+        Ok(offset)
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1i128 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1i128 << (self.bits - 1)) - 1
+        } else {
+            (1i128 << self.bits) - 1
+        }
+    }
+
+    /// Fits `value` into the field according to the overflow policy, or
+    /// returns `None` when the policy is `FAIL` and the value does not fit.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(value.clamp(self.min(), self.max()) as i64),
+            Overflow::Wrap => {
+                let wrapped = value.rem_euclid(1i128 << self.bits);
+                if self.signed && wrapped > self.max() {
+                    Some((wrapped - (1i128 << self.bits)) as i64)
+                } else {
+                    Some(wrapped as i64)
+                }
+            }
+        }
+    }
+
+    /// Interprets the low `bits` of `raw` as a value of this type.
+    fn decode(&self, raw: u64) -> i64 {
+        if self.signed && self.bits < 64 && raw & (1 << (self.bits - 1)) != 0 {
+            (raw | (u64::MAX << self.bits)) as i64
+        } else {
+            raw as i64
+        }
+    }
+}
+
+impl BitFieldOp {
+    pub fn parse_all(args: &[BulkString]) -> Result<Vec<Self>, CommandError> {
+        let mut ops = Vec::new();
+        let mut rest = args;
+
+        while let Some((op, tail)) = rest.split_first() {
+            let (op, tail) = match (&op.inner().to_lowercase()[..], tail) {
+                ("get", [ty, offset, tail @ ..]) => {
+                    let ty = BitFieldType::parse(ty)?;
+                    let offset = ty.offset(offset)?;
+                    (BitFieldOp::Get { ty, offset }, tail)
+                }
+                ("set", [ty, offset, value, tail @ ..]) => {
+                    let ty = BitFieldType::parse(ty)?;
+                    let offset = ty.offset(offset)?;
+                    let value = int(value)?;
+                    (BitFieldOp::Set { ty, offset, value }, tail)
+                }
+                ("incrby", [ty, offset, increment, tail @ ..]) => {
+                    let ty = BitFieldType::parse(ty)?;
+                    let offset = ty.offset(offset)?;
+                    let increment = int(increment)?;
+                    (
+                        BitFieldOp::IncrBy {
+                            ty,
+                            offset,
+                            increment,
+                        },
+                        tail,
+                    )
+                }
+                ("overflow", [policy, tail @ ..]) => {
+                    let policy = match &policy.inner().to_lowercase()[..] {
+                        "wrap" => Overflow::Wrap,
+                        "sat" => Overflow::Sat,
+                        "fail" => Overflow::Fail,
+                        _ => {
+                            return Err(CommandError::Invalid(
+                                "Invalid OVERFLOW type specified".into(),
+                            ))
+                        }
+                    };
+                    (BitFieldOp::Overflow(policy), tail)
+                }
+                _ => return Err(CommandError::Syntax),
+            };
+            ops.push(op);
+            rest = tail;
+        }
+        Ok(ops)
+    }
+
+    fn is_write(&self) -> bool {
+        matches!(self, BitFieldOp::Set { .. } | BitFieldOp::IncrBy { .. })
+    }
+
+    fn end(&self) -> u64 {
+        match self {
+            BitFieldOp::Get { ty, offset }
+            | BitFieldOp::Set { ty, offset, .. }
+            | BitFieldOp::IncrBy { ty, offset, .. } => offset + ty.bits as u64,
+            BitFieldOp::Overflow(_) => 0,
+        }
+    }
+}
+
+impl BitRange {
+    /// Resolves the range against a string of `len` bytes into an inclusive
+    /// range of bit positions, or `None` if it selects nothing.
+    fn resolve(&self, len: usize) -> Option<(u64, u64)> {
+        let total = match self.unit {
+            BitUnit::Byte => len as i64,
+            BitUnit::Bit => len as i64 * 8,
+        };
+        let normalize = |index: i64| {
+            if index < 0 {
+                (index + total).max(0)
+            } else {
+                index
+            }
+        };
+        let start = normalize(self.start);
+        let end = normalize(self.end).min(total - 1);
+
+        if total == 0 || start > end {
+            return None;
+        }
+        match self.unit {
+            BitUnit::Byte => Some((start as u64 * 8, end as u64 * 8 + 7)),
+            BitUnit::Bit => Some((start as u64, end as u64)),
+        }
+    }
+}
+
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    bytes
+        .get((offset / 8) as usize)
+        .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+fn set_bit(bytes: &mut [u8], offset: u64, bit: bool) {
+    let byte = &mut bytes[(offset / 8) as usize];
+    let mask = 0x80 >> (offset % 8);
+    if bit {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
+
+/// Pads `bytes` with zeros to hold `bits` bits, returning whether it had to.
+fn grow(bytes: &mut Vec<u8>, bits: u64) -> bool {
+    let len = bits.div_ceil(8) as usize;
+    let grown = bytes.len() < len;
+    if grown {
+        bytes.resize(len, 0);
+    }
+    grown
+}
+
+/// Reads `bits` bits starting at `offset`, most significant bit first.
+fn read_bits(bytes: &[u8], offset: u64, bits: u32) -> u64 {
+    (0..bits as u64).fold(0, |acc, i| (acc << 1) | get_bit(bytes, offset + i) as u64)
+}
+
+fn write_bits(bytes: &mut [u8], offset: u64, bits: u32, value: u64) {
+    for i in 0..bits as u64 {
+        set_bit(bytes, offset + i, value & (1 << (bits as u64 - 1 - i)) != 0);
+    }
+}
+
+pub fn setbit(store: &mut Store, key: &str, offset: u64, bit: bool) -> Value {
+    let bytes = match store.string_mut(key) {
+        Ok(bytes) => bytes,
+        Err(err) => return err.into(),
+    };
+    let grown = grow(bytes, offset + 1);
+    let old = get_bit(bytes, offset);
+    set_bit(bytes, offset, bit);
+    if grown || old != bit {
+        store.notify(EventClass::String, "setbit", key);
+    }
+
+    Value::Int(old as isize)
+}
+
+pub fn getbit(store: &mut Store, key: &str, offset: u64) -> Value {
+    match store.get_string(key) {
+        Ok(bytes) => Value::Int(bytes.is_some_and(|bytes| get_bit(bytes, offset)) as isize),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn bitcount(store: &mut Store, key: &str, range: Option<BitRange>) -> Value {
+    let bytes = match store.get_string(key) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return Value::Int(0),
+        Err(err) => return err.into(),
+    };
+    let count = match range {
+        None => bytes.iter().map(|b| b.count_ones() as u64).sum(),
+        Some(range) => match range.resolve(bytes.len()) {
+            Some((start, end)) => (start..=end).filter(|&i| get_bit(bytes, i)).count() as u64,
+            None => 0,
+        },
+    };
+
+    Value::Int(count as isize)
+}
+
+pub fn bitpos(store: &mut Store, key: &str, bit: bool, range: BitRange, end_given: bool) -> Value {
+    let bytes = match store.get_string(key) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return Value::Int(if bit { -1 } else { 0 }),
+        Err(err) => return err.into(),
+    };
+    let Some((start, end)) = range.resolve(bytes.len()) else {
+        return Value::Int(-1);
+    };
+
+    match (start..=end).find(|&i| get_bit(bytes, i) == bit) {
+        Some(pos) => Value::Int(pos as isize),
+        // looking for a clear bit in an open-ended range: the string is
+        // conceptually padded with zeros to the right
+        None if !bit && !end_given => Value::Int(end as isize + 1),
+        None => Value::Int(-1),
+    }
+}
+
+pub fn bitop(store: &mut Store, op: BitOp, dest: &str, keys: &[String]) -> Value {
+    let mut sources = Vec::with_capacity(keys.len());
+    for key in keys {
+        match store.get_string(key) {
+            Ok(bytes) => sources.push(bytes.cloned().unwrap_or_default()),
+            Err(err) => return err.into(),
+        }
+    }
+    let len = sources.iter().map(Vec::len).max().unwrap_or(0);
+    let byte = |source: &Vec<u8>, i: usize| source.get(i).copied().unwrap_or(0);
+
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut rest = sources.iter().map(|source| byte(source, i));
+            let first = rest.next().unwrap_or(0);
+            match op {
+                BitOp::And => rest.fold(first, |acc, b| acc & b),
+                BitOp::Or => rest.fold(first, |acc, b| acc | b),
+                BitOp::Xor => rest.fold(first, |acc, b| acc ^ b),
+                BitOp::Not => !first,
+                BitOp::Diff => first & !rest.fold(0, |acc, b| acc | b),
+            }
+        })
+        .collect();
+
+    if result.is_empty() {
+        if store.remove(dest).is_some() {
+            store.notify(EventClass::Generic, "del", dest);
+        }
+    } else {
+        store.insert(dest.to_string(), DurableValue::new(Object::String(result)));
+        store.notify(EventClass::String, "set", dest);
+    }
+    Value::Int(len as isize)
+}
+
+pub fn bitfield(store: &mut Store, key: &str, ops: &[BitFieldOp]) -> Value {
+    if !ops.iter().any(BitFieldOp::is_write) {
+        let bytes = match store.get_string(key) {
+            Ok(bytes) => bytes.map(Vec::as_slice).unwrap_or_default(),
+            Err(err) => return err.into(),
+        };
+        return ops
+            .iter()
+            .filter_map(|op| match *op {
+                BitFieldOp::Get { ty, offset } => Some(Value::Int(
+                    ty.decode(read_bits(bytes, offset, ty.bits)) as isize,
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .into();
+    }
+
+    let bytes = match store.string_mut(key) {
+        Ok(bytes) => bytes,
+        Err(err) => return err.into(),
+    };
+    // only writes grow the string, reads past its end see zeros
+    let writes = ops.iter().filter(|op| op.is_write());
+    let mut changed = grow(bytes, writes.map(BitFieldOp::end).max().unwrap_or(0));
+
+    let mut overflow = Overflow::Wrap;
+    let mut replies = Vec::new();
+    for op in ops {
+        match *op {
+            BitFieldOp::Overflow(policy) => overflow = policy,
+            BitFieldOp::Get { ty, offset } => {
+                replies.push(Value::Int(
+                    ty.decode(read_bits(bytes, offset, ty.bits)) as isize
+                ));
+            }
+            BitFieldOp::Set { ty, offset, value } => {
+                let old = ty.decode(read_bits(bytes, offset, ty.bits));
+                // unsigned fields take the value's bit pattern, so `SET u8 0 -1` overflows upward
+                let value = if ty.signed {
+                    value as i128
+                } else {
+                    value as u64 as i128
+                };
+                match ty.fit(value, overflow) {
+                    Some(new) => {
+                        write_bits(bytes, offset, ty.bits, new as u64);
+                        changed |= new != old;
+                        replies.push(Value::Int(old as isize));
+                    }
+                    None => replies.push(BulkString::Null.into()),
+                }
+            }
+            BitFieldOp::IncrBy {
+                ty,
+                offset,
+                increment,
+            } => {
+                let old = ty.decode(read_bits(bytes, offset, ty.bits));
+                match ty.fit(old as i128 + increment as i128, overflow) {
+                    Some(new) => {
+                        write_bits(bytes, offset, ty.bits, new as u64);
+                        changed |= new != old;
+                        replies.push(Value::Int(new as isize));
+                    }
+                    None => replies.push(BulkString::Null.into()),
+                }
+            }
+        }
+    }
+    if changed {
+        store.notify(EventClass::String, "setbit", key);
+    }
+
+    replies.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store_with(key: &str, bytes: &[u8]) -> Store {
+        Store::from_iter([(
+            key.to_string(),
+            DurableValue::new(Object::String(bytes.to_vec())),
+        )])
+    }
+
+    fn args(items: &[&str]) -> Vec<BulkString> {
+        items.iter().map(|item| BulkString::from(*item)).collect()
+    }
+
+    #[test]
+    fn setbit_grows_and_reports_old_bit() {
+        let mut store = Store::default();
+
+        assert_eq!(setbit(&mut store, "k", 7, true), Value::Int(0));
+        assert_eq!(setbit(&mut store, "k", 7, false), Value::Int(1));
+        assert_eq!(setbit(&mut store, "k", 17, true), Value::Int(0));
+        assert_eq!(store.get_string("k"), Ok(Some(&vec![0x00, 0x00, 0x40])));
+        assert_eq!(getbit(&mut store, "k", 17), Value::Int(1));
+        assert_eq!(getbit(&mut store, "k", 1000), Value::Int(0));
+    }
+
+    #[test]
+    fn bitcount_ranges() {
+        let mut store = store_with("k", b"foobar");
+        let range = |start, end, unit| Some(BitRange { start, end, unit });
+
+        assert_eq!(bitcount(&mut store, "k", None), Value::Int(26));
+        assert_eq!(
+            bitcount(&mut store, "k", range(0, 0, BitUnit::Byte)),
+            Value::Int(4)
+        );
+        assert_eq!(
+            bitcount(&mut store, "k", range(1, 1, BitUnit::Byte)),
+            Value::Int(6)
+        );
+        assert_eq!(
+            bitcount(&mut store, "k", range(1, 1, BitUnit::Bit)),
+            Value::Int(1)
+        );
+        assert_eq!(
+            bitcount(&mut store, "k", range(5, 30, BitUnit::Bit)),
+            Value::Int(17)
+        );
+        assert_eq!(
+            bitcount(&mut store, "k", range(-2, -1, BitUnit::Byte)),
+            Value::Int(7)
+        );
+        assert_eq!(
+            bitcount(&mut store, "k", range(3, 1, BitUnit::Byte)),
+            Value::Int(0)
+        );
+    }
+
+    #[test]
+    fn bitpos_finds_bits() {
+        let mut store = store_with("k", &[0xff, 0xf0, 0x00]);
+        let range = |start, end, unit| BitRange { start, end, unit };
+        let whole = range(0, -1, BitUnit::Byte);
+
+        assert_eq!(bitpos(&mut store, "k", false, whole, false), Value::Int(12));
+        assert_eq!(
+            bitpos(&mut store, "k", true, range(2, -1, BitUnit::Byte), true),
+            Value::Int(-1)
+        );
+        assert_eq!(
+            bitpos(&mut store, "k", true, range(7, 15, BitUnit::Bit), true),
+            Value::Int(7)
+        );
+
+        let mut store = store_with("k", &[0xff, 0xff]);
+        assert_eq!(bitpos(&mut store, "k", false, whole, false), Value::Int(16));
+        assert_eq!(bitpos(&mut store, "k", false, whole, true), Value::Int(-1));
+        assert_eq!(
+            bitpos(&mut store, "missing", false, whole, false),
+            Value::Int(0)
+        );
+        assert_eq!(
+            bitpos(&mut store, "missing", true, whole, false),
+            Value::Int(-1)
+        );
+    }
+
+    #[test]
+    fn bitop_combines_sources() {
+        let mut store = store_with("a", &[0b1100_1100, 0xff]);
+        store.insert(
+            "b".into(),
+            DurableValue::new(Object::String(vec![0b1010_1010])),
+        );
+        let keys = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        let cases = [
+            (BitOp::And, keys(&["a", "b"]), vec![0b1000_1000, 0x00]),
+            (BitOp::Or, keys(&["a", "b"]), vec![0b1110_1110, 0xff]),
+            (BitOp::Xor, keys(&["a", "b"]), vec![0b0110_0110, 0xff]),
+            (BitOp::Not, keys(&["b"]), vec![0b0101_0101]),
+            (BitOp::Diff, keys(&["a", "b"]), vec![0b0100_0100, 0xff]),
+        ];
+        for (op, sources, expected) in cases {
+            assert_eq!(
+                bitop(&mut store, op, "dest", &sources),
+                Value::Int(expected.len() as isize)
+            );
+            assert_eq!(store.get_string("dest"), Ok(Some(&expected)));
+        }
+
+        assert_eq!(
+            bitop(&mut store, BitOp::Or, "dest", &keys(&["x"])),
+            Value::Int(0)
+        );
+        assert_eq!(store.get("dest"), None);
+    }
+
+    #[test]
+    fn bitop_arity() {
+        assert!(BitOp::parse("not", 2).is_err());
+        assert!(BitOp::parse("diff", 1).is_err());
+        assert!(BitOp::parse("nand", 2).is_err());
+        assert_eq!(BitOp::parse("DIFF", 3), Ok(BitOp::Diff));
+    }
+
+    #[test]
+    fn bitfield_types_and_offsets() {
+        let ops =
+            BitFieldOp::parse_all(&args(&["get", "u8", "#2", "set", "i64", "0", "-1"])).unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                BitFieldOp::Get {
+                    ty: BitFieldType {
+                        signed: false,
+                        bits: 8
+                    },
+                    offset: 16
+                },
+                BitFieldOp::Set {
+                    ty: BitFieldType {
+                        signed: true,
+                        bits: 64
+                    },
+                    offset: 0,
+                    value: -1
+                },
+            ]
+        );
+
+        assert!(BitFieldOp::parse_all(&args(&["get", "u64", "0"])).is_err());
+        assert!(BitFieldOp::parse_all(&args(&["get", "i0", "0"])).is_err());
+        assert!(BitFieldOp::parse_all(&args(&["get", "u8"])).is_err());
+        assert!(BitFieldOp::parse_all(&args(&["overflow", "clamp"])).is_err());
+    }
+
+    #[test]
+    fn bitfield_overflow_policies() {
+        let mut store = Store::default();
+        let run = |store: &mut Store, items: &[&str]| {
+            bitfield(store, "k", &BitFieldOp::parse_all(&args(items)).unwrap())
+        };
+
+        assert_eq!(
+            run(
+                &mut store,
+                &["set", "u8", "0", "255", "get", "u8", "0", "get", "i8", "0"]
+            ),
+            vec![Value::Int(0), Value::Int(255), Value::Int(-1)].into()
+        );
+        assert_eq!(
+            run(&mut store, &["incrby", "u8", "0", "10"]),
+            vec![Value::Int(9)].into()
+        );
+        assert_eq!(
+            run(
+                &mut store,
+                &["overflow", "sat", "incrby", "u8", "0", "1000", "incrby", "i8", "0", "-1000"]
+            ),
+            vec![Value::Int(255), Value::Int(-128)].into()
+        );
+        assert_eq!(
+            run(
+                &mut store,
+                &["overflow", "fail", "incrby", "i8", "0", "-1", "get", "i8", "0"]
+            ),
+            vec![BulkString::Null.into(), Value::Int(-128)].into()
+        );
+        assert_eq!(
+            run(&mut store, &["set", "u4", "#1", "-1", "get", "u8", "0"]),
+            vec![Value::Int(0), Value::Int(0x8f)].into()
+        );
+        assert_eq!(
+            run(
+                &mut store,
+                &["overflow", "wrap", "incrby", "i4", "4", "8", "get", "i4", "4"]
+            ),
+            vec![Value::Int(7), Value::Int(7)].into()
+        );
+    }
+
+    #[test]
+    fn bitfield_reads_do_not_create_keys() {
+        let mut store = Store::default();
+        let ops = BitFieldOp::parse_all(&args(&["get", "i16", "100"])).unwrap();
+
+        assert_eq!(bitfield(&mut store, "k", &ops), vec![Value::Int(0)].into());
+        assert_eq!(store.get("k"), None);
+
+        let ops = BitFieldOp::parse_all(&args(&["set", "u8", "0", "1", "get", "u8", "8000000"]));
+        assert_eq!(
+            bitfield(&mut store, "k", &ops.unwrap()),
+            vec![Value::Int(0), Value::Int(0)].into()
+        );
+        assert_eq!(store.get_string("k"), Ok(Some(&vec![1])));
+    }
+}
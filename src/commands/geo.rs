@@ -0,0 +1,1026 @@
+//! Geo indexes are sorted sets whose scores are 52 bit geohashes: 26 bits of
+//! latitude interleaved with 26 bits of longitude. Searches cover the shape
+//! with the geohash cell of the centre and its eight neighbours, scan the
+//! matching score ranges, then filter on the exact distance.
+
+use std::collections::BTreeSet;
+
+use crate::message::{float, int, CommandError};
+use crate::parser::resp::{BulkString, Value};
+use crate::pubsub::EventClass;
+use crate::store::{DurableValue, Object, SortedSet, Store};
+
+const STEP_MAX: u32 = 26;
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+/// Latitudes are limited to what EPSG:3857 (web mercator) can represent.
+const LAT_MIN: f64 = -85.051_128_78;
+const LAT_MAX: f64 = 85.051_128_78;
+const EARTH_RADIUS_IN_METERS: f64 = 6_372_797.560_856;
+const MERCATOR_MAX: f64 = 20_037_726.37;
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Meters,
+    Kilometers,
+    Miles,
+    Feet,
+}
+
+impl Unit {
+    pub fn parse(arg: &BulkString) -> Result<Self, CommandError> {
+        match &arg.inner().to_lowercase()[..] {
+            "m" => Ok(Unit::Meters),
+            "km" => Ok(Unit::Kilometers),
+            "mi" => Ok(Unit::Miles),
+            "ft" => Ok(Unit::Feet),
+            _ => Err(CommandError::Invalid(
+                "unsupported unit provided. please use M, KM, FT, MI".into(),
+            )),
+        }
+    }
+
+    fn to_meters(self) -> f64 {
+        match self {
+            Unit::Meters => 1.0,
+            Unit::Kilometers => 1000.0,
+            Unit::Miles => 1609.34,
+            Unit::Feet => 0.3048,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Member(Vec<u8>),
+    LonLat(f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoSearch {
+    pub origin: Origin,
+    pub shape: Shape,
+    pub unit: Unit,
+    pub order: Option<Order>,
+    pub count: Option<usize>,
+    pub any: bool,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+    /// `GEOSEARCHSTORE` only: store distances instead of geohashes as scores.
+    pub store_dist: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoAdd {
+    pub nx: bool,
+    pub xx: bool,
+    pub ch: bool,
+    pub items: Vec<(f64, f64, Vec<u8>)>,
+}
+
+impl GeoAdd {
+    pub fn parse(args: &[BulkString]) -> Result<Self, CommandError> {
+        let (mut nx, mut xx, mut ch) = (false, false, false);
+        let mut rest = args;
+        while let Some((option, tail)) = rest.split_first() {
+            match &option.inner().to_lowercase()[..] {
+                "nx" => nx = true,
+                "xx" => xx = true,
+                "ch" => ch = true,
+                _ => break,
+            }
+            rest = tail;
+        }
+        if nx && xx {
+            return Err(CommandError::Invalid(
+                "XX and NX options at the same time are not compatible".into(),
+            ));
+        }
+        if rest.is_empty() || !rest.len().is_multiple_of(3) {
+            return Err(CommandError::Syntax);
+        }
+
+        let items = rest
+            .chunks(3)
+            .map(|item| {
+                let (lon, lat) = (float(&item[0])?, float(&item[1])?);
+                validate(lon, lat)?;
+                Ok((lon, lat, item[2].as_bytes().to_vec()))
+            })
+            .collect::<Result<_, CommandError>>()?;
+
+        Ok(Self { nx, xx, ch, items })
+    }
+}
+
+fn validate(lon: f64, lat: f64) -> Result<(), CommandError> {
+    if (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat) {
+        Ok(())
+    } else {
+        Err(CommandError::Invalid(format!(
+            "invalid longitude,latitude pair {lon:.6},{lat:.6}"
+        )))
+    }
+}
+
+impl GeoSearch {
+    pub fn parse(args: &[BulkString], store: bool) -> Result<Self, CommandError> {
+        let (mut origin, mut shape) = (None, None);
+        let mut search = GeoSearch {
+            origin: Origin::LonLat(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            unit: Unit::Meters,
+            order: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store_dist: false,
+        };
+
+        let mut rest = args;
+        while let Some((option, tail)) = rest.split_first() {
+            rest = match (&option.inner().to_lowercase()[..], tail) {
+                ("frommember", [member, tail @ ..]) if origin.is_none() => {
+                    origin = Some(Origin::Member(member.as_bytes().to_vec()));
+                    tail
+                }
+                ("fromlonlat", [lon, lat, tail @ ..]) if origin.is_none() => {
+                    let (lon, lat) = (float(lon)?, float(lat)?);
+                    validate(lon, lat)?;
+                    origin = Some(Origin::LonLat(lon, lat));
+                    tail
+                }
+                ("frommember" | "fromlonlat", _) if origin.is_some() => {
+                    return Err(CommandError::Invalid(
+                        "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+                            .into(),
+                    ))
+                }
+                ("byradius", [radius, unit, tail @ ..]) if shape.is_none() => {
+                    let radius = float(radius)?;
+                    if radius < 0.0 {
+                        return Err(CommandError::Invalid("radius cannot be negative".into()));
+                    }
+                    shape = Some(Shape::Radius(radius));
+                    search.unit = Unit::parse(unit)?;
+                    tail
+                }
+                ("bybox", [width, height, unit, tail @ ..]) if shape.is_none() => {
+                    let (width, height) = (float(width)?, float(height)?);
+                    if width < 0.0 || height < 0.0 {
+                        return Err(CommandError::Invalid(
+                            "height or width cannot be negative".into(),
+                        ));
+                    }
+                    shape = Some(Shape::Box { width, height });
+                    search.unit = Unit::parse(unit)?;
+                    tail
+                }
+                ("byradius" | "bybox", _) if shape.is_some() => return Err(CommandError::Invalid(
+                    "exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH"
+                        .into(),
+                )),
+                ("asc", tail) => {
+                    search.order = Some(Order::Asc);
+                    tail
+                }
+                ("desc", tail) => {
+                    search.order = Some(Order::Desc);
+                    tail
+                }
+                ("count", [count, tail @ ..]) => {
+                    let count: i64 = int(count)?;
+                    if count <= 0 {
+                        return Err(CommandError::Invalid("COUNT must be > 0".into()));
+                    }
+                    search.count = Some(count as usize);
+                    match tail.split_first() {
+                        Some((any, tail)) if any.inner().to_lowercase() == "any" => {
+                            search.any = true;
+                            tail
+                        }
+                        _ => tail,
+                    }
+                }
+                ("withcoord", tail) if !store => {
+                    search.with_coord = true;
+                    tail
+                }
+                ("withdist", tail) if !store => {
+                    search.with_dist = true;
+                    tail
+                }
+                ("withhash", tail) if !store => {
+                    search.with_hash = true;
+                    tail
+                }
+                ("storedist", tail) if store => {
+                    search.store_dist = true;
+                    tail
+                }
+                _ => return Err(CommandError::Syntax),
+            };
+        }
+
+        search.origin = origin.ok_or_else(|| {
+            CommandError::Invalid(
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH".into(),
+            )
+        })?;
+        search.shape = shape.ok_or_else(|| {
+            CommandError::Invalid(
+                "exactly one of BYRADIUS and BYBOX arguments must be provided for GEOSEARCH".into(),
+            )
+        })?;
+        if search.any && search.count.is_none() {
+            return Err(CommandError::Invalid(
+                "the ANY argument requires COUNT argument".into(),
+            ));
+        }
+        Ok(search)
+    }
+}
+
+/// A geohash of `step` bits per coordinate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Hash {
+    bits: u64,
+    step: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Area {
+    lon: (f64, f64),
+    lat: (f64, f64),
+}
+
+/// Spreads the bits of `x` into the even bit positions of the result.
+fn spread(x: u32) -> u64 {
+    (0..32).fold(0, |acc, i| acc | ((x as u64 >> i) & 1) << (2 * i))
+}
+
+fn squash(x: u64) -> u32 {
+    (0..32).fold(0, |acc, i| acc | (((x >> (2 * i)) & 1) as u32) << i)
+}
+
+fn encode(lon: f64, lat: f64, step: u32, lat_range: (f64, f64)) -> Hash {
+    let cells = (1u64 << step) as f64;
+    let lat_offset = (lat - lat_range.0) / (lat_range.1 - lat_range.0) * cells;
+    let lon_offset = (lon - LON_MIN) / (LON_MAX - LON_MIN) * cells;
+
+    Hash {
+        bits: spread(lat_offset as u32) | spread(lon_offset as u32) << 1,
+        step,
+    }
+}
+
+fn decode(hash: Hash) -> Area {
+    let cells = (1u64 << hash.step) as f64;
+    let lat = squash(hash.bits) as f64;
+    let lon = squash(hash.bits >> 1) as f64;
+    let (lat_scale, lon_scale) = (LAT_MAX - LAT_MIN, LON_MAX - LON_MIN);
+
+    Area {
+        lat: (
+            LAT_MIN + lat / cells * lat_scale,
+            LAT_MIN + (lat + 1.0) / cells * lat_scale,
+        ),
+        lon: (
+            LON_MIN + lon / cells * lon_scale,
+            LON_MIN + (lon + 1.0) / cells * lon_scale,
+        ),
+    }
+}
+
+/// The centre of the cell a stored score refers to.
+fn score_to_lonlat(score: f64) -> (f64, f64) {
+    let area = decode(Hash {
+        bits: score as u64,
+        step: STEP_MAX,
+    });
+    let lon = ((area.lon.0 + area.lon.1) / 2.0).clamp(LON_MIN, LON_MAX);
+    let lat = ((area.lat.0 + area.lat.1) / 2.0).clamp(LAT_MIN, LAT_MAX);
+    (lon, lat)
+}
+
+fn lonlat_to_score(lon: f64, lat: f64) -> f64 {
+    encode(lon, lat, STEP_MAX, (LAT_MIN, LAT_MAX)).bits as f64
+}
+
+/// Moves a hash one cell east (`d > 0`) or west (`d < 0`).
+fn move_x(hash: Hash, d: i8) -> Hash {
+    if d == 0 {
+        return hash;
+    }
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0x5555_5555_5555_5555u64 >> (64 - hash.step * 2);
+    let x = if d > 0 {
+        x.wrapping_add(zz + 1)
+    } else {
+        (x | zz).wrapping_sub(zz + 1)
+    } & (0xaaaa_aaaa_aaaa_aaaa >> (64 - hash.step * 2));
+
+    Hash {
+        bits: x | y,
+        ..hash
+    }
+}
+
+/// Moves a hash one cell north (`d > 0`) or south (`d < 0`).
+fn move_y(hash: Hash, d: i8) -> Hash {
+    if d == 0 {
+        return hash;
+    }
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0xaaaa_aaaa_aaaa_aaaau64 >> (64 - hash.step * 2);
+    let y = if d > 0 {
+        y.wrapping_add(zz + 1)
+    } else {
+        (y | zz).wrapping_sub(zz + 1)
+    } & (0x5555_5555_5555_5555 >> (64 - hash.step * 2));
+
+    Hash {
+        bits: x | y,
+        ..hash
+    }
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+/// Haversine distance in meters.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+fn estimate_steps(mut range: f64, lat: f64) -> u32 {
+    if range == 0.0 {
+        return STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // make sure the range is included in most of the base cases
+    step -= 2;
+
+    // cells get narrower towards the poles
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP_MAX as i32) as u32
+}
+
+impl Shape {
+    /// Half extents of the shape, in meters.
+    fn half_extents(&self, unit: Unit) -> (f64, f64) {
+        let conversion = unit.to_meters();
+        match *self {
+            Shape::Radius(radius) => (radius * conversion, radius * conversion),
+            Shape::Box { width, height } => (width / 2.0 * conversion, height / 2.0 * conversion),
+        }
+    }
+
+    /// The distance from the centre to `(lon, lat)` in meters, if the point lies inside the shape.
+    fn contains(&self, unit: Unit, (x1, y1): (f64, f64), (x2, y2): (f64, f64)) -> Option<f64> {
+        let (half_width, half_height) = self.half_extents(unit);
+        match self {
+            Shape::Radius(_) => Some(distance(x1, y1, x2, y2)).filter(|d| *d <= half_width),
+            Shape::Box { .. } => {
+                if lat_distance(y2, y1) > half_height || distance(x2, y2, x1, y2) > half_width {
+                    return None;
+                }
+                Some(distance(x1, y1, x2, y2))
+            }
+        }
+    }
+
+    /// The score ranges that together cover the shape around `(lon, lat)`.
+    fn score_ranges(&self, unit: Unit, (lon, lat): (f64, f64)) -> BTreeSet<(u64, u64)> {
+        let (half_width, half_height) = self.half_extents(unit);
+        let lat_delta = (half_height / EARTH_RADIUS_IN_METERS).to_degrees();
+        let lon_delta_top =
+            (half_width / EARTH_RADIUS_IN_METERS / (lat + lat_delta).to_radians().cos())
+                .to_degrees();
+        let lon_delta_bottom =
+            (half_width / EARTH_RADIUS_IN_METERS / (lat - lat_delta).to_radians().cos())
+                .to_degrees();
+        // the hemispheres widen in opposite directions
+        let lon_delta = if lat < 0.0 {
+            lon_delta_bottom
+        } else {
+            lon_delta_top
+        };
+        let (min_lon, max_lon) = (lon - lon_delta, lon + lon_delta);
+        let (min_lat, max_lat) = (lat - lat_delta, lat + lat_delta);
+
+        let radius = match self {
+            Shape::Radius(_) => half_width,
+            Shape::Box { .. } => half_width.hypot(half_height),
+        };
+        let mut step = estimate_steps(radius, lat);
+        let mut hash = encode(lon, lat, step, (LAT_MIN, LAT_MAX));
+
+        // near the edges of the centre cell a neighbour may be too small to
+        // reach the end of the search area, so use bigger cells
+        let north = decode(move_y(hash, 1));
+        let south = decode(move_y(hash, -1));
+        let east = decode(move_x(hash, 1));
+        let west = decode(move_x(hash, -1));
+        if step > 1
+            && (north.lat.1 < max_lat
+                || south.lat.0 > min_lat
+                || east.lon.1 < max_lon
+                || west.lon.0 > min_lon)
+        {
+            step -= 1;
+            hash = encode(lon, lat, step, (LAT_MIN, LAT_MAX));
+        }
+        let area = decode(hash);
+
+        let mut cells = Vec::with_capacity(9);
+        for dx in [-1, 0, 1] {
+            for dy in [-1, 0, 1] {
+                // skip neighbours lying entirely outside the search area
+                let useless = step >= 2
+                    && ((dy < 0 && area.lat.0 < min_lat)
+                        || (dy > 0 && area.lat.1 > max_lat)
+                        || (dx < 0 && area.lon.0 < min_lon)
+                        || (dx > 0 && area.lon.1 > max_lon));
+                if !useless {
+                    cells.push(move_y(move_x(hash, dx), dy));
+                }
+            }
+        }
+
+        let shift = (STEP_MAX - step) * 2;
+        cells
+            .into_iter()
+            .map(|cell| (cell.bits << shift, (cell.bits + 1) << shift))
+            .collect()
+    }
+}
+
+/// Formats a coordinate the way Redis replies with long doubles.
+fn coordinate(value: f64) -> Value {
+    let formatted = format!("{value:.17}");
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    BulkString::from(trimmed).into()
+}
+
+fn geohash_string(score: f64) -> String {
+    let (lon, lat) = score_to_lonlat(score);
+    // the standard geohash alphabet assumes latitudes over the full -90..90 range
+    let hash = encode(lon, lat, STEP_MAX, (-90.0, 90.0));
+
+    (0..11)
+        .map(|i| {
+            let index = if i == 10 {
+                0
+            } else {
+                (hash.bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            BASE32[index as usize] as char
+        })
+        .collect()
+}
+
+pub fn geoadd(store: &mut Store, key: &str, add: GeoAdd) -> Value {
+    let set = match store.zset_mut(key) {
+        Ok(set) => set,
+        Err(err) => return err.into(),
+    };
+
+    let mut changed = 0;
+    let mut updated = false;
+    for (lon, lat, member) in add.items {
+        let score = lonlat_to_score(lon, lat);
+        match set.score(&member) {
+            Some(_) if add.nx => {}
+            None if add.xx => {}
+            Some(previous) => {
+                set.insert(member, score);
+                if previous != score {
+                    updated = true;
+                    changed += add.ch as isize;
+                }
+            }
+            None => {
+                set.insert(member, score);
+                updated = true;
+                changed += 1;
+            }
+        }
+    }
+    // XX against a missing key must not leave an empty set behind
+    if set.is_empty() {
+        store.remove(key);
+    }
+    if updated {
+        store.notify(EventClass::ZSet, "zadd", key);
+    }
+
+    Value::Int(changed)
+}
+
+pub fn geodist(store: &mut Store, key: &str, from: &[u8], to: &[u8], unit: Unit) -> Value {
+    let set = match store.get_zset(key) {
+        Ok(Some(set)) => set,
+        Ok(None) => return BulkString::Null.into(),
+        Err(err) => return err.into(),
+    };
+
+    match set.score(from).zip(set.score(to)) {
+        Some((from, to)) => {
+            let (x1, y1) = score_to_lonlat(from);
+            let (x2, y2) = score_to_lonlat(to);
+            let dist = distance(x1, y1, x2, y2) / unit.to_meters();
+            BulkString::from(format!("{dist:.4}")).into()
+        }
+        None => BulkString::Null.into(),
+    }
+}
+
+fn members_reply(
+    store: &mut Store,
+    key: &str,
+    members: &[Vec<u8>],
+    reply: impl Fn(f64) -> Value,
+    missing: Value,
+) -> Value {
+    match store.get_zset(key) {
+        Ok(set) => members
+            .iter()
+            .map(|member| match set.and_then(|set| set.score(member)) {
+                Some(score) => reply(score),
+                None => missing.clone(),
+            })
+            .collect::<Vec<_>>()
+            .into(),
+        Err(err) => err.into(),
+    }
+}
+
+pub fn geopos(store: &mut Store, key: &str, members: &[Vec<u8>]) -> Value {
+    members_reply(
+        store,
+        key,
+        members,
+        |score| {
+            let (lon, lat) = score_to_lonlat(score);
+            vec![coordinate(lon), coordinate(lat)].into()
+        },
+        crate::parser::resp::Array::Null.into(),
+    )
+}
+
+pub fn geohash(store: &mut Store, key: &str, members: &[Vec<u8>]) -> Value {
+    members_reply(
+        store,
+        key,
+        members,
+        |score| BulkString::from(geohash_string(score)).into(),
+        BulkString::Null.into(),
+    )
+}
+
+struct Hit {
+    member: Vec<u8>,
+    score: f64,
+    dist: f64,
+}
+
+fn search(set: &SortedSet, search: &GeoSearch) -> Result<Vec<Hit>, Value> {
+    let center = match &search.origin {
+        Origin::LonLat(lon, lat) => (*lon, *lat),
+        Origin::Member(member) => match set.score(member) {
+            Some(score) => score_to_lonlat(score),
+            None => {
+                return Err(
+                    CommandError::Invalid("could not decode requested zset member".into()).into(),
+                )
+            }
+        },
+    };
+
+    let mut hits = Vec::new();
+    'ranges: for (min, max) in search.shape.score_ranges(search.unit, center) {
+        for (member, score) in set.range(min as f64, max as f64) {
+            if let Some(dist) = search
+                .shape
+                .contains(search.unit, center, score_to_lonlat(score))
+            {
+                hits.push(Hit {
+                    member: member.to_vec(),
+                    score,
+                    dist,
+                });
+                if search.any && Some(hits.len()) == search.count {
+                    break 'ranges;
+                }
+            }
+        }
+    }
+
+    // a plain COUNT returns the closest matches
+    let order = match search.order {
+        None if search.count.is_some() && !search.any => Some(Order::Asc),
+        order => order,
+    };
+    match order {
+        Some(Order::Asc) => hits.sort_by(|a, b| a.dist.total_cmp(&b.dist)),
+        Some(Order::Desc) => hits.sort_by(|a, b| b.dist.total_cmp(&a.dist)),
+        None => {}
+    }
+    if let Some(count) = search.count {
+        hits.truncate(count);
+    }
+    Ok(hits)
+}
+
+pub fn geosearch(store: &mut Store, key: &str, query: &GeoSearch) -> Value {
+    let hits = match store.get_zset(key) {
+        Ok(Some(set)) => match search(set, query) {
+            Ok(hits) => hits,
+            Err(err) => return err,
+        },
+        Ok(None) => Vec::new(),
+        Err(err) => return err.into(),
+    };
+    let conversion = query.unit.to_meters();
+
+    hits.into_iter()
+        .map(|hit| {
+            let member = BulkString::from(hit.member).into();
+            if !(query.with_dist || query.with_hash || query.with_coord) {
+                return member;
+            }
+            let mut item = vec![member];
+            if query.with_dist {
+                item.push(BulkString::from(format!("{:.4}", hit.dist / conversion)).into());
+            }
+            if query.with_hash {
+                item.push(Value::Int(hit.score as isize));
+            }
+            if query.with_coord {
+                let (lon, lat) = score_to_lonlat(hit.score);
+                item.push(vec![coordinate(lon), coordinate(lat)].into());
+            }
+            item.into()
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+pub fn geosearchstore(store: &mut Store, dest: &str, key: &str, query: &GeoSearch) -> Value {
+    let hits = match store.get_zset(key) {
+        Ok(Some(set)) => match search(set, query) {
+            Ok(hits) => hits,
+            Err(err) => return err,
+        },
+        Ok(None) => Vec::new(),
+        Err(err) => return err.into(),
+    };
+    let conversion = query.unit.to_meters();
+    let count = hits.len();
+
+    if hits.is_empty() {
+        if store.remove(dest).is_some() {
+            store.notify(EventClass::Generic, "del", dest);
+        }
+    } else {
+        let set = hits
+            .into_iter()
+            .map(|hit| {
+                let score = if query.store_dist {
+                    hit.dist / conversion
+                } else {
+                    hit.score
+                };
+                (hit.member, score)
+            })
+            .collect();
+        store.insert(dest.to_string(), DurableValue::new(Object::ZSet(set)));
+        store.notify(EventClass::ZSet, "geosearchstore", dest);
+    }
+    Value::Int(count as isize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(items: &[&str]) -> Vec<BulkString> {
+        items.iter().map(|item| BulkString::from(*item)).collect()
+    }
+
+    fn sicily() -> Store {
+        let mut store = Store::default();
+        let add = GeoAdd::parse(&args(&[
+            "13.361389",
+            "38.115556",
+            "Palermo",
+            "15.087269",
+            "37.502669",
+            "Catania",
+        ]))
+        .unwrap();
+        assert_eq!(geoadd(&mut store, "Sicily", add), Value::Int(2));
+        store
+    }
+
+    fn members(value: Value) -> Vec<Value> {
+        match value {
+            Value::Array(crate::parser::resp::Array::Items(items)) => items,
+            Value::Array(_) => Vec::new(),
+            other => panic!("expected an array, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn scores_match_redis() {
+        assert_eq!(lonlat_to_score(13.361389, 38.115556), 3479099956230698.0);
+        assert_eq!(lonlat_to_score(15.087269, 37.502669), 3479447370796909.0);
+    }
+
+    #[test]
+    fn geodist_units() {
+        let mut store = sicily();
+        let dist = |store: &mut Store, unit| geodist(store, "Sicily", b"Palermo", b"Catania", unit);
+
+        assert_eq!(
+            dist(&mut store, Unit::Meters),
+            BulkString::from("166274.1516").into()
+        );
+        assert_eq!(
+            dist(&mut store, Unit::Kilometers),
+            BulkString::from("166.2742").into()
+        );
+        assert_eq!(
+            dist(&mut store, Unit::Miles),
+            BulkString::from("103.3182").into()
+        );
+        assert_eq!(
+            geodist(&mut store, "Sicily", b"Palermo", b"Agrigento", Unit::Meters),
+            BulkString::Null.into()
+        );
+    }
+
+    #[test]
+    fn geopos_and_geohash() {
+        let mut store = sicily();
+        let members = vec![b"Palermo".to_vec(), b"NonExisting".to_vec()];
+
+        assert_eq!(
+            geopos(&mut store, "Sicily", &members),
+            vec![
+                vec![
+                    BulkString::from("13.36138933897018433").into(),
+                    BulkString::from("38.11555639549629859").into()
+                ]
+                .into(),
+                crate::parser::resp::Array::Null.into(),
+            ]
+            .into()
+        );
+        assert_eq!(
+            geohash(
+                &mut store,
+                "Sicily",
+                &[b"Palermo".to_vec(), b"Catania".to_vec()]
+            ),
+            vec![
+                BulkString::from("sqc8b49rny0").into(),
+                BulkString::from("sqdtr74hyu0").into()
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn geoadd_options() {
+        let mut store = sicily();
+        let add = |items: &[&str]| GeoAdd::parse(&args(items)).unwrap();
+
+        assert_eq!(
+            geoadd(
+                &mut store,
+                "Sicily",
+                add(&["xx", "ch", "13.5", "38.1", "Palermo", "1", "1", "x"])
+            ),
+            Value::Int(1)
+        );
+        assert_eq!(
+            geoadd(
+                &mut store,
+                "Sicily",
+                add(&["nx", "13.5", "38.1", "Palermo", "1", "1", "x"])
+            ),
+            Value::Int(1)
+        );
+        assert!(GeoAdd::parse(&args(&["nx", "xx", "1", "1", "a"])).is_err());
+        assert!(GeoAdd::parse(&args(&["1", "89", "a"])).is_err());
+        assert!(GeoAdd::parse(&args(&["1", "1"])).is_err());
+    }
+
+    #[test]
+    fn geosearch_radius_and_box() {
+        let mut store = sicily();
+        let add = GeoAdd::parse(&args(&[
+            "12.758489",
+            "38.788135",
+            "edge1",
+            "17.241510",
+            "38.788135",
+            "edge2",
+        ]))
+        .unwrap();
+        geoadd(&mut store, "Sicily", add);
+
+        let query = |items: &[&str]| GeoSearch::parse(&args(items), false).unwrap();
+
+        assert_eq!(
+            geosearch(
+                &mut store,
+                "Sicily",
+                &query(&["fromlonlat", "15", "37", "byradius", "200", "km", "asc"])
+            ),
+            vec![
+                BulkString::from("Catania").into(),
+                BulkString::from("Palermo").into()
+            ]
+            .into()
+        );
+        assert_eq!(
+            geosearch(
+                &mut store,
+                "Sicily",
+                &query(&[
+                    "fromlonlat",
+                    "15",
+                    "37",
+                    "bybox",
+                    "400",
+                    "400",
+                    "km",
+                    "desc"
+                ])
+            ),
+            vec![
+                BulkString::from("edge1").into(),
+                BulkString::from("edge2").into(),
+                BulkString::from("Palermo").into(),
+                BulkString::from("Catania").into()
+            ]
+            .into()
+        );
+
+        let hits = members(geosearch(
+            &mut store,
+            "Sicily",
+            &query(&[
+                "frommember",
+                "Palermo",
+                "byradius",
+                "200",
+                "km",
+                "count",
+                "1",
+                "withdist",
+                "withhash",
+                "withcoord",
+            ]),
+        ));
+        assert_eq!(
+            hits,
+            vec![vec![
+                BulkString::from("Palermo").into(),
+                BulkString::from("0.0000").into(),
+                Value::Int(3479099956230698),
+                vec![
+                    BulkString::from("13.36138933897018433").into(),
+                    BulkString::from("38.11555639549629859").into()
+                ]
+                .into(),
+            ]
+            .into()]
+        );
+    }
+
+    #[test]
+    fn geosearch_options_are_validated() {
+        let parse = |items: &[&str], store| GeoSearch::parse(&args(items), store);
+
+        assert!(parse(&["byradius", "1", "km"], false).is_err());
+        assert!(parse(&["fromlonlat", "1", "1"], false).is_err());
+        assert!(parse(
+            &[
+                "fromlonlat",
+                "1",
+                "1",
+                "frommember",
+                "a",
+                "byradius",
+                "1",
+                "m"
+            ],
+            false
+        )
+        .is_err());
+        assert!(parse(
+            &["fromlonlat", "1", "1", "byradius", "1", "m", "any"],
+            false
+        )
+        .is_err());
+        assert!(parse(
+            &[
+                "fromlonlat",
+                "1",
+                "1",
+                "byradius",
+                "1",
+                "m",
+                "count",
+                "1",
+                "any"
+            ],
+            false
+        )
+        .is_ok());
+        assert!(parse(&["fromlonlat", "1", "1", "byradius", "1", "parsec"], false).is_err());
+        assert!(parse(
+            &["fromlonlat", "1", "1", "byradius", "1", "m", "withdist"],
+            true
+        )
+        .is_err());
+        assert!(parse(
+            &["fromlonlat", "1", "1", "byradius", "1", "m", "storedist"],
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn geosearchstore_scores() {
+        let mut store = sicily();
+        let query = GeoSearch::parse(
+            &args(&[
+                "fromlonlat",
+                "15",
+                "37",
+                "byradius",
+                "200",
+                "km",
+                "storedist",
+            ]),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            geosearchstore(&mut store, "dest", "Sicily", &query),
+            Value::Int(2)
+        );
+        let set = store.get_zset("dest").unwrap().unwrap();
+        assert_eq!(format!("{:.4}", set.score(b"Catania").unwrap()), "56.4413");
+
+        let query =
+            GeoSearch::parse(&args(&["fromlonlat", "0", "0", "byradius", "1", "m"]), true).unwrap();
+        assert_eq!(
+            geosearchstore(&mut store, "dest", "Sicily", &query),
+            Value::Int(0)
+        );
+        assert_eq!(store.get("dest"), None);
+    }
+}
@@ -0,0 +1,583 @@
+//! HyperLogLogs stored as plain strings in the same layout Redis uses, so the
+//! values survive a round trip through an RDB file shared with a real server.
+//!
+//! A value is a 16 byte header (`HYLL`, encoding, 3 unused bytes and a little
+//! endian cached cardinality whose top bit marks it stale) followed by 2^14
+//! six bit registers, either densely packed or run-length encoded (sparse).
+
+use crate::parser::resp::Value;
+use crate::pubsub::EventClass;
+use crate::store::{Store, WrongType};
+
+const MAGIC: &[u8; 4] = b"HYLL";
+const P: u32 = 14;
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << BITS) - 1;
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * BITS).div_ceil(8);
+/// Sparse values larger than this are promoted to the dense encoding.
+const SPARSE_MAX_BYTES: usize = 3000;
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const SEED: u64 = 0xadc8_3b19;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Dense = 0,
+    Sparse = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HllError {
+    /// The string is not a HyperLogLog at all.
+    WrongType,
+    /// The header is fine but the registers cannot be decoded.
+    Corrupted,
+}
+
+impl From<HllError> for Value {
+    fn from(err: HllError) -> Self {
+        match err {
+            HllError::WrongType => {
+                Value::error("WRONGTYPE", "Key is not a valid HyperLogLog string value.")
+            }
+            HllError::Corrupted => Value::error("INVALIDOBJ", "Corrupted HLL object detected"),
+        }
+    }
+}
+
+impl From<WrongType> for HllError {
+    fn from(_: WrongType) -> Self {
+        HllError::WrongType
+    }
+}
+
+/// MurmurHash64A, the hash Redis feeds HyperLogLog elements through.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The register an element maps to and the length of the run of zeros,
+/// plus one, in the remaining hash bits.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, SEED);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // the sentinel bit bounds the count at Q + 1
+    let rest = (hash >> P) | (1 << Q);
+
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(registers: &[u8], index: usize) -> u8 {
+    let bit = index * BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let b0 = registers[byte] as u16;
+    let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+
+    (((b0 >> shift) | (b1 << (8 - shift))) & REGISTER_MAX as u16) as u8
+}
+
+fn dense_set(registers: &mut [u8], index: usize, value: u8) {
+    let bit = index * BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let value = value as u16;
+
+    registers[byte] &= !((REGISTER_MAX as u16) << shift) as u8;
+    registers[byte] |= (value << shift) as u8;
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next &= !((REGISTER_MAX as u16) >> (8 - shift)) as u8;
+        *next |= (value >> (8 - shift)) as u8;
+    }
+}
+
+/// Expands a sparse register stream into one byte per register.
+fn sparse_decode(data: &[u8]) -> Result<Vec<u8>, HllError> {
+    let mut registers = Vec::with_capacity(REGISTERS);
+    let mut i = 0;
+
+    while i < data.len() {
+        let op = data[i];
+        let (value, len) = match op >> 6 {
+            // ZERO: 00xxxxxx
+            0b00 => (0, (op & 0x3f) as usize + 1),
+            // XZERO: 01xxxxxx yyyyyyyy
+            0b01 => {
+                i += 1;
+                let low = *data.get(i).ok_or(HllError::Corrupted)? as usize;
+                (0, ((op as usize & 0x3f) << 8 | low) + 1)
+            }
+            // VAL: 1vvvvvxx
+            _ => (((op >> 2) & 0x1f) + 1, (op & 0x3) as usize + 1),
+        };
+        if registers.len() + len > REGISTERS {
+            return Err(HllError::Corrupted);
+        }
+        registers.resize(registers.len() + len, value);
+        i += 1;
+    }
+
+    if registers.len() != REGISTERS {
+        return Err(HllError::Corrupted);
+    }
+    Ok(registers)
+}
+
+/// Run-length encodes registers, or returns `None` when a register is too
+/// large for the sparse representation.
+fn sparse_encode(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < registers.len() {
+        let value = registers[i];
+        let run = registers[i..].iter().take_while(|&&r| r == value).count();
+
+        if value == 0 {
+            let mut left = run;
+            while left > 0 {
+                if left > SPARSE_ZERO_MAX_LEN {
+                    let len = left.min(SPARSE_XZERO_MAX_LEN);
+                    out.push(0x40 | ((len - 1) >> 8) as u8);
+                    out.push(((len - 1) & 0xff) as u8);
+                    left -= len;
+                } else {
+                    out.push((left - 1) as u8);
+                    left = 0;
+                }
+            }
+        } else {
+            if value > SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            let mut left = run;
+            while left > 0 {
+                let len = left.min(SPARSE_VAL_MAX_LEN);
+                out.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                left -= len;
+            }
+        }
+        i += run;
+    }
+    Some(out)
+}
+
+/// Ertl's improved estimator, as used by Redis since 5.0.
+fn estimate(histogram: &[u32; 64]) -> u64 {
+    fn tau(mut x: f64) -> f64 {
+        if x == 0. || x == 1. {
+            return 0.;
+        }
+        let mut y = 1.0;
+        let mut z = 1. - x;
+        loop {
+            x = x.sqrt();
+            let prev = z;
+            y *= 0.5;
+            z -= (1. - x).powi(2) * y;
+            if prev == z {
+                return z / 3.;
+            }
+        }
+    }
+
+    fn sigma(mut x: f64) -> f64 {
+        if x == 1. {
+            return f64::INFINITY;
+        }
+        let mut y = 1.;
+        let mut z = x;
+        loop {
+            x *= x;
+            let prev = z;
+            z += x * y;
+            y += y;
+            if prev == z {
+                return z;
+            }
+        }
+    }
+
+    let m = REGISTERS as f64;
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for j in (1..=Q as usize).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Hll {
+    encoding: Encoding,
+    /// The raw value, header included.
+    bytes: Vec<u8>,
+}
+
+impl Hll {
+    fn new() -> Self {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + 2);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&[Encoding::Sparse as u8, 0, 0, 0]);
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend(sparse_encode(&[0; REGISTERS]).unwrap());
+
+        Self {
+            encoding: Encoding::Sparse,
+            bytes,
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, HllError> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return Err(HllError::WrongType);
+        }
+        let encoding = match bytes[4] {
+            0 if bytes.len() == DENSE_SIZE => Encoding::Dense,
+            1 => Encoding::Sparse,
+            _ => return Err(HllError::WrongType),
+        };
+
+        Ok(Self {
+            encoding,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    fn registers(&self) -> Result<Vec<u8>, HllError> {
+        let data = &self.bytes[HEADER_SIZE..];
+        match self.encoding {
+            Encoding::Dense => Ok((0..REGISTERS).map(|i| dense_get(data, i)).collect()),
+            Encoding::Sparse => sparse_decode(data),
+        }
+    }
+
+    /// Re-encodes the value from `registers`, staying sparse when allowed and possible.
+    fn set_registers(&mut self, registers: &[u8], allow_sparse: bool) {
+        self.bytes.truncate(HEADER_SIZE);
+        let sparse = sparse_encode(registers)
+            .filter(|data| allow_sparse && HEADER_SIZE + data.len() <= SPARSE_MAX_BYTES);
+
+        match sparse {
+            Some(data) => {
+                self.encoding = Encoding::Sparse;
+                self.bytes.extend(data);
+            }
+            None => {
+                self.encoding = Encoding::Dense;
+                self.bytes.resize(DENSE_SIZE, 0);
+                for (i, value) in registers.iter().enumerate() {
+                    dense_set(&mut self.bytes[HEADER_SIZE..], i, *value);
+                }
+            }
+        }
+        self.bytes[4] = self.encoding as u8;
+        self.invalidate_cache();
+    }
+
+    /// Adds elements, returning whether any register changed.
+    fn add<'a>(&mut self, elements: impl Iterator<Item = &'a [u8]>) -> Result<bool, HllError> {
+        let mut changed = false;
+        match self.encoding {
+            Encoding::Dense => {
+                let data = &mut self.bytes[HEADER_SIZE..];
+                for (index, count) in elements.map(pattern_len) {
+                    if count > dense_get(data, index) {
+                        dense_set(data, index, count);
+                        changed = true;
+                    }
+                }
+                if changed {
+                    self.invalidate_cache();
+                }
+            }
+            Encoding::Sparse => {
+                let mut registers = self.registers()?;
+                for (index, count) in elements.map(pattern_len) {
+                    if count > registers[index] {
+                        registers[index] = count;
+                        changed = true;
+                    }
+                }
+                if changed {
+                    self.set_registers(&registers, true);
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn cached(&self) -> Option<u64> {
+        let card = u64::from_le_bytes(self.bytes[8..16].try_into().unwrap());
+        (self.bytes[15] & 0x80 == 0).then_some(card)
+    }
+
+    fn set_cache(&mut self, card: u64) {
+        self.bytes[8..16].copy_from_slice(&card.to_le_bytes());
+    }
+
+    fn invalidate_cache(&mut self) {
+        self.bytes[15] |= 0x80;
+    }
+}
+
+fn count(registers: &[u8]) -> u64 {
+    let mut histogram = [0; 64];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+    estimate(&histogram)
+}
+
+fn lookup(store: &mut Store, key: &str) -> Result<Option<Hll>, HllError> {
+    store
+        .get_string(key)?
+        .map(|bytes| Hll::from_bytes(bytes))
+        .transpose()
+}
+
+fn save(store: &mut Store, key: &str, hll: Hll) {
+    match store.string_mut(key) {
+        Ok(bytes) => *bytes = hll.bytes,
+        Err(WrongType) => unreachable!("HyperLogLog keys are checked before being written"),
+    }
+}
+
+pub fn pfadd(store: &mut Store, key: &str, elements: &[Vec<u8>]) -> Value {
+    let result = lookup(store, key).and_then(|hll| {
+        let created = hll.is_none();
+        let mut hll = hll.unwrap_or_else(Hll::new);
+        let changed = hll.add(elements.iter().map(Vec::as_slice))?;
+        Ok((hll, created || changed))
+    });
+
+    match result {
+        Ok((hll, updated)) => {
+            if updated {
+                save(store, key, hll);
+                store.notify(EventClass::String, "pfadd", key);
+            }
+            Value::Int(updated as isize)
+        }
+        Err(err) => err.into(),
+    }
+}
+
+pub fn pfcount(store: &mut Store, keys: &[String]) -> Value {
+    if let [key] = keys {
+        let result = lookup(store, key).and_then(|hll| match hll {
+            None => Ok(None),
+            Some(hll) if hll.cached().is_some() => Ok(Some((hll, false))),
+            Some(mut hll) => {
+                let card = count(&hll.registers()?);
+                hll.set_cache(card);
+                Ok(Some((hll, true)))
+            }
+        });
+        return match result {
+            Ok(None) => Value::Int(0),
+            Ok(Some((hll, refreshed))) => {
+                let card = hll.cached().unwrap_or_default();
+                // the cache lives in the value itself, so a stale one is written back
+                if refreshed {
+                    save(store, key, hll);
+                }
+                Value::Int(card as isize)
+            }
+            Err(err) => err.into(),
+        };
+    }
+
+    match merged(store, keys) {
+        Ok((registers, _)) => Value::Int(count(&registers) as isize),
+        Err(err) => err.into(),
+    }
+}
+
+/// The register-wise maximum of every HyperLogLog at `keys`, and whether all of them were sparse.
+fn merged(store: &mut Store, keys: &[String]) -> Result<(Vec<u8>, bool), HllError> {
+    let mut registers = vec![0; REGISTERS];
+    let mut all_sparse = true;
+
+    for key in keys {
+        let Some(hll) = lookup(store, key)? else {
+            continue;
+        };
+        all_sparse &= hll.encoding == Encoding::Sparse;
+        for (max, register) in registers.iter_mut().zip(hll.registers()?) {
+            *max = (*max).max(register);
+        }
+    }
+    Ok((registers, all_sparse))
+}
+
+pub fn pfmerge(store: &mut Store, dest: &str, keys: &[String]) -> Value {
+    let sources: Vec<String> = std::iter::once(dest.to_string())
+        .chain(keys.iter().cloned())
+        .collect();
+
+    match merged(store, &sources) {
+        Ok((registers, all_sparse)) => {
+            let mut hll = Hll::new();
+            hll.set_registers(&registers, all_sparse);
+            save(store, dest, hll);
+            store.notify(EventClass::String, "pfadd", dest);
+            Value::ok()
+        }
+        Err(err) => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::{DurableValue, Object};
+
+    fn elements(range: std::ops::Range<u32>, prefix: &str) -> Vec<Vec<u8>> {
+        range.map(|i| format!("{prefix}{i}").into_bytes()).collect()
+    }
+
+    #[test]
+    fn murmurhash_matches_reference() {
+        assert_eq!(murmurhash64a(b"", SEED), 0xd8df_ea65_85bc_9732);
+        assert_eq!(murmurhash64a(b"hello", 0), 0x1e68_d17c_457b_f117);
+        assert_eq!(murmurhash64a(b"foobar12", SEED), 0xb177_92b5_b755_bc81);
+    }
+
+    #[test]
+    fn new_hll_layout() {
+        assert_eq!(
+            Hll::new().bytes,
+            [b'H', b'Y', b'L', b'L', 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x7f, 0xff]
+        );
+    }
+
+    #[test]
+    fn sparse_round_trip() {
+        let mut registers = vec![0; REGISTERS];
+        registers[0] = 3;
+        registers[1] = 3;
+        registers[100] = 32;
+        registers[REGISTERS - 1] = 1;
+
+        let encoded = sparse_encode(&registers).unwrap();
+        assert_eq!(sparse_decode(&encoded).unwrap(), registers);
+
+        registers[5] = 33;
+        assert_eq!(sparse_encode(&registers), None);
+        assert_eq!(sparse_decode(&[0x7f]), Err(HllError::Corrupted));
+    }
+
+    #[test]
+    fn dense_registers() {
+        let mut data = vec![0; DENSE_SIZE - HEADER_SIZE];
+        for i in 0..REGISTERS {
+            dense_set(&mut data, i, (i % 64) as u8);
+        }
+        for i in 0..REGISTERS {
+            assert_eq!(dense_get(&data, i), (i % 64) as u8);
+        }
+    }
+
+    #[test]
+    fn pfadd_and_pfcount() {
+        let mut store = Store::default();
+        let items = elements(0..7, "");
+
+        assert_eq!(pfadd(&mut store, "hll", &items), Value::Int(1));
+        assert_eq!(pfadd(&mut store, "hll", &items), Value::Int(0));
+        assert_eq!(pfadd(&mut store, "empty", &[]), Value::Int(1));
+        assert_eq!(pfcount(&mut store, &["hll".into()]), Value::Int(7));
+        assert_eq!(
+            pfcount(&mut store, &["empty".into(), "missing".into()]),
+            Value::Int(0)
+        );
+    }
+
+    #[test]
+    fn large_sets_promote_to_dense() {
+        let mut store = Store::default();
+        let items = elements(0..100_000, "element:");
+
+        for chunk in items.chunks(1000) {
+            pfadd(&mut store, "hll", chunk);
+        }
+        let bytes = store.get_string("hll").unwrap().unwrap();
+        assert_eq!(bytes.len(), DENSE_SIZE);
+        assert_eq!(bytes[4], Encoding::Dense as u8);
+
+        let Value::Int(card) = pfcount(&mut store, &["hll".into()]) else {
+            panic!("expected an integer");
+        };
+        assert!(
+            (card - 100_000).abs() < 2_000,
+            "estimate {card} too far off"
+        );
+    }
+
+    #[test]
+    fn pfmerge_unions_sources() {
+        let mut store = Store::default();
+        pfadd(&mut store, "a", &elements(0..500, ""));
+        pfadd(&mut store, "b", &elements(250..750, ""));
+
+        assert_eq!(
+            pfmerge(&mut store, "c", &["a".into(), "b".into()]),
+            Value::ok()
+        );
+        let Value::Int(card) = pfcount(&mut store, &["c".into()]) else {
+            panic!("expected an integer");
+        };
+        assert!((card - 750).abs() < 20, "estimate {card} too far off");
+        assert_eq!(
+            pfcount(&mut store, &["a".into(), "b".into()]),
+            Value::Int(card)
+        );
+    }
+
+    #[test]
+    fn rejects_plain_strings() {
+        let mut store = Store::default();
+        store.insert(
+            "s".into(),
+            DurableValue::new(Object::String(b"not an hll".to_vec())),
+        );
+
+        assert_eq!(pfadd(&mut store, "s", &[]), HllError::WrongType.into());
+        assert_eq!(
+            pfcount(&mut store, &["s".into()]),
+            HllError::WrongType.into()
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use crate::message::CommandError;
+use crate::parser::resp::{BulkString, Value};
+use crate::pubsub::EventClass;
+use crate::store::{lazy_free, Store};
+
+pub fn del(store: &mut Store, keys: &[String]) -> Value {
+    let mut removed = 0;
+    for key in keys {
+        if store.remove(key).is_some() {
+            store.notify(EventClass::Generic, "del", key);
+            removed += 1;
+        }
+    }
+    Value::Int(removed)
+}
+
+/// Like `DEL`, but large values are dropped on a background thread.
+pub fn unlink(store: &mut Store, keys: &[String]) -> Value {
+    let mut removed = 0;
+    for key in keys {
+        if let Some(value) = store.remove(key) {
+            lazy_free(value);
+            store.notify(EventClass::Generic, "del", key);
+            removed += 1;
+        }
+    }
+    Value::Int(removed)
+}
+
+/// Counts the keys that exist, so a key given twice is counted twice.
+pub fn exists(store: &mut Store, keys: &[String]) -> Value {
+    let found = keys.iter().filter(|key| store.contains(key)).count();
+    Value::Int(found as isize)
+}
+
+/// Counts the keys that exist like `EXISTS`, marking each as just used.
+pub fn touch(store: &mut Store, keys: &[String]) -> Value {
+    let found = keys.iter().filter(|key| store.touch(key)).count();
+    Value::Int(found as isize)
+}
+
+pub fn key_type(store: &mut Store, key: &str) -> Value {
+    let name = store
+        .get(key)
+        .map(|entry| entry.val.type_name())
+        .unwrap_or("none");
+    Value::String(name.into())
+}
+
+/// Moves `from` to `to` along with its expiration. Returns whether the rename
+/// happened, which with `nx` is only when `to` does not exist.
+fn rename_key(store: &mut Store, from: &str, to: &str, nx: bool) -> Result<bool, Value> {
+    if !store.contains(from) {
+        return Err(CommandError::Invalid("no such key".into()).into());
+    }
+    if from == to {
+        return Ok(!nx);
+    }
+    if nx && store.contains(to) {
+        return Ok(false);
+    }
+    if let Some(value) = store.remove(from) {
+        if let Some(old) = store.remove(to) {
+            lazy_free(old);
+        }
+        store.insert(to.to_string(), value);
+        store.notify(EventClass::Generic, "rename_from", from);
+        store.notify(EventClass::Generic, "rename_to", to);
+    }
+    Ok(true)
+}
+
+pub fn rename(store: &mut Store, from: &str, to: &str) -> Value {
+    match rename_key(store, from, to, false) {
+        Ok(_) => Value::ok(),
+        Err(err) => err,
+    }
+}
+
+pub fn renamenx(store: &mut Store, from: &str, to: &str) -> Value {
+    match rename_key(store, from, to, true) {
+        Ok(renamed) => Value::Int(renamed as isize),
+        Err(err) => err,
+    }
+}
+
+/// Copies `source` into `dest` of `target`, the database `dest` lives in.
+/// `target` is `None` when both keys are in the same database.
+pub fn copy(
+    store: &mut Store,
+    target: Option<&mut Store>,
+    source: &str,
+    dest: &str,
+    replace: bool,
+) -> Value {
+    if target.is_none() && source == dest {
+        return CommandError::Invalid("source and destination objects are the same".into()).into();
+    }
+    let Some(value) = store.get(source).cloned() else {
+        return Value::Int(0);
+    };
+    let target = target.unwrap_or(store);
+
+    if target.contains(dest) {
+        if !replace {
+            return Value::Int(0);
+        }
+        if let Some(old) = target.remove(dest) {
+            lazy_free(old);
+        }
+    }
+    target.insert(dest.to_string(), value);
+    target.notify(EventClass::Generic, "copy_to", dest);
+    Value::Int(1)
+}
+
+pub fn randomkey(store: &mut Store) -> Value {
+    match store.random_key() {
+        Some(key) => BulkString::from(key.as_str()).into(),
+        None => BulkString::Null.into(),
+    }
+}
+
+pub fn dbsize(store: &mut Store) -> Value {
+    Value::Int(store.len() as isize)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::*;
+    use crate::store::{DurableValue, Expiration, Object, Usage};
+
+    fn store_with(keys: &[&str]) -> Store {
+        keys.iter()
+            .map(|key| {
+                (
+                    key.to_string(),
+                    DurableValue::new(Object::String(key.as_bytes().to_vec())),
+                )
+            })
+            .collect()
+    }
+
+    fn keys(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn expired() -> DurableValue {
+        DurableValue {
+            expiration: Expiration::Period {
+                duration: Duration::ZERO,
+                insert_at: Instant::now() - Duration::from_millis(10),
+            },
+            ..DurableValue::new(Object::String(b"gone".to_vec()))
+        }
+    }
+
+    #[test]
+    fn del_exists_and_unlink() {
+        let mut store = store_with(&["a", "b", "c"]);
+        store.insert("old".into(), expired());
+
+        assert_eq!(
+            exists(&mut store, &keys(&["a", "a", "x", "old"])),
+            Value::Int(2)
+        );
+        assert_eq!(del(&mut store, &keys(&["a", "x", "old"])), Value::Int(1));
+        assert_eq!(unlink(&mut store, &keys(&["b", "b"])), Value::Int(1));
+        assert_eq!(dbsize(&mut store), Value::Int(1));
+    }
+
+    #[test]
+    fn dbsize_counts_keys_as_they_change() {
+        let mut store = store_with(&["a", "b"]);
+        store.insert("a".into(), DurableValue::new(Object::String(b"A".to_vec())));
+        store.string_mut("c").unwrap().push(b'c');
+        assert_eq!(dbsize(&mut store), Value::Int(3));
+
+        // expired keys count until something finds them expired
+        store.insert("old".into(), expired());
+        assert_eq!(dbsize(&mut store), Value::Int(4));
+        del(&mut store, &keys(&["a", "b", "c", "old"]));
+        assert_eq!(dbsize(&mut store), Value::Int(0));
+    }
+
+    #[test]
+    fn touch_resets_idle_time() {
+        let mut store = store_with(&["a"]);
+        let used = SystemTime::now() - Duration::from_secs(1000);
+        store.insert(
+            "idle".into(),
+            DurableValue {
+                usage: Usage::Lru(used),
+                ..DurableValue::new(Object::String(b"v".to_vec()))
+            },
+        );
+        store.insert("old".into(), expired());
+
+        assert_eq!(
+            touch(&mut store, &keys(&["idle", "a", "missing", "old"])),
+            Value::Int(2)
+        );
+        let Some(Usage::Lru(touched)) = store.get("idle").map(|entry| entry.usage) else {
+            panic!("touched key has no access time");
+        };
+        assert!(touched.elapsed().unwrap() < Duration::from_secs(10));
+        assert!(matches!(store.get("a").unwrap().usage, Usage::Lru(_)));
+    }
+
+    #[test]
+    fn type_names() {
+        let mut store = store_with(&["s"]);
+        store.insert(
+            "z".into(),
+            DurableValue::new(Object::ZSet(Default::default())),
+        );
+
+        assert_eq!(key_type(&mut store, "s"), Value::String("string".into()));
+        assert_eq!(key_type(&mut store, "z"), Value::String("zset".into()));
+        store.insert(
+            "st".into(),
+            DurableValue::new(Object::Stream(Default::default())),
+        );
+        assert_eq!(key_type(&mut store, "st"), Value::String("stream".into()));
+        assert_eq!(key_type(&mut store, "x"), Value::String("none".into()));
+    }
+
+    #[test]
+    fn rename_keeps_expiration() {
+        let mut store = store_with(&["a", "b"]);
+        let expiration = Expiration::Period {
+            duration: Duration::from_secs(100),
+            insert_at: Instant::now(),
+        };
+        store.insert(
+            "a".into(),
+            DurableValue {
+                expiration: expiration.clone(),
+                ..DurableValue::new(Object::String(b"a".to_vec()))
+            },
+        );
+
+        assert_eq!(renamenx(&mut store, "a", "b"), Value::Int(0));
+        assert_eq!(rename(&mut store, "a", "b"), Value::ok());
+        assert_eq!(store.get("a"), None);
+        assert_eq!(store.get("b").unwrap().expiration, expiration);
+        assert_eq!(renamenx(&mut store, "b", "c"), Value::Int(1));
+        assert_eq!(rename(&mut store, "c", "c"), Value::ok());
+        assert_eq!(renamenx(&mut store, "c", "c"), Value::Int(0));
+        assert!(matches!(
+            rename(&mut store, "missing", "d"),
+            Value::Error(_)
+        ));
+    }
+
+    #[test]
+    fn copy_respects_replace() {
+        let mut store = store_with(&["a", "b"]);
+
+        assert_eq!(copy(&mut store, None, "a", "b", false), Value::Int(0));
+        assert_eq!(copy(&mut store, None, "a", "b", true), Value::Int(1));
+        assert_eq!(store.get_string("b"), Ok(Some(&b"a".to_vec())));
+        assert_eq!(copy(&mut store, None, "missing", "c", false), Value::Int(0));
+        assert!(matches!(
+            copy(&mut store, None, "a", "a", true),
+            Value::Error(_)
+        ));
+
+        let mut other = Store::default();
+        assert_eq!(
+            copy(&mut store, Some(&mut other), "a", "a", false),
+            Value::Int(1)
+        );
+        assert_eq!(other.get_string("a"), Ok(Some(&b"a".to_vec())));
+    }
+
+    #[test]
+    fn randomkey_skips_expired() {
+        let mut store = Store::default();
+        store.insert("old".into(), expired());
+        assert_eq!(randomkey(&mut store), BulkString::Null.into());
+
+        store.insert("a".into(), DurableValue::new(Object::String(vec![])));
+        assert_eq!(randomkey(&mut store), BulkString::from("a").into());
+    }
+}
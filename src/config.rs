@@ -3,19 +3,79 @@ use std::path::PathBuf;
 use itertools::Itertools;
 
 use crate::parser::resp::{Array, BulkString, Value};
+use crate::persistence::aof::{AofConfig, Fsync};
+use crate::persistence::{parse_save_rules, SaveRule};
+use crate::pubsub::notify;
+use crate::replication::{DisklessLoad, DEFAULT_BACKLOG_SIZE};
 
 #[derive(Default, Debug)]
 pub struct Config {
     dir: Option<String>,
     filename: Option<String>,
+    databases: Option<usize>,
+    port: Option<u16>,
+    replicaof: Option<String>,
+    repl_backlog_size: Option<String>,
+    repl_diskless_sync: Option<String>,
+    repl_diskless_load: Option<String>,
+    replica_serve_stale_data: Option<String>,
+    notify_keyspace_events: Option<String>,
+    save: Option<String>,
+    rdbcompression: Option<String>,
+    appendonly: Option<String>,
+    appendfsync: Option<String>,
+    appendfilename: Option<String>,
+    aof_load_truncated: Option<String>,
+    appenddirname: Option<String>,
+    auto_aof_rewrite_percentage: Option<String>,
+    auto_aof_rewrite_min_size: Option<String>,
+}
+
+const DEFAULT_DATABASES: usize = 16;
+const DEFAULT_PORT: u16 = 6379;
+const DEFAULT_DIR: &str = ".";
+const DEFAULT_FILENAME: &str = "dump.rdb";
+const DEFAULT_SAVE: &str = "3600 1 300 100 60 10000";
+const DEFAULT_APPENDFILENAME: &str = "appendonly.aof";
+const DEFAULT_APPENDDIRNAME: &str = "appendonlydir";
+const DEFAULT_AUTO_AOF_REWRITE_PERCENTAGE: u64 = 100;
+const DEFAULT_AUTO_AOF_REWRITE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+fn yes(value: &Option<String>, default: bool) -> bool {
+    value
+        .as_deref()
+        .map_or(default, |value| value.eq_ignore_ascii_case("yes"))
+}
+
+/// Parses a size such as `64mb`, in bytes.
+fn memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &value[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
 }
 
 impl FromIterator<(String, String)> for Config {
     fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
         let mut config = Config::default();
+        // whether `--replicaof` was given the host alone, its port following
+        let mut replicaof_host = false;
         for (key, value) in iter {
-            if config.filename.is_some() && config.dir.is_some() {
-                break;
+            if std::mem::take(&mut replicaof_host) && !value.starts_with("--") {
+                if let Some(replicaof) = &mut config.replicaof {
+                    replicaof.push(' ');
+                    replicaof.push_str(&value);
+                }
+                continue;
             }
             match &key[..] {
                 "--dir" => {
@@ -24,6 +84,58 @@ impl FromIterator<(String, String)> for Config {
                 "--dbfilename" => {
                     config.filename = Some(value);
                 }
+                "--save" => {
+                    config.save = Some(value);
+                }
+                "--rdbcompression" => {
+                    config.rdbcompression = Some(value);
+                }
+                "--appendonly" => {
+                    config.appendonly = Some(value);
+                }
+                "--appendfsync" => {
+                    config.appendfsync = Some(value);
+                }
+                "--appendfilename" => {
+                    config.appendfilename = Some(value);
+                }
+                "--aof-load-truncated" => {
+                    config.aof_load_truncated = Some(value);
+                }
+                "--appenddirname" => {
+                    config.appenddirname = Some(value);
+                }
+                "--auto-aof-rewrite-percentage" => {
+                    config.auto_aof_rewrite_percentage = Some(value);
+                }
+                "--auto-aof-rewrite-min-size" => {
+                    config.auto_aof_rewrite_min_size = Some(value);
+                }
+                "--port" => {
+                    config.port = value.parse().ok();
+                }
+                "--replicaof" | "--slaveof" => {
+                    replicaof_host = !value.contains(char::is_whitespace);
+                    config.replicaof = Some(value);
+                }
+                "--repl-backlog-size" => {
+                    config.repl_backlog_size = Some(value);
+                }
+                "--repl-diskless-sync" => {
+                    config.repl_diskless_sync = Some(value);
+                }
+                "--repl-diskless-load" => {
+                    config.repl_diskless_load = Some(value);
+                }
+                "--replica-serve-stale-data" | "--slave-serve-stale-data" => {
+                    config.replica_serve_stale_data = Some(value);
+                }
+                "--notify-keyspace-events" => {
+                    config.notify_keyspace_events = Some(value);
+                }
+                "--databases" => {
+                    config.databases = value.parse().ok().filter(|count| *count > 0);
+                }
                 _ => (),
             }
         }
@@ -35,18 +147,13 @@ impl Config {
     pub fn new() -> Self {
         std::env::args().skip(1).tuple_windows().collect::<Self>()
     }
-    #[allow(dead_code)]
-    pub fn dir_to_path(&self) -> Option<PathBuf> {
-        self.dir.as_ref().map(PathBuf::from)
-    }
-
     pub fn dir_to_value(&self) -> Value {
         self.dir
             .as_ref()
             .map(|dir| {
                 Array::Items(vec![
-                    BulkString::String("dir".to_string()).into(),
-                    BulkString::String(dir.to_string()).into(),
+                    BulkString::from("dir").into(),
+                    BulkString::from(dir.as_str()).into(),
                 ])
                 .into()
             })
@@ -54,19 +161,186 @@ impl Config {
     }
 
     pub fn filename_to_value(&self) -> Value {
-        self.dir
+        self.filename
             .as_ref()
-            .map(|dir| {
+            .map(|filename| {
                 Array::Items(vec![
-                    BulkString::String("dbfilename".to_string()).into(),
-                    BulkString::String(dir.to_string()).into(),
+                    BulkString::from("dbfilename").into(),
+                    BulkString::from(filename.as_str()).into(),
                 ])
                 .into()
             })
             .unwrap_or(Array::Empty.into())
     }
 
-    pub fn filename(&self) -> Option<String> {
-        self.filename.as_ref().map(ToString::to_string)
+    /// Where snapshots are loaded from and saved to, `./dump.rdb` unless configured.
+    pub fn rdb_path(&self) -> PathBuf {
+        PathBuf::from(self.dir.as_deref().unwrap_or(DEFAULT_DIR))
+            .join(self.filename.as_deref().unwrap_or(DEFAULT_FILENAME))
+    }
+
+    /// The `save` rules to start with, `None` when `--save` cannot be parsed.
+    pub fn save_rules(&self) -> Option<Vec<SaveRule>> {
+        parse_save_rules(self.save.as_deref().unwrap_or(DEFAULT_SAVE))
+    }
+
+    /// Whether snapshots LZF compress long strings, which they do by default.
+    pub fn rdbcompression(&self) -> bool {
+        yes(&self.rdbcompression, true)
+    }
+
+    pub fn appendonly(&self) -> bool {
+        yes(&self.appendonly, false)
+    }
+
+    fn appendfilename(&self) -> &str {
+        self.appendfilename
+            .as_deref()
+            .unwrap_or(DEFAULT_APPENDFILENAME)
+    }
+
+    /// The single file append only files were kept in before they were split
+    /// into a base and incremental files.
+    pub fn legacy_aof_path(&self) -> PathBuf {
+        PathBuf::from(self.dir.as_deref().unwrap_or(DEFAULT_DIR)).join(self.appendfilename())
+    }
+
+    /// How the append only file is kept, or why the options describing it are invalid.
+    pub fn aof(&self) -> Result<AofConfig, String> {
+        let fsync = match self.appendfsync.as_deref() {
+            Some(policy) => policy.parse().map_err(|_| "invalid appendfsync")?,
+            None => Fsync::EverySec,
+        };
+        let auto_rewrite_percentage = match self.auto_aof_rewrite_percentage.as_deref() {
+            Some(percentage) => percentage
+                .parse()
+                .map_err(|_| "invalid auto-aof-rewrite-percentage")?,
+            None => DEFAULT_AUTO_AOF_REWRITE_PERCENTAGE,
+        };
+        let auto_rewrite_min_size = match self.auto_aof_rewrite_min_size.as_deref() {
+            Some(size) => memory(size).ok_or("invalid auto-aof-rewrite-min-size")?,
+            None => DEFAULT_AUTO_AOF_REWRITE_MIN_SIZE,
+        };
+
+        Ok(AofConfig {
+            dir: PathBuf::from(self.dir.as_deref().unwrap_or(DEFAULT_DIR)).join(
+                self.appenddirname
+                    .as_deref()
+                    .unwrap_or(DEFAULT_APPENDDIRNAME),
+            ),
+            name: self.appendfilename().to_string(),
+            fsync,
+            auto_rewrite_percentage,
+            auto_rewrite_min_size,
+            rdb_compression: self.rdbcompression(),
+        })
+    }
+
+    /// Whether a truncated last command in the append only file is dropped
+    /// rather than stopping the server from starting.
+    pub fn aof_load_truncated(&self) -> bool {
+        yes(&self.aof_load_truncated, true)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    /// The master to replicate, given as `"<host> <port>"` or as two
+    /// arguments. `None` when this server is a master.
+    pub fn replicaof(&self) -> Result<Option<(String, u16)>, &'static str> {
+        let Some(replicaof) = self.replicaof.as_deref() else {
+            return Ok(None);
+        };
+        replicaof
+            .split_whitespace()
+            .collect_tuple()
+            .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+            .map(Some)
+            .ok_or("invalid replicaof")
+    }
+
+    /// How many bytes of writes masters keep for replicas that reconnect.
+    pub fn repl_backlog_size(&self) -> Result<usize, &'static str> {
+        match self.repl_backlog_size.as_deref() {
+            Some(size) => memory(size)
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|size| *size > 0)
+                .ok_or("invalid repl-backlog-size"),
+            None => Ok(DEFAULT_BACKLOG_SIZE),
+        }
+    }
+
+    /// Whether masters stream snapshots straight to replicas that can take
+    /// them, rather than serializing them whole first.
+    pub fn repl_diskless_sync(&self) -> bool {
+        yes(&self.repl_diskless_sync, false)
+    }
+
+    /// Whether replicas load their master's snapshot as it arrives.
+    pub fn repl_diskless_load(&self) -> Result<DisklessLoad, &'static str> {
+        match self.repl_diskless_load.as_deref() {
+            Some(mode) => mode.parse().map_err(|_| "invalid repl-diskless-load"),
+            None => Ok(DisklessLoad::Disabled),
+        }
+    }
+
+    /// Whether replicas answer clients with what they have while they are
+    /// not connected to their master, which they do by default.
+    pub fn replica_serve_stale_data(&self) -> bool {
+        yes(&self.replica_serve_stale_data, true)
+    }
+
+    /// Which keyspace notifications are published, none by default.
+    pub fn notify_keyspace_events(&self) -> Result<u16, &'static str> {
+        match self.notify_keyspace_events.as_deref() {
+            Some(flags) => notify::parse_flags(flags).ok_or("invalid notify-keyspace-events"),
+            None => Ok(0),
+        }
+    }
+
+    pub fn databases(&self) -> usize {
+        self.databases.unwrap_or(DEFAULT_DATABASES)
+    }
+
+    pub fn databases_to_value(&self) -> Value {
+        Array::Items(vec![
+            BulkString::from("databases").into(),
+            BulkString::from(self.databases().to_string()).into(),
+        ])
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .tuple_windows()
+            .collect()
+    }
+
+    #[test]
+    fn takes_replicaof_as_one_argument_or_two() {
+        let master = Some(("localhost".to_string(), 6380));
+        assert_eq!(
+            config(&["--replicaof", "localhost 6380", "--port", "6381"]).replicaof(),
+            Ok(master.clone())
+        );
+        let replica = config(&["--replicaof", "localhost", "6380", "--port", "6381"]);
+        assert_eq!(replica.replicaof(), Ok(master));
+        assert_eq!(replica.port(), 6381);
+
+        for args in [
+            &["--replicaof", "localhost"][..],
+            &["--replicaof", "localhost", "--port", "6381"],
+            &["--replicaof", "localhost port"],
+        ] {
+            assert!(config(args).replicaof().is_err(), "{args:?}");
+        }
+        assert_eq!(config(&["--port", "6381"]).replicaof(), Ok(None));
     }
 }
@@ -1,222 +1,358 @@
+mod client;
+mod commands;
 mod config;
 mod message;
 mod parser;
+mod persistence;
+mod pubsub;
+mod replication;
+mod server;
+mod store;
+mod transaction;
 
 use std::{
-    collections::HashMap,
     error::Error,
-    fs::File,
+    fs::{self, File},
     io::{self, Read, Write},
     net::{TcpListener, TcpStream},
-    sync::{Arc, OnceLock},
+    sync::{Arc, Mutex, OnceLock},
     thread,
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
 
+use client::Client;
 use config::Config;
 use message::RespMessage;
-use parser::{
-    rdb::KVPair,
-    resp::{parser, Value},
-};
+use parser::resp::parser;
+use persistence::aof::{self, Aof, AofConfig};
+use server::Server;
+use store::Databases;
 use thiserror::Error;
 
-use crate::parser::resp::BulkString;
-use crate::parser::{rdb::parse_rdb, resp::Array};
+use crate::parser::resp::Value;
 
-#[derive(Debug, Clone, PartialEq)]
-struct DurableValue {
-    val: Value,
-    expiration: Expiration,
-}
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
-#[derive(Debug, Clone, PartialEq, Default)]
-enum Expiration {
-    #[default]
-    Empty,
-    Date(SystemTime),
-    Period {
-        duration: Duration,
-        insert_at: Instant,
-    },
-}
+/// How often background housekeeping such as finishing snapshots runs.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    CONFIG.set(Config::new()).unwrap();
+
+    let config = CONFIG.get().unwrap();
+    let server = configured(config)?;
+    let aof_config = config.aof()?;
+    let master = config.replicaof()?;
+    let server = Arc::new(Mutex::new(server));
+
+    // clients can connect and ask for INFO while the dataset loads
+    let listener = TcpListener::bind(("127.0.0.1", config.port()))?;
+    let accepting = Arc::clone(&server);
+    thread::spawn(move || accept(listener, accepting));
 
-impl Expiration {
-    fn elapsed(&self) -> bool {
-        match self {
-            Expiration::Empty => false,
-            Expiration::Date(time) => SystemTime::now() >= *time,
-            Expiration::Period {
-                duration,
-                insert_at,
-            } => insert_at.elapsed() > *duration,
+    load(&server, config, aof_config)?;
+    if let Some(master) = master {
+        server.lock().unwrap().replication.set_master(Some(master));
+    }
+    loop {
+        thread::sleep(CRON_INTERVAL);
+        {
+            let server = &mut *server.lock().unwrap();
+            server
+                .persistence
+                .cron(&server.databases, &config.rdb_path());
         }
+        replication::cron(&server, config.port(), &config.rdb_path());
     }
 }
 
-impl DurableValue {
-    pub fn reply(&self, stream: &mut TcpStream) -> io::Result<usize> {
-        stream.write(self.val.to_string().as_bytes())
-    }
+/// A server set up as `config` says, loading until `load` is done with it.
+fn configured(config: &Config) -> Result<Server, Box<dyn Error>> {
+    let mut server = Server::new(Databases::new(config.databases()));
+    let rules = config.save_rules().ok_or("invalid save parameters")?;
+    server.persistence.set_rules(rules);
+    server.persistence.set_compression(config.rdbcompression());
+    server
+        .replication
+        .set_backlog_size(config.repl_backlog_size()?);
+    server
+        .replication
+        .set_diskless_sync(config.repl_diskless_sync());
+    server
+        .replication
+        .set_diskless_load(config.repl_diskless_load()?);
+    server
+        .replication
+        .set_serve_stale_data(config.replica_serve_stale_data());
+    server
+        .pubsub
+        .set_notify_flags(config.notify_keyspace_events()?);
+    // even with nothing to load, writes must wait for the empty dataset to
+    // be swapped in and logged from
+    server.persistence.start_loading(0);
+    Ok(server)
 }
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
-
-fn main() -> Result<(), Box<dyn Error>> {
-    CONFIG.set(Config::new()).unwrap();
+/// Loads the dataset from disk into `server`, without holding its lock but
+/// to swap the loaded keys in.
+fn load(
+    server: &Mutex<Server>,
+    config: &Config,
+    aof_config: AofConfig,
+) -> Result<(), Box<dyn Error>> {
+    // the append only file is the more complete record when there is one
+    let legacy_aof = config.legacy_aof_path();
+    let mut upgrading = false;
+    let mut loaded = Server::new(Databases::new(config.databases()));
+    if config.appendonly() && aof_config.manifest_path().exists() {
+        aof::load(&mut loaded, &aof_config, config.aof_load_truncated())?;
+    } else if config.appendonly() && legacy_aof.exists() {
+        let total = fs::metadata(&legacy_aof)?.len() as usize;
+        server.lock().unwrap().persistence.start_loading(total);
+        aof::load_file(&mut loaded, &legacy_aof, config.aof_load_truncated())?;
+        upgrading = true;
+    } else if config.rdb_path().exists() {
+        let file = File::open(config.rdb_path())?;
+        let total = file.metadata()?.len() as usize;
+        let progress = server.lock().unwrap().persistence.start_loading(total);
+        persistence::rdb::load_from(&mut loaded.databases, file, &progress)?;
+    }
 
-    let rdb = if let Some(filename) = CONFIG
-        .get()
-        .and_then(|c| c.dir_to_path().zip(c.filename()))
-        .map(|(dir, name)| dir.join(name))
-    {
-        if filename.exists() {
-            let mut file = File::open(filename)?;
-            let mut buffer = Vec::new();
-
-            file.read_to_end(&mut buffer)?;
-
-            let (_, rdb) = parse_rdb(&buffer).map_err(|err| format!("{err}"))?;
-            let map = rdb
-                .entries()
-                .map(
-                    |KVPair {
-                         key,
-                         value,
-                         expiration,
-                     }| {
-                        (
-                            key.to_string(),
-                            DurableValue {
-                                val: Value::from(value),
-                                expiration: expiration
-                                    .map(|exp| Expiration::Date(UNIX_EPOCH + exp))
-                                    .unwrap_or_default(),
-                            },
-                        )
-                    },
-                )
-                .collect::<HashMap<_, _>>();
-            Arc::new(map)
-        } else {
-            Arc::new(HashMap::default())
+    let server = &mut *server.lock().unwrap();
+    server.databases = loaded.databases;
+    if config.appendonly() {
+        let aof = Aof::open(aof_config, &server.databases)?;
+        server.persistence.set_aof(aof);
+        // its contents are in the new base file now
+        if upgrading {
+            fs::remove_file(legacy_aof)?;
         }
-    } else {
-        Arc::new(HashMap::default())
-    };
-
-    let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
+    }
+    server.persistence.finish_loading();
+    Ok(())
+}
 
+fn accept(listener: TcpListener, server: Arc<Mutex<Server>>) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                handle_requests(stream, Arc::clone(&rdb));
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    if let Err(err) = handle_requests(stream, server) {
+                        eprintln!("connection closed: {err}");
+                    }
+                });
             }
             Err(e) => {
                 println!("error: {}", e);
             }
         }
     }
-    Ok(())
 }
 
-pub fn now() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time cannot go before 1970 with this implementation")
-        .as_millis() as u64
+fn handle_requests(stream: TcpStream, server: Arc<Mutex<Server>>) -> Result<(), RedisError> {
+    let mut client = Client::default();
+    let result = serve(stream, &server, &mut client);
+    // so nothing is published to it any more
+    server.lock().unwrap().pubsub.unsubscribe_all(&mut client);
+    result
 }
 
-fn handle_requests(mut stream: TcpStream, rdb: Arc<HashMap<String, DurableValue>>) {
-    let mut store: HashMap<String, DurableValue> =
-        HashMap::from_iter(rdb.iter().map(|(k, v)| (k.clone(), v.clone())));
-
-    thread::spawn(move || loop {
-        let mut buffer = [0; 512];
-        match stream.read(&mut buffer) {
-            Ok(_) => {
-                let entry = String::from_utf8(buffer.to_vec()).unwrap();
-
-                let message: RespMessage = if let Ok((_, val)) = parser(&entry) {
-                    val.try_into().unwrap()
-                } else {
-                    continue;
-                };
-
-                match message {
-                    RespMessage::Ping => {
-                        let _ = stream.write(Value::String("PONG".into()).to_string().as_bytes());
+fn serve(
+    mut stream: TcpStream,
+    server: &Arc<Mutex<Server>>,
+    client: &mut Client,
+) -> Result<(), RedisError> {
+    let mut pending = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buffer[..read]);
+
+        // a single read may carry several pipelined commands, or only part of one
+        loop {
+            let (consumed, value) = match parser(&pending) {
+                Ok((rest, value)) => (pending.len() - rest.len(), value),
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(_) => {
+                    Value::error("ERR", "Protocol error").reply(&mut stream)?;
+                    return Err(RedisError::Protocol);
+                }
+            };
+            let mut quit = false;
+            let in_transaction = client.transaction.is_some();
+            // some commands are answered here rather than by `execute`, but
+            // are refused all the same
+            let message = RespMessage::try_from(value)
+                .map_err(Value::from)
+                .and_then(|message| {
+                    match commands::refusal(&server.lock().unwrap(), client, &message) {
+                        Some(refusal) => Err(refusal),
+                        None => Ok(message),
                     }
-                    RespMessage::Echo(bs) => {
-                        let _ = stream.write(bs.to_string().as_bytes());
+                });
+            let replies = match message {
+                // the connection is the replica's from now on
+                Ok(RespMessage::Psync { replid, offset }) if !in_transaction => {
+                    let rdb_path = CONFIG.get().unwrap().rdb_path();
+                    return Ok(replication::serve_replica(
+                        stream, server, client, &replid, offset, &rdb_path,
+                    )?);
+                }
+                Ok(message @ (RespMessage::Wait { .. } | RespMessage::WaitAof { .. }))
+                    if !in_transaction =>
+                {
+                    vec![replication::wait(server, client, message)]
+                }
+                Ok(message) if message.is_subscription() => {
+                    if client.subscriptions.push.is_none() {
+                        client.subscriptions.push = Some(pubsub::writer(stream.try_clone()?));
                     }
-                    RespMessage::Set { key, val, expiry } => {
-                        if let Some(millis) = expiry {
-                            store.insert(
-                                key,
-                                DurableValue {
-                                    val,
-                                    expiration: Expiration::Period {
-                                        duration: Duration::from_millis(millis as u64),
-                                        insert_at: Instant::now(),
-                                    },
-                                },
-                            );
-                        } else {
-                            store.insert(
-                                key,
-                                DurableValue {
-                                    val,
-                                    expiration: Expiration::Empty,
-                                },
-                            );
-                        }
-                        let _ = Value::String("OK".into()).reply(&mut stream);
+                    // queued for EXEC, which needs somewhere to send messages to
+                    if in_transaction {
+                        vec![commands::execute(
+                            &mut server.lock().unwrap(),
+                            client,
+                            message,
+                            &pending[..consumed],
+                        )]
+                    } else {
+                        pubsub::subscriptions(&mut server.lock().unwrap(), client, message)
                     }
-                    RespMessage::Get(key) => {
-                        let val = store.get(&key).unwrap_or(&DurableValue {
-                            val: Value::BulkString(BulkString::Null),
-                            expiration: Expiration::Empty,
-                        });
-
-                        if val.expiration.elapsed() {
-                            store.remove(&key);
-                            let _ = Value::BulkString(BulkString::Null).reply(&mut stream);
-                        } else {
-                            let _ = val.reply(&mut stream);
-                        }
+                }
+                Ok(message) => {
+                    quit = matches!(message, RespMessage::Quit);
+                    vec![commands::execute(
+                        &mut server.lock().unwrap(),
+                        client,
+                        message,
+                        &pending[..consumed],
+                    )]
+                }
+                Err(reply) => {
+                    // EXEC cannot run what did not parse or was refused
+                    if let Some(transaction) = &mut client.transaction {
+                        transaction.abort();
                     }
-                    RespMessage::ConfigGet(key) => match &key[..] {
-                        "dir" => {
-                            let _ = CONFIG.get().unwrap().dir_to_value().reply(&mut stream);
-                        }
-                        "dbfilename" => {
-                            let _ = CONFIG.get().unwrap().filename_to_value().reply(&mut stream);
-                        }
-                        _ => {
-                            eprintln!("unexpected config key: {key}");
-                        }
-                    },
-                    RespMessage::Keys(_) => {
-                        let keys = store
-                            .keys()
-                            .map(|k| BulkString::String(k.to_string()).into())
-                            .collect();
-                        let value: Value = Array::Items(keys).into();
-
-                        let _ = value.reply(&mut stream);
+                    vec![reply]
+                }
+            };
+            pending.drain(..consumed);
+            for reply in replies {
+                match &client.subscriptions.push {
+                    Some(push) => {
+                        push.send(reply.to_bytes().into());
                     }
+                    None => stream.write_all(&reply.to_bytes())?,
                 }
             }
-            Err(_) => {
-                break;
+            if quit {
+                return Ok(());
             }
         }
-    });
+    }
 }
 
 #[derive(Error, Debug)]
 enum RedisError {
     #[error("could not read stream")]
     ReadStream(#[from] io::Error),
+    #[error("invalid RESP input")]
+    Protocol,
+}
+
+#[cfg(test)]
+mod test {
+    use std::process;
+
+    use super::*;
+    use crate::parser::resp::BulkString;
+
+    fn run(server: &Mutex<Server>, args: &[&str]) -> Value {
+        let value: Value = args
+            .iter()
+            .map(|arg| BulkString::from(*arg).into())
+            .collect::<Vec<Value>>()
+            .into();
+        let raw = value.to_bytes();
+        let message = RespMessage::try_from(value).unwrap();
+        commands::execute(
+            &mut server.lock().unwrap(),
+            &mut Client::default(),
+            message,
+            &raw,
+        )
+    }
+
+    #[test]
+    fn connections_refuse_what_they_answer_themselves() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Arc::new(Mutex::new(Server::new(Databases::new(1))));
+        thread::spawn(move || accept(listener, server));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut expect = |args: &[&str], reply: &[u8]| {
+            let command: Value = args
+                .iter()
+                .map(|arg| BulkString::from(*arg).into())
+                .collect::<Vec<Value>>()
+                .into();
+            stream.write_all(&command.to_bytes()).unwrap();
+            let mut read = vec![0; reply.len()];
+            stream.read_exact(&mut read).unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&read),
+                String::from_utf8_lossy(reply)
+            );
+        };
+
+        expect(
+            &["subscribe", "news"],
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n",
+        );
+        for (command, args) in [
+            ("wait", &["wait", "0", "0"][..]),
+            ("psync", &["psync", "?", "-1"]),
+        ] {
+            let refusal = format!(
+                "-ERR Can't execute '{command}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / \
+                 QUIT / RESET are allowed in this context\r\n"
+            );
+            expect(args, refusal.as_bytes());
+        }
+    }
+
+    #[test]
+    fn writes_wait_until_the_dataset_is_loaded() {
+        let dir = std::env::temp_dir().join(format!("startup-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config: Config = [("--dir", dir.to_str().unwrap()), ("--appendonly", "yes")]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        // connections are accepted before anything is loaded, even with no files
+        let server = Mutex::new(configured(&config).unwrap());
+        assert_eq!(
+            run(&server, &["set", "early", "v"]),
+            Value::error("LOADING", "Redis is loading the dataset in memory")
+        );
+        load(&server, &config, config.aof().unwrap()).unwrap();
+        assert_eq!(run(&server, &["set", "k", "v"]), Value::ok());
+
+        // and what is written from then on is in the append only file
+        let restarted = Mutex::new(configured(&config).unwrap());
+        load(&restarted, &config, config.aof().unwrap()).unwrap();
+        assert_eq!(run(&restarted, &["get", "k"]), BulkString::from("v").into());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
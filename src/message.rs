@@ -1,4 +1,12 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::commands::bitmap::{bit_offset, BitFieldOp, BitOp, BitRange, BitUnit};
+use crate::commands::geo::{GeoAdd, GeoSearch, Unit};
 use crate::parser::resp::{Array, BulkString, Value};
+use crate::store::Expiration;
 
 #[derive(Debug)]
 pub enum RespMessage {
@@ -6,72 +14,774 @@ pub enum RespMessage {
     Echo(BulkString),
     Set {
         key: String,
-        val: Value,
-        expiry: Option<usize>,
+        val: Vec<u8>,
+        expiration: Expiration,
     },
     Get(String),
     ConfigGet(String),
+    ConfigSet(Vec<(String, String)>),
+    #[allow(dead_code)]
     Keys(String),
+    SetBit {
+        key: String,
+        offset: u64,
+        bit: bool,
+    },
+    GetBit {
+        key: String,
+        offset: u64,
+    },
+    BitCount {
+        key: String,
+        range: Option<BitRange>,
+    },
+    BitPos {
+        key: String,
+        bit: bool,
+        range: BitRange,
+        end_given: bool,
+    },
+    BitOp {
+        op: BitOp,
+        dest: String,
+        keys: Vec<String>,
+    },
+    BitField {
+        key: String,
+        ops: Vec<BitFieldOp>,
+    },
+    PfAdd {
+        key: String,
+        elements: Vec<Vec<u8>>,
+    },
+    PfCount(Vec<String>),
+    PfMerge {
+        dest: String,
+        keys: Vec<String>,
+    },
+    GeoAdd {
+        key: String,
+        add: GeoAdd,
+    },
+    GeoDist {
+        key: String,
+        from: Vec<u8>,
+        to: Vec<u8>,
+        unit: Unit,
+    },
+    GeoPos {
+        key: String,
+        members: Vec<Vec<u8>>,
+    },
+    GeoHash {
+        key: String,
+        members: Vec<Vec<u8>>,
+    },
+    GeoSearch {
+        key: String,
+        search: GeoSearch,
+    },
+    GeoSearchStore {
+        dest: String,
+        key: String,
+        search: GeoSearch,
+    },
+    Del(Vec<String>),
+    Unlink(Vec<String>),
+    Exists(Vec<String>),
+    Type(String),
+    Rename {
+        from: String,
+        to: String,
+    },
+    RenameNx {
+        from: String,
+        to: String,
+    },
+    Copy {
+        source: String,
+        dest: String,
+        db: Option<usize>,
+        replace: bool,
+    },
+    Touch(Vec<String>),
+    RandomKey,
+    DbSize,
+    Select(usize),
+    Move {
+        key: String,
+        db: usize,
+    },
+    SwapDb(usize, usize),
+    FlushDb {
+        lazy: bool,
+    },
+    FlushAll {
+        lazy: bool,
+    },
+    Save,
+    BgSave {
+        schedule: bool,
+    },
+    LastSave,
+    BgRewriteAof,
+    Info(Vec<String>),
+    /// Follow the master at this host and port, or stop following with `None`.
+    ReplicaOf(Option<(String, u16)>),
+    ReplConf(Vec<(String, String)>),
+    /// Asks for the writes that follow `offset` in the history `replid`.
+    Psync {
+        replid: String,
+        offset: i64,
+    },
+    /// Blocks until `numreplicas` replicas have the client's writes, or
+    /// for `timeout` milliseconds at most, zero meaning for ever.
+    Wait {
+        numreplicas: usize,
+        timeout: u64,
+    },
+    /// Blocks until the client's writes are fsynced locally, when
+    /// `numlocal` is 1, and on `numreplicas` replicas.
+    WaitAof {
+        numlocal: usize,
+        numreplicas: usize,
+        timeout: u64,
+    },
+    Subscribe(Vec<String>),
+    /// Unsubscribes from these channels, or from every one when empty.
+    Unsubscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    PUnsubscribe(Vec<String>),
+    /// Subscribes to shard channels, which must all be in one hash slot.
+    SSubscribe(Vec<String>),
+    SUnsubscribe(Vec<String>),
+    Publish {
+        channel: String,
+        message: Vec<u8>,
+    },
+    SPublish {
+        channel: String,
+        message: Vec<u8>,
+    },
+    /// The channels with subscribers, only those matching the pattern if given.
+    PubSubChannels(Option<String>),
+    PubSubNumSub(Vec<String>),
+    PubSubNumPat,
+    PubSubShardChannels(Option<String>),
+    PubSubShardNumSub(Vec<String>),
+    Quit,
+    Reset,
+    Multi,
+    Exec,
+    Discard,
+}
+
+impl RespMessage {
+    /// The name of the command, as it is listed in `COMMANDS`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RespMessage::Ping => "ping",
+            RespMessage::Echo(_) => "echo",
+            RespMessage::Set { .. } => "set",
+            RespMessage::Get(_) => "get",
+            RespMessage::ConfigGet(_) | RespMessage::ConfigSet(_) => "config",
+            RespMessage::Keys(_) => "keys",
+            RespMessage::SetBit { .. } => "setbit",
+            RespMessage::GetBit { .. } => "getbit",
+            RespMessage::BitCount { .. } => "bitcount",
+            RespMessage::BitPos { .. } => "bitpos",
+            RespMessage::BitOp { .. } => "bitop",
+            // only reading is as harmless as BITFIELD_RO, however it was sent
+            RespMessage::BitField { ops, .. }
+                if ops.iter().all(|op| matches!(op, BitFieldOp::Get { .. })) =>
+            {
+                "bitfield_ro"
+            }
+            RespMessage::BitField { .. } => "bitfield",
+            RespMessage::PfAdd { .. } => "pfadd",
+            RespMessage::PfCount(_) => "pfcount",
+            RespMessage::PfMerge { .. } => "pfmerge",
+            RespMessage::GeoAdd { .. } => "geoadd",
+            RespMessage::GeoDist { .. } => "geodist",
+            RespMessage::GeoPos { .. } => "geopos",
+            RespMessage::GeoHash { .. } => "geohash",
+            RespMessage::GeoSearch { .. } => "geosearch",
+            RespMessage::GeoSearchStore { .. } => "geosearchstore",
+            RespMessage::Del(_) => "del",
+            RespMessage::Unlink(_) => "unlink",
+            RespMessage::Exists(_) => "exists",
+            RespMessage::Type(_) => "type",
+            RespMessage::Rename { .. } => "rename",
+            RespMessage::RenameNx { .. } => "renamenx",
+            RespMessage::Copy { .. } => "copy",
+            RespMessage::Touch(_) => "touch",
+            RespMessage::RandomKey => "randomkey",
+            RespMessage::DbSize => "dbsize",
+            RespMessage::Select(_) => "select",
+            RespMessage::Move { .. } => "move",
+            RespMessage::SwapDb(..) => "swapdb",
+            RespMessage::FlushDb { .. } => "flushdb",
+            RespMessage::FlushAll { .. } => "flushall",
+            RespMessage::Save => "save",
+            RespMessage::BgSave { .. } => "bgsave",
+            RespMessage::LastSave => "lastsave",
+            RespMessage::BgRewriteAof => "bgrewriteaof",
+            RespMessage::Info(_) => "info",
+            RespMessage::ReplicaOf(_) => "replicaof",
+            RespMessage::ReplConf(_) => "replconf",
+            RespMessage::Psync { .. } => "psync",
+            RespMessage::Wait { .. } => "wait",
+            RespMessage::WaitAof { .. } => "waitaof",
+            RespMessage::Subscribe(_) => "subscribe",
+            RespMessage::Unsubscribe(_) => "unsubscribe",
+            RespMessage::PSubscribe(_) => "psubscribe",
+            RespMessage::PUnsubscribe(_) => "punsubscribe",
+            RespMessage::SSubscribe(_) => "ssubscribe",
+            RespMessage::SUnsubscribe(_) => "sunsubscribe",
+            RespMessage::Publish { .. } => "publish",
+            RespMessage::SPublish { .. } => "spublish",
+            RespMessage::PubSubChannels(_)
+            | RespMessage::PubSubNumSub(_)
+            | RespMessage::PubSubNumPat
+            | RespMessage::PubSubShardChannels(_)
+            | RespMessage::PubSubShardNumSub(_) => "pubsub",
+            RespMessage::Quit => "quit",
+            RespMessage::Reset => "reset",
+            RespMessage::Multi => "multi",
+            RespMessage::Exec => "exec",
+            RespMessage::Discard => "discard",
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let name = self.name();
+        COMMANDS
+            .iter()
+            .find(|(command, _)| *command == name)
+            .map_or(0, |(_, flags)| *flags)
+    }
+
+    /// Whether the command can modify the keyspace, and so counts towards the
+    /// changes `save` rules look at.
+    pub fn is_write(&self) -> bool {
+        self.flags() & WRITE != 0
+    }
+
+    /// Whether the command can run while the dataset is still being loaded.
+    pub fn is_allowed_while_loading(&self) -> bool {
+        self.flags() & LOADING != 0
+    }
+
+    /// Whether the command can run on a replica cut off from its master that
+    /// does not serve stale data.
+    pub fn is_allowed_while_stale(&self) -> bool {
+        self.flags() & STALE != 0
+    }
+
+    /// Whether the command can run on a connection that subscribed to
+    /// something, which otherwise only receives messages.
+    pub fn is_allowed_while_subscribed(&self) -> bool {
+        self.flags() & SUBSCRIBED != 0
+    }
+
+    /// Whether the command subscribes or unsubscribes, which replies once
+    /// for every channel or pattern.
+    pub fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            RespMessage::Subscribe(_)
+                | RespMessage::Unsubscribe(_)
+                | RespMessage::PSubscribe(_)
+                | RespMessage::PUnsubscribe(_)
+                | RespMessage::SSubscribe(_)
+                | RespMessage::SUnsubscribe(_)
+        )
+    }
+
+    /// Whether the command runs right away inside a transaction, rather
+    /// than being queued for `EXEC`.
+    pub fn is_transaction_control(&self) -> bool {
+        matches!(
+            self,
+            RespMessage::Multi
+                | RespMessage::Exec
+                | RespMessage::Discard
+                | RespMessage::Quit
+                | RespMessage::Reset
+        )
+    }
+}
+
+/// The command can modify the keyspace.
+const WRITE: u8 = 1;
+/// The command can run while the dataset is still being loaded.
+const LOADING: u8 = 1 << 1;
+/// The command can run on a replica whose link with its master is down.
+const STALE: u8 = 1 << 2;
+/// The command can run on a connection in pub/sub mode.
+const SUBSCRIBED: u8 = 1 << 3;
+
+/// Every command name the server understands and how it may be run, also
+/// used to tell a malformed call of a known command apart from an unknown one.
+const COMMANDS: &[(&str, u8)] = &[
+    ("get", 0),
+    ("keys", 0),
+    ("config", LOADING | STALE),
+    ("set", WRITE),
+    ("echo", 0),
+    ("ping", STALE | SUBSCRIBED),
+    ("setbit", WRITE),
+    ("getbit", 0),
+    ("bitcount", 0),
+    ("bitpos", 0),
+    ("bitop", WRITE),
+    ("bitfield", WRITE),
+    ("bitfield_ro", 0),
+    ("pfadd", WRITE),
+    ("pfcount", 0),
+    ("pfmerge", WRITE),
+    ("geoadd", WRITE),
+    ("geodist", 0),
+    ("geopos", 0),
+    ("geohash", 0),
+    ("geosearch", 0),
+    ("geosearchstore", WRITE),
+    ("del", WRITE),
+    ("unlink", WRITE),
+    ("exists", 0),
+    ("type", 0),
+    ("rename", WRITE),
+    ("renamenx", WRITE),
+    ("copy", WRITE),
+    ("touch", 0),
+    ("randomkey", 0),
+    ("dbsize", 0),
+    ("select", STALE),
+    ("move", WRITE),
+    ("swapdb", WRITE),
+    ("flushdb", WRITE),
+    ("flushall", WRITE),
+    ("save", 0),
+    ("bgsave", 0),
+    ("lastsave", 0),
+    ("bgrewriteaof", 0),
+    ("info", LOADING | STALE),
+    ("replicaof", STALE),
+    ("slaveof", STALE),
+    ("replconf", STALE),
+    ("psync", 0),
+    ("wait", 0),
+    ("waitaof", 0),
+    ("subscribe", LOADING | STALE | SUBSCRIBED),
+    ("unsubscribe", LOADING | STALE | SUBSCRIBED),
+    ("psubscribe", LOADING | STALE | SUBSCRIBED),
+    ("punsubscribe", LOADING | STALE | SUBSCRIBED),
+    ("ssubscribe", LOADING | STALE | SUBSCRIBED),
+    ("sunsubscribe", LOADING | STALE | SUBSCRIBED),
+    ("publish", LOADING | STALE),
+    ("spublish", LOADING | STALE),
+    ("pubsub", LOADING | STALE),
+    ("quit", LOADING | STALE | SUBSCRIBED),
+    ("reset", LOADING | STALE | SUBSCRIBED),
+    ("multi", LOADING | STALE),
+    ("exec", LOADING | STALE),
+    ("discard", LOADING | STALE),
+];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum CommandError {
+    #[error("ERR Protocol error: expected an array of bulk strings")]
+    Protocol,
+    #[error("ERR unknown command '{0}'")]
+    Unknown(String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    Arity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR {0}")]
+    Invalid(String),
+}
+
+impl From<CommandError> for Value {
+    fn from(err: CommandError) -> Self {
+        let message = err.to_string();
+        let (title, message) = message.split_once(' ').unwrap_or((&message, ""));
+        Value::error(title, message)
+    }
+}
+
+/// Parses a numeric argument, failing with the generic integer error.
+pub fn int<T: FromStr>(arg: &BulkString) -> Result<T, CommandError> {
+    arg.inner().parse().map_err(|_| CommandError::NotInteger)
+}
+
+/// Parses a floating point argument, rejecting NaN like Redis does.
+pub fn float(arg: &BulkString) -> Result<f64, CommandError> {
+    arg.inner()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| !value.is_nan())
+        .ok_or(CommandError::NotFloat)
+}
+
+fn members(args: &[BulkString]) -> Vec<Vec<u8>> {
+    args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+}
+
+/// A key, which the keyspace holds as a string, so one that is not valid
+/// UTF-8 is refused rather than mangled into another key.
+fn to_key(arg: &BulkString) -> Result<String, CommandError> {
+    String::from_utf8(arg.as_bytes().to_vec())
+        .map_err(|_| CommandError::Invalid("keys must be valid UTF-8".into()))
+}
+
+fn keys(args: &[BulkString]) -> Result<Vec<String>, CommandError> {
+    args.iter().map(to_key).collect()
+}
+
+/// Channel names and patterns, which are only ever matched against each other.
+fn names(args: &[BulkString]) -> Vec<String> {
+    args.iter().map(BulkString::inner).collect()
+}
+
+fn flush_mode(arg: Option<&BulkString>) -> Result<bool, CommandError> {
+    match arg.map(|mode| mode.inner().to_lowercase()).as_deref() {
+        None | Some("sync") => Ok(false),
+        Some("async") => Ok(true),
+        Some(_) => Err(CommandError::Syntax),
+    }
+}
+
+fn wait_timeout(arg: &BulkString) -> Result<u64, CommandError> {
+    let timeout: i64 = int(arg)?;
+    u64::try_from(timeout).map_err(|_| CommandError::Invalid("timeout is negative".into()))
+}
+
+fn bit_unit(arg: Option<&BulkString>) -> Result<BitUnit, CommandError> {
+    match arg.map(|unit| unit.inner().to_lowercase()).as_deref() {
+        None | Some("byte") => Ok(BitUnit::Byte),
+        Some("bit") => Ok(BitUnit::Bit),
+        Some(_) => Err(CommandError::Syntax),
+    }
+}
+
+fn bit(arg: &BulkString, err: &str) -> Result<bool, CommandError> {
+    match arg.as_bytes() {
+        b"0" => Ok(false),
+        b"1" => Ok(true),
+        _ => Err(CommandError::Invalid(err.to_string())),
+    }
 }
 
 impl TryFrom<Value> for RespMessage {
-    type Error = (String, Value);
+    type Error = CommandError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
-        match &value {
-            Value::Array(Array::Items(entry)) => match entry.as_slice() {
-                [Value::BulkString(get), Value::BulkString(key)]
-                    if get.inner().to_lowercase() == "get" =>
-                {
-                    Ok(RespMessage::Get(key.inner()))
-                }
-                [Value::BulkString(keys), Value::BulkString(key_value)]
-                    if keys.inner().to_lowercase() == "keys" =>
-                {
-                    Ok(RespMessage::Keys(key_value.inner()))
-                }
-                [Value::BulkString(config), Value::BulkString(get), Value::BulkString(key)]
-                    if config.inner().to_lowercase() == "config"
-                        && get.inner().to_lowercase() == "get" =>
-                {
-                    Ok(RespMessage::ConfigGet(key.inner()))
+        let entry = match value {
+            Value::Array(Array::Items(entry)) => entry
+                .into_iter()
+                .map(|item| match item {
+                    Value::BulkString(arg) => Ok(arg),
+                    _ => Err(CommandError::Protocol),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(CommandError::Protocol),
+        };
+        let Some((name, args)) = entry.split_first() else {
+            return Err(CommandError::Protocol);
+        };
+        let name = name.inner().to_lowercase();
+
+        match (name.as_str(), args) {
+            ("get", [key]) => Ok(RespMessage::Get(to_key(key)?)),
+            ("keys", [key_value]) => Ok(RespMessage::Keys(key_value.inner())),
+            ("config", [get, key]) if get.inner().to_lowercase() == "get" => {
+                Ok(RespMessage::ConfigGet(key.inner()))
+            }
+            ("config", [set, params @ ..])
+                if set.inner().to_lowercase() == "set"
+                    && !params.is_empty()
+                    && params.len() % 2 == 0 =>
+            {
+                Ok(RespMessage::ConfigSet(
+                    params
+                        .chunks(2)
+                        .map(|pair| (pair[0].inner().to_lowercase(), pair[1].inner()))
+                        .collect(),
+                ))
+            }
+            ("set", [key, val, rest @ ..]) => {
+                // NX, XX, GET, KEEPTTL and the expiries in seconds are not supported
+                let expiration = match rest {
+                    [] => Expiration::Empty,
+                    [px, millis] if px.inner().to_lowercase() == "px" => Expiration::Period {
+                        duration: Duration::from_millis(int(millis)?),
+                        insert_at: Instant::now(),
+                    },
+                    [pxat, millis] if pxat.inner().to_lowercase() == "pxat" => {
+                        Expiration::Date(UNIX_EPOCH + Duration::from_millis(int(millis)?))
+                    }
+                    _ => return Err(CommandError::Syntax),
+                };
+                Ok(RespMessage::Set {
+                    key: to_key(key)?,
+                    val: val.as_bytes().to_vec(),
+                    expiration,
+                })
+            }
+            ("echo", [sec]) => Ok(RespMessage::Echo(sec.clone())),
+            ("ping", []) => Ok(RespMessage::Ping),
+            ("setbit", [key, offset, value]) => Ok(RespMessage::SetBit {
+                key: to_key(key)?,
+                offset: bit_offset(offset)?,
+                bit: bit(value, "bit is not an integer or out of range")?,
+            }),
+            ("getbit", [key, offset]) => Ok(RespMessage::GetBit {
+                key: to_key(key)?,
+                offset: bit_offset(offset)?,
+            }),
+            ("bitcount", [key, rest @ ..]) => {
+                let range = match rest {
+                    [] => None,
+                    [start, end] | [start, end, _] => Some(BitRange {
+                        start: int(start)?,
+                        end: int(end)?,
+                        unit: bit_unit(rest.get(2))?,
+                    }),
+                    _ => return Err(CommandError::Syntax),
+                };
+                Ok(RespMessage::BitCount {
+                    key: to_key(key)?,
+                    range,
+                })
+            }
+            ("bitpos", [key, value, rest @ ..]) if rest.len() <= 3 => {
+                let bit = bit(value, "The bit argument must be 1 or 0.")?;
+                let range = BitRange {
+                    start: rest.first().map(int).transpose()?.unwrap_or(0),
+                    end: rest.get(1).map(int).transpose()?.unwrap_or(-1),
+                    unit: bit_unit(rest.get(2))?,
+                };
+                Ok(RespMessage::BitPos {
+                    key: to_key(key)?,
+                    bit,
+                    range,
+                    end_given: rest.len() > 1,
+                })
+            }
+            ("bitop", [op, dest, sources @ ..]) if !sources.is_empty() => {
+                let op = BitOp::parse(&op.inner(), sources.len())?;
+                Ok(RespMessage::BitOp {
+                    op,
+                    dest: to_key(dest)?,
+                    keys: keys(sources)?,
+                })
+            }
+            ("bitfield", [key, rest @ ..]) => Ok(RespMessage::BitField {
+                key: to_key(key)?,
+                ops: BitFieldOp::parse_all(rest)?,
+            }),
+            ("bitfield_ro", [key, rest @ ..]) => {
+                let ops = BitFieldOp::parse_all(rest)?;
+                if ops.iter().any(|op| !matches!(op, BitFieldOp::Get { .. })) {
+                    return Err(CommandError::Invalid(
+                        "BITFIELD_RO only supports the GET subcommand".to_string(),
+                    ));
                 }
-                [Value::BulkString(set), Value::BulkString(key), val, rest @ ..]
-                    if set.inner().to_lowercase() == "set" =>
-                {
-                    match rest {
-                        [Value::BulkString(px), Value::BulkString(millis), ..]
-                            if px.inner().to_lowercase() == "px" =>
-                        {
-                            Ok(RespMessage::Set {
-                                key: key.inner(),
-                                val: val.clone(),
-                                expiry: Some(
-                                    millis
-                                        .inner()
-                                        .parse::<usize>()
-                                        .expect("could not parse expiry duration"),
-                                ),
-                            })
+                Ok(RespMessage::BitField {
+                    key: to_key(key)?,
+                    ops,
+                })
+            }
+            ("pfadd", [key, elements @ ..]) => Ok(RespMessage::PfAdd {
+                key: to_key(key)?,
+                elements: members(elements),
+            }),
+            ("pfcount", [_, ..]) => Ok(RespMessage::PfCount(keys(args)?)),
+            ("pfmerge", [dest, sources @ ..]) => Ok(RespMessage::PfMerge {
+                dest: to_key(dest)?,
+                keys: keys(sources)?,
+            }),
+            ("geoadd", [key, rest @ ..]) if rest.len() >= 3 => Ok(RespMessage::GeoAdd {
+                key: to_key(key)?,
+                add: GeoAdd::parse(rest)?,
+            }),
+            ("geodist", [key, from, to, rest @ ..]) if rest.len() <= 1 => {
+                Ok(RespMessage::GeoDist {
+                    key: to_key(key)?,
+                    from: from.as_bytes().to_vec(),
+                    to: to.as_bytes().to_vec(),
+                    unit: rest
+                        .first()
+                        .map(Unit::parse)
+                        .transpose()?
+                        .unwrap_or(Unit::Meters),
+                })
+            }
+            ("geopos", [key, rest @ ..]) => Ok(RespMessage::GeoPos {
+                key: to_key(key)?,
+                members: members(rest),
+            }),
+            ("geohash", [key, rest @ ..]) => Ok(RespMessage::GeoHash {
+                key: to_key(key)?,
+                members: members(rest),
+            }),
+            ("geosearch", [key, rest @ ..]) if !rest.is_empty() => Ok(RespMessage::GeoSearch {
+                key: to_key(key)?,
+                search: GeoSearch::parse(rest, false)?,
+            }),
+            ("geosearchstore", [dest, key, rest @ ..]) if !rest.is_empty() => {
+                Ok(RespMessage::GeoSearchStore {
+                    dest: to_key(dest)?,
+                    key: to_key(key)?,
+                    search: GeoSearch::parse(rest, true)?,
+                })
+            }
+            ("del", [_, ..]) => Ok(RespMessage::Del(keys(args)?)),
+            ("unlink", [_, ..]) => Ok(RespMessage::Unlink(keys(args)?)),
+            ("exists", [_, ..]) => Ok(RespMessage::Exists(keys(args)?)),
+            ("touch", [_, ..]) => Ok(RespMessage::Touch(keys(args)?)),
+            ("type", [key]) => Ok(RespMessage::Type(to_key(key)?)),
+            ("rename", [from, to]) => Ok(RespMessage::Rename {
+                from: to_key(from)?,
+                to: to_key(to)?,
+            }),
+            ("renamenx", [from, to]) => Ok(RespMessage::RenameNx {
+                from: to_key(from)?,
+                to: to_key(to)?,
+            }),
+            ("copy", [source, dest, rest @ ..]) => {
+                let (mut db, mut replace) = (None, false);
+                let mut rest = rest;
+                while let Some((option, tail)) = rest.split_first() {
+                    rest = match (&option.inner().to_lowercase()[..], tail) {
+                        ("db", [index, tail @ ..]) => {
+                            db = Some(int(index)?);
+                            tail
                         }
-                        _ => Ok(RespMessage::Set {
-                            key: key.inner(),
-                            val: val.clone(),
-                            expiry: None,
-                        }),
-                    }
+                        ("replace", tail) => {
+                            replace = true;
+                            tail
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    };
                 }
-                [Value::BulkString(fs), Value::BulkString(sec)]
-                    if fs.inner().to_lowercase() == "echo" =>
+                Ok(RespMessage::Copy {
+                    source: to_key(source)?,
+                    dest: to_key(dest)?,
+                    db,
+                    replace,
+                })
+            }
+            ("randomkey", []) => Ok(RespMessage::RandomKey),
+            ("dbsize", []) => Ok(RespMessage::DbSize),
+            ("select", [index]) => Ok(RespMessage::Select(int(index)?)),
+            ("move", [key, db]) => Ok(RespMessage::Move {
+                key: to_key(key)?,
+                db: int(db)?,
+            }),
+            ("swapdb", [a, b]) => Ok(RespMessage::SwapDb(
+                int(a).map_err(|_| CommandError::Invalid("invalid first DB index".into()))?,
+                int(b).map_err(|_| CommandError::Invalid("invalid second DB index".into()))?,
+            )),
+            ("flushdb", [] | [_]) => Ok(RespMessage::FlushDb {
+                lazy: flush_mode(args.first())?,
+            }),
+            ("flushall", [] | [_]) => Ok(RespMessage::FlushAll {
+                lazy: flush_mode(args.first())?,
+            }),
+            ("save", []) => Ok(RespMessage::Save),
+            ("bgsave", []) => Ok(RespMessage::BgSave { schedule: false }),
+            ("bgsave", [option]) if option.inner().eq_ignore_ascii_case("schedule") => {
+                Ok(RespMessage::BgSave { schedule: true })
+            }
+            ("bgsave", [_]) => Err(CommandError::Syntax),
+            ("lastsave", []) => Ok(RespMessage::LastSave),
+            ("bgrewriteaof", []) => Ok(RespMessage::BgRewriteAof),
+            ("info", sections) => Ok(RespMessage::Info(
+                sections
+                    .iter()
+                    .map(|section| section.inner().to_lowercase())
+                    .collect(),
+            )),
+            ("replicaof" | "slaveof", [host, port]) => {
+                if host.inner().eq_ignore_ascii_case("no")
+                    && port.inner().eq_ignore_ascii_case("one")
                 {
-                    Ok(RespMessage::Echo(sec.clone()))
+                    Ok(RespMessage::ReplicaOf(None))
+                } else {
+                    let port = port
+                        .inner()
+                        .parse()
+                        .map_err(|_| CommandError::Invalid("Invalid master port".into()))?;
+                    Ok(RespMessage::ReplicaOf(Some((host.inner(), port))))
                 }
-                [Value::BulkString(fs)] if fs.inner().to_lowercase() == "ping" => {
-                    Ok(RespMessage::Ping)
+            }
+            ("replconf", params) if params.len() % 2 == 0 => Ok(RespMessage::ReplConf(
+                params
+                    .chunks(2)
+                    .map(|pair| (pair[0].inner().to_lowercase(), pair[1].inner()))
+                    .collect(),
+            )),
+            ("replconf", _) => Err(CommandError::Syntax),
+            ("psync", [replid, offset]) => Ok(RespMessage::Psync {
+                replid: replid.inner(),
+                offset: int(offset)?,
+            }),
+            ("wait", [numreplicas, timeout]) => Ok(RespMessage::Wait {
+                numreplicas: int(numreplicas)?,
+                timeout: wait_timeout(timeout)?,
+            }),
+            ("waitaof", [numlocal, numreplicas, timeout]) => Ok(RespMessage::WaitAof {
+                numlocal: int(numlocal)?,
+                numreplicas: int(numreplicas)?,
+                timeout: wait_timeout(timeout)?,
+            }),
+            ("subscribe", [_, ..]) => Ok(RespMessage::Subscribe(names(args))),
+            ("unsubscribe", channels) => Ok(RespMessage::Unsubscribe(names(channels))),
+            ("psubscribe", [_, ..]) => Ok(RespMessage::PSubscribe(names(args))),
+            ("punsubscribe", patterns) => Ok(RespMessage::PUnsubscribe(names(patterns))),
+            ("ssubscribe", [_, ..]) => Ok(RespMessage::SSubscribe(names(args))),
+            ("sunsubscribe", channels) => Ok(RespMessage::SUnsubscribe(names(channels))),
+            ("publish", [channel, message]) => Ok(RespMessage::Publish {
+                channel: channel.inner(),
+                message: message.as_bytes().to_vec(),
+            }),
+            ("spublish", [channel, message]) => Ok(RespMessage::SPublish {
+                channel: channel.inner(),
+                message: message.as_bytes().to_vec(),
+            }),
+            ("pubsub", [subcommand, rest @ ..]) => {
+                match (&subcommand.inner().to_lowercase()[..], rest) {
+                    ("channels", []) => Ok(RespMessage::PubSubChannels(None)),
+                    ("channels", [pattern]) => Ok(RespMessage::PubSubChannels(Some(pattern.inner()))),
+                    ("numsub", channels) => Ok(RespMessage::PubSubNumSub(names(channels))),
+                    ("numpat", []) => Ok(RespMessage::PubSubNumPat),
+                    ("shardchannels", []) => Ok(RespMessage::PubSubShardChannels(None)),
+                    ("shardchannels", [pattern]) => {
+                        Ok(RespMessage::PubSubShardChannels(Some(pattern.inner())))
+                    }
+                    ("shardnumsub", channels) => {
+                        Ok(RespMessage::PubSubShardNumSub(names(channels)))
+                    }
+                    (subcommand, _) => Err(CommandError::Invalid(format!(
+                        "unknown subcommand or wrong number of arguments for '{subcommand}'. Try PUBSUB HELP."
+                    ))),
                 }
-                _ => Err(("Unsupported".to_string(), value)),
-            },
-            _ => Err(("Unsupported".to_string(), value)),
+            }
+            ("quit", _) => Ok(RespMessage::Quit),
+            ("reset", []) => Ok(RespMessage::Reset),
+            ("multi", []) => Ok(RespMessage::Multi),
+            ("exec", []) => Ok(RespMessage::Exec),
+            ("discard", []) => Ok(RespMessage::Discard),
+            _ if COMMANDS.iter().any(|(command, _)| *command == name) => {
+                Err(CommandError::Arity(name))
+            }
+            _ => Err(CommandError::Unknown(name)),
         }
     }
 }
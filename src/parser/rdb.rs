@@ -1,27 +1,91 @@
 use std::time::Duration;
 
-use nom::branch::alt;
-use nom::bytes::complete::{tag, take};
-use nom::combinator::{map, map_res, opt};
+use itertools::Itertools;
+
+use nom::bytes::streaming::{tag, take};
+use nom::combinator::{map, map_opt, map_res};
 
 use nom::error::{ErrorKind, FromExternalError};
-use nom::multi::{many0, many_till};
-use nom::number::complete::{be_i16, be_i32, be_i8, be_u32, be_u8, le_u32, le_u64};
-use nom::sequence::{pair, preceded};
+use nom::multi::{count, many_till};
+use nom::number::streaming::{
+    be_u32, be_u64, be_u8, le_f64, le_i16, le_i24, le_i32, le_i64, le_i8, le_u16, le_u32, le_u64,
+};
+use nom::sequence::{pair, preceded, tuple};
 use nom::{IResult as NomResult, Parser};
 
+use crate::persistence::lzf;
+use crate::store::{Consumer, ConsumerGroup, Fields, PendingEntry, Stream, StreamId};
+
 const MAGIC: &[u8; 5] = b"REDIS";
 
-trait ParseRDB<'a, T>: Parser<&'a [u8], T, nom::error::Error<&'a [u8]>> {}
-type IResult<'a, T> = NomResult<&'a [u8], T>;
+trait ParseRDB<'a, T>: Parser<&'a [u8], T, RdbError<'a>> {}
+pub type IResult<'a, T> = NomResult<&'a [u8], T, RdbError<'a>>;
+
+impl<'a, T, U> ParseRDB<'a, T> for U where U: Parser<&'a [u8], T, RdbError<'a>> {}
+
+/// Why parsing failed, where, and the opcode and key it was reading when it did.
+#[derive(Debug, PartialEq)]
+pub struct RdbError<'a> {
+    /// The input from where the problem was found on.
+    pub input: &'a [u8],
+    pub reason: String,
+    pub opcode: Option<u8>,
+    pub key: Option<String>,
+}
+
+impl<'a> RdbError<'a> {
+    /// Records the opcode being read, unless a more specific one already was.
+    fn at_opcode(mut self, opcode: u8) -> Self {
+        self.opcode.get_or_insert(opcode);
+        self
+    }
+
+    fn at_key(mut self, key: &DBString) -> Self {
+        self.key.get_or_insert_with(|| key.to_string());
+        self
+    }
+}
+
+impl<'a> nom::error::ParseError<&'a [u8]> for RdbError<'a> {
+    fn from_error_kind(input: &'a [u8], kind: ErrorKind) -> Self {
+        let reason = if input.is_empty() {
+            "Unexpected end of file".to_string()
+        } else {
+            format!("Invalid data ({})", kind.description())
+        };
+        RdbError {
+            input,
+            reason,
+            opcode: None,
+            key: None,
+        }
+    }
+
+    // the innermost error is the one that says what went wrong
+    fn append(_: &'a [u8], _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
 
-impl<'a, T, U> ParseRDB<'a, T> for U where U: Parser<&'a [u8], T, nom::error::Error<&'a [u8]>> {}
+impl<'a, E: std::fmt::Display> FromExternalError<&'a [u8], E> for RdbError<'a> {
+    fn from_external_error(input: &'a [u8], _: ErrorKind, e: E) -> Self {
+        RdbError {
+            input,
+            reason: e.to_string(),
+            opcode: None,
+            key: None,
+        }
+    }
+}
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 pub struct RDB {
     pub version: u32,
     pub auxilliary_field: Vec<Auxilliary>,
     pub databases: Vec<DB>,
+    /// The code of every function library, which this server cannot run.
+    pub functions: Vec<DBString>,
 }
 #[derive(Debug, PartialEq)]
 pub struct Auxilliary {
@@ -31,7 +95,7 @@ pub struct Auxilliary {
 
 #[derive(PartialEq, Debug)]
 enum LenEncoded {
-    Num(u32),
+    Num(u64),
     Special(u8),
 }
 
@@ -40,11 +104,15 @@ pub struct KVPair {
     pub key: DBString,
     pub value: Value,
     pub expiration: Option<Duration>,
+    /// How long since the key was used, saved under an LRU eviction policy.
+    pub idle: Option<Duration>,
+    /// The key's access frequency counter, saved under an LFU eviction policy.
+    pub freq: Option<u8>,
 }
 
 #[derive(PartialEq, Debug)]
 pub struct DB {
-    pub number: u32,
+    pub number: u64,
     pub resize_db: Option<ResizeDBAttr>,
     pub key_value_pairs: Vec<KVPair>,
 }
@@ -64,11 +132,27 @@ impl RDB {
     pub fn entries<'a>(&'a self) -> impl Iterator<Item = &'a KVPair> + 'a {
         self.databases.iter().flat_map(|db| db.entries())
     }
+
+    /// The database keys go to, the first one until one is selected.
+    fn current_db(&mut self) -> &mut DB {
+        if self.databases.is_empty() {
+            self.databases.push(DB::new(0));
+        }
+        self.databases.last_mut().unwrap()
+    }
 }
 
 impl DB {
+    fn new(number: u64) -> Self {
+        DB {
+            number,
+            resize_db: None,
+            key_value_pairs: Vec::new(),
+        }
+    }
+
     #[allow(dead_code)]
-    fn get<'a, 'b>(&'a self, key: &'b str) -> Option<&'a Value> {
+    fn get<'a>(&'a self, key: &str) -> Option<&'a Value> {
         self.key_value_pairs
             .iter()
             .find(|KVPair { key: k, .. }| k.to_string() == key)
@@ -87,28 +171,30 @@ impl DB {
 
 #[derive(PartialEq, Debug)]
 pub struct ResizeDBAttr {
-    pub hash_table_size: u32,
-    pub expire_hash_table_size: u32,
+    pub hash_table_size: u64,
+    pub expire_hash_table_size: u64,
 }
 
 #[derive(PartialEq, Debug)]
 pub enum DBString {
-    Int(i32),
-    Str(String),
-    #[allow(dead_code)]
-    Lzf {
-        clen: u32,
-        ulen: u32,
-        data: Vec<u8>,
-    },
+    Int(i64),
+    Str(Vec<u8>),
 }
 
-impl ToString for DBString {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for DBString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DBString::Int(ref num) => num.to_string(),
-            DBString::Str(s) => s.to_owned(),
-            DBString::Lzf { .. } => String::new(),
+            DBString::Int(ref num) => write!(f, "{num}"),
+            DBString::Str(s) => f.write_str(&String::from_utf8_lossy(s)),
+        }
+    }
+}
+
+impl DBString {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            DBString::Str(bytes) => bytes.clone(),
+            other => other.to_string().into_bytes(),
         }
     }
 }
@@ -117,6 +203,7 @@ impl PartialEq<str> for Value {
     fn eq(&self, other: &str) -> bool {
         match self {
             Value::String(s) => s.to_string() == other,
+            _ => false,
         }
     }
 }
@@ -124,68 +211,109 @@ impl PartialEq<str> for Value {
 #[derive(PartialEq, Debug)]
 pub enum Value {
     String(DBString),
-}
-
-impl From<&Value> for super::resp::Value {
-    fn from(value: &Value) -> Self {
-        match value {
-            Value::String(s) => s.into(),
-        }
-    }
-}
-
-impl From<Value> for super::resp::Value {
-    fn from(value: Value) -> Self {
-        (&value).into()
-    }
-}
-
-impl From<&DBString> for super::resp::Value {
-    fn from(value: &DBString) -> Self {
-        Self::String(value.to_string())
-    }
+    List(Vec<DBString>),
+    Set(Vec<DBString>),
+    /// Members with their scores, whichever encoding they were stored in.
+    SortedSet(Vec<(DBString, f64)>),
+    /// Fields with their values.
+    Hash(Vec<(DBString, DBString)>),
+    Stream(Stream),
+    /// A module data type, identified by `id`, and its serialized fields.
+    Module {
+        id: u64,
+        payload: Vec<u8>,
+    },
 }
 
 fn nom_error<'a, T>(input: &'a [u8], msg: impl Into<String>) -> IResult<'a, T> {
-    Err(nom::Err::Error(nom::error::Error::from_external_error(
+    Err(nom::Err::Error(RdbError::from_external_error(
         input,
         ErrorKind::MapRes,
         msg.into(),
     )))
 }
 
+/// What can follow the header, each introduced by its opcode except for keys,
+/// which start with their type.
+#[derive(PartialEq, Debug)]
+pub enum Item {
+    Aux(Auxilliary),
+    SelectDb(u64),
+    ResizeDb(ResizeDBAttr),
+    Function(DBString),
+    /// Module and cluster slot metadata, which is read past but not kept.
+    Skipped,
+    Entry(KVPair),
+}
+
+pub fn item(input: &[u8]) -> IResult<'_, Item> {
+    let (next, opcode) = be_u8(input)?;
+    let result = match opcode {
+        0xFA => map(pair(string, string), |(key, value)| {
+            Item::Aux(Auxilliary { key, value })
+        })(next),
+        0xFE => map(db_number, Item::SelectDb)(input),
+        0xFB => map(resize_db, Item::ResizeDb)(input),
+        0xF5 => map(string, Item::Function)(next),
+        0xF6 => nom_error(next, "Pre-release function libraries are not supported"),
+        // MODULE_AUX: the module, when it was saved, then fields like a module value's
+        0xF7 => map(tuple((use_len, use_len, use_len, module_fields)), |_| {
+            Item::Skipped
+        })(next),
+        // SLOT_INFO: a cluster slot with how many keys and expiring keys it has
+        0xF4 => map(tuple((use_len, use_len, use_len)), |_| Item::Skipped)(next),
+        _ => map(kv_pair, Item::Entry)(input),
+    };
+    result.map_err(|err| err.map(|err| err.at_opcode(opcode)))
+}
+
+/// Parses a whole file held in memory. Loading reads one `item` at a time instead.
+#[allow(dead_code)]
 pub fn parse_rdb<'a>(input: &'a [u8]) -> IResult<'a, RDB> {
     let (input, version) = header(input)?;
-    let (input, auxilliary_field) = auxilliary(input)?;
-    let (input, (databases, _)) = many_till(db, tag([0xff]))(input)?;
+    let (input, (items, _)) = many_till(item, tag([0xff]))(input)?;
 
-    let res = RDB {
+    let mut res = RDB {
         version,
-        databases,
-        auxilliary_field,
+        auxilliary_field: Vec::new(),
+        databases: Vec::new(),
+        functions: Vec::new(),
     };
-    return Ok((input, res));
+    for item in items {
+        match item {
+            Item::Aux(aux) => res.auxilliary_field.push(aux),
+            Item::SelectDb(number) => res.databases.push(DB::new(number)),
+            Item::ResizeDb(attr) => res.current_db().resize_db = Some(attr),
+            Item::Function(code) => res.functions.push(code),
+            Item::Skipped => (),
+            Item::Entry(kv) => res.current_db().key_value_pairs.push(kv),
+        }
+    }
+    Ok((input, res))
 }
 
-fn header(input: &[u8]) -> IResult<u32> {
+pub fn header(input: &[u8]) -> IResult<'_, u32> {
     preceded(
         tag(MAGIC),
         map_res(map_res(take(4u8), std::str::from_utf8), str::parse::<u32>),
     )(input)
 }
 
-fn len(input: &[u8]) -> IResult<LenEncoded> {
+fn len(input: &[u8]) -> IResult<'_, LenEncoded> {
     let (next, l) = be_u8(input)?;
     match l >> 6 {
         0b00 => Ok((next, LenEncoded::Num((l & 0x3F).into()))), // The next 6 bits represent the length
 
         // Read one additional byte. The combined 14 bits represent the length
         0b01 => map(be_u8, |ex| {
-            LenEncoded::Num(((l as u32 & 0x3F) << 8) | (ex as u32))
+            LenEncoded::Num(((l as u64 & 0x3F) << 8) | (ex as u64))
         })(next),
 
+        // 0x81 is followed by an 8 byte length, as large stream IDs need
+        0b10 if l == 0x81 => map(be_u64, LenEncoded::Num)(next),
+
         // Discard the remaining 6 bits. The next 4 bytes from the stream represent the length
-        0b10 => map(be_u32, |n| LenEncoded::Num(n))(next),
+        0b10 => map(be_u32, |n| LenEncoded::Num(n.into()))(next),
 
         // The next object is encoded in a special format. The remaining 6 bits indicate the format.
         0b11 => Ok((next, LenEncoded::Special(l & 0x3F))),
@@ -193,40 +321,105 @@ fn len(input: &[u8]) -> IResult<LenEncoded> {
     }
 }
 
-fn string(input: &[u8]) -> IResult<DBString> {
+fn string(input: &[u8]) -> IResult<'_, DBString> {
     let (next, len) = len(input)?;
     match len {
-        LenEncoded::Num(num) => map_res(take(num), |bytes| {
-            std::str::from_utf8(bytes).map(|s| DBString::Str(s.into()))
-        })(next),
+        LenEncoded::Num(num) => map(take(num), |bytes: &[u8]| DBString::Str(bytes.to_vec()))(next),
         LenEncoded::Special(flag) => match flag {
-            0 => map(be_i8, |n| DBString::Int(n as i32))(next),
-            1 => map(be_i16, |n| DBString::Int(n as i32))(next),
-            2 => map(be_i32, |n| DBString::Int(n))(next),
-            3 => nom_error(next, "Lzf strings are not supported yet"),
-            // 3 => Ok((next, DBString::Lzf)),
+            // Integers are stored in little endian
+            0 => map(le_i8, |n| DBString::Int(n.into()))(next),
+            1 => map(le_i16, |n| DBString::Int(n.into()))(next),
+            2 => map(le_i32, |n| DBString::Int(n.into()))(next),
+            3 => lzf_string(next),
             _ => nom_error(next, "Unspported special length encoding"),
         },
     }
 }
 
-fn kv_pair(input: &[u8]) -> IResult<KVPair> {
-    let (input, variant) = opt(alt((tag([0xFD]), tag([0xFC]))))(input)?;
+/// A compressed string is its compressed and uncompressed lengths followed
+/// by the compressed bytes.
+fn lzf_string(input: &[u8]) -> IResult<'_, DBString> {
+    let (input, (clen, ulen)) = pair(use_len, use_len)(input)?;
+    let (next, compressed) = take(clen)(input)?;
+    match lzf::decompress(compressed, ulen as usize) {
+        Some(bytes) => Ok((next, DBString::Str(bytes))),
+        None => nom_error(input, "Invalid LZF compressed string"),
+    }
+}
 
-    // The expiry is in little endian and wasn't documented
-    let (input, expiration) = match variant {
-        Some([0xFD]) => map(le_u32, |n| Some(Duration::from_secs(n as u64)))(input)?,
-        Some([0xFC]) => map(le_u64, |n| Some(Duration::from_millis(n)))(input)?,
-        _ => (input, None),
+fn kv_pair<'a>(input: &'a [u8]) -> IResult<'a, KVPair> {
+    let (mut input, mut expiration, mut idle, mut freq) = (input, None, None, None);
+    // A key may be preceded by its expiry and by how recently or often it was used
+    let value_type = loop {
+        let (next, opcode) = be_u8(input)?;
+        input = match opcode {
+            // The expiry is in little endian and wasn't documented
+            0xFD => {
+                let (next, secs) = le_u32(next)?;
+                expiration = Some(Duration::from_secs(secs.into()));
+                next
+            }
+            0xFC => {
+                let (next, millis) = le_u64(next)?;
+                expiration = Some(Duration::from_millis(millis));
+                next
+            }
+            0xF8 => {
+                let (next, secs) = use_len(next)?;
+                idle = Some(Duration::from_secs(secs));
+                next
+            }
+            0xF9 => {
+                let (next, counter) = be_u8(next)?;
+                freq = Some(counter);
+                next
+            }
+            value_type => {
+                input = next;
+                break value_type;
+            }
+        };
     };
 
-    let (input, value_type) = be_u8(input)?;
-    let (input, key) = string(input)?;
+    let at_type = |err: nom::Err<RdbError<'a>>| err.map(|err| err.at_opcode(value_type));
+    let (input, key) = string(input).map_err(at_type)?;
 
     let (input, value) = match value_type {
         0 => map(string, Value::String)(input),
+        1 => map(counted(string), Value::List)(input),
+        2 => map(counted(string), Value::Set)(input),
+        3 => map(counted(pair(string, string_double)), Value::SortedSet)(input),
+        4 => map(counted(pair(string, string)), Value::Hash)(input),
+        // Scores are stored as little endian binary doubles.
+        5 => map(counted(pair(string, le_f64)), Value::SortedSet)(input),
+        10 => map(encoded("ziplist", ziplist), Value::List)(input),
+        11 => map(encoded("intset", intset), Value::Set)(input),
+        12 => map(
+            map_opt(encoded("ziplist", ziplist), scored),
+            Value::SortedSet,
+        )(input),
+        13 => map(map_opt(encoded("ziplist", ziplist), pairs), Value::Hash)(input),
+        // A list of ziplists
+        14 => map(counted(encoded("ziplist", ziplist)), |nodes| {
+            Value::List(nodes.into_iter().flatten().collect())
+        })(input),
+        16 => map(map_opt(encoded("listpack", listpack), pairs), Value::Hash)(input),
+        17 => map(
+            map_opt(encoded("listpack", listpack), scored),
+            Value::SortedSet,
+        )(input),
+        18 => map(counted(quicklist_node), |nodes| {
+            Value::List(nodes.into_iter().flatten().collect())
+        })(input),
+        20 => map(encoded("listpack", listpack), Value::Set)(input),
+        // Stream listpacks, each version adding fields to the previous one
+        15 => stream(1)(input),
+        19 => stream(2)(input),
+        21 => stream(3)(input),
+        7 => module(input),
         other => nom_error(input, format!("Unspported value type {other}")),
-    }?;
+    }
+    .map_err(|err| at_type(err).map(|err| err.at_key(&key)))?;
 
     Ok((
         input,
@@ -234,14 +427,397 @@ fn kv_pair(input: &[u8]) -> IResult<KVPair> {
             key,
             value,
             expiration,
+            idle,
+            freq,
         },
     ))
 }
 
+/// A length followed by that many items.
+fn counted<'a, O>(mut item: impl ParseRDB<'a, O>) -> impl FnMut(&'a [u8]) -> IResult<'a, Vec<O>> {
+    move |input: &'a [u8]| {
+        let (mut input, len) = use_len(input)?;
+        // the length comes from the file, so do not trust it with the allocation
+        let mut items = Vec::with_capacity((len as usize).min(1024));
+        for _ in 0..len {
+            let (next, value) = item.parse(input)?;
+            items.push(value);
+            input = next;
+        }
+        Ok((input, items))
+    }
+}
+
+/// Scores of the oldest sorted set encoding are strings, with the lengths
+/// 253 to 255 standing for NaN, infinity and negative infinity.
+fn string_double(input: &[u8]) -> IResult<'_, f64> {
+    let (next, len) = be_u8(input)?;
+    match len {
+        253 => Ok((next, f64::NAN)),
+        254 => Ok((next, f64::INFINITY)),
+        255 => Ok((next, f64::NEG_INFINITY)),
+        len => map_opt(take(len), |bytes: &[u8]| {
+            std::str::from_utf8(bytes).ok()?.parse().ok()
+        })(next),
+    }
+}
+
+/// Compact encodings are blobs stored as a single string, which `inner` parses.
+fn encoded<'a>(
+    name: &'static str,
+    inner: fn(&[u8]) -> IResult<'_, Vec<DBString>>,
+) -> impl FnMut(&'a [u8]) -> IResult<'a, Vec<DBString>> {
+    move |input: &'a [u8]| {
+        let (next, blob) = string(input)?;
+        match inner(&blob.to_bytes()) {
+            Ok((_, entries)) => Ok((next, entries)),
+            Err(_) => nom_error(input, format!("Invalid {name}")),
+        }
+    }
+}
+
+fn bytes<'a>(len: impl nom::ToUsize) -> impl FnMut(&'a [u8]) -> IResult<'a, DBString> {
+    map(take(len), |bytes: &[u8]| DBString::Str(bytes.to_vec()))
+}
+
+/// A ziplist is a header holding its size, the offset of its last entry and
+/// its entry count, then entries until a 0xFF byte.
+fn ziplist(input: &[u8]) -> IResult<'_, Vec<DBString>> {
+    let (input, _) = tuple((le_u32, le_u32, le_u16))(input)?;
+    map(many_till(ziplist_entry, tag([0xFF])), |(entries, _)| {
+        entries
+    })(input)
+}
+
+/// Each entry starts with the length of the previous one, which takes 5 bytes
+/// from 254 on, then an encoding that is either a string length or an integer type.
+fn ziplist_entry(input: &[u8]) -> IResult<'_, DBString> {
+    let (input, prevlen) = be_u8(input)?;
+    let (input, _) = take(if prevlen == 0xFE { 4u8 } else { 0 })(input)?;
+    let (input, encoding) = be_u8(input)?;
+    match encoding {
+        0x00..=0x3F => bytes(encoding)(input),
+        0x40..=0x7F => {
+            let (input, low) = be_u8(input)?;
+            bytes((((encoding & 0x3F) as u32) << 8) | low as u32)(input)
+        }
+        0x80 => {
+            let (input, len) = be_u32(input)?;
+            bytes(len)(input)
+        }
+        0xC0 => map(le_i16, |n| DBString::Int(n.into()))(input),
+        0xD0 => map(le_i32, |n| DBString::Int(n.into()))(input),
+        0xE0 => map(le_i64, DBString::Int)(input),
+        0xF0 => map(le_i24, |n| DBString::Int(n.into()))(input),
+        0xFE => map(le_i8, |n| DBString::Int(n.into()))(input),
+        // Small integers are stored in the encoding itself, off by one
+        0xF1..=0xFD => Ok((input, DBString::Int((encoding & 0x0F) as i64 - 1))),
+        other => nom_error(input, format!("Invalid ziplist encoding {other:#x}")),
+    }
+}
+
+/// A listpack is a header holding its size and element count, then elements
+/// until a 0xFF byte.
+fn listpack(input: &[u8]) -> IResult<'_, Vec<DBString>> {
+    let (input, _) = pair(le_u32, le_u16)(input)?;
+    map(many_till(listpack_entry, tag([0xFF])), |(entries, _)| {
+        entries
+    })(input)
+}
+
+/// Each element is an encoding, its data, then the size of both so the list can
+/// be walked backwards, in as many bytes as it takes 7 bits at a time.
+fn listpack_entry(start: &[u8]) -> IResult<'_, DBString> {
+    let (input, encoding) = be_u8(start)?;
+    let (input, entry) = match encoding {
+        0x00..=0x7F => Ok((input, DBString::Int(encoding.into()))),
+        0x80..=0xBF => bytes(encoding & 0x3F)(input),
+        0xC0..=0xDF => map(be_u8, |low| {
+            // a 13 bit signed integer
+            let n = ((encoding as i64 & 0x1F) << 8) | low as i64;
+            DBString::Int(if n >= 1 << 12 { n - (1 << 13) } else { n })
+        })(input),
+        0xE0..=0xEF => {
+            let (input, low) = be_u8(input)?;
+            bytes((((encoding & 0x0F) as u32) << 8) | low as u32)(input)
+        }
+        0xF0 => {
+            let (input, len) = le_u32(input)?;
+            bytes(len)(input)
+        }
+        0xF1 => map(le_i16, |n| DBString::Int(n.into()))(input),
+        0xF2 => map(le_i24, |n| DBString::Int(n.into()))(input),
+        0xF3 => map(le_i32, |n| DBString::Int(n.into()))(input),
+        0xF4 => map(le_i64, DBString::Int)(input),
+        other => nom_error(input, format!("Invalid listpack encoding {other:#x}")),
+    }?;
+
+    let backlen = match start.len() - input.len() {
+        0..=127 => 1u8,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    };
+    let (input, _) = take(backlen)(input)?;
+    Ok((input, entry))
+}
+
+/// An intset is the byte width of its integers, their count, then the integers.
+fn intset(input: &[u8]) -> IResult<'_, Vec<DBString>> {
+    let (input, (width, len)) = pair(le_u32, le_u32)(input)?;
+    let len = len as usize;
+    match width {
+        2 => count(map(le_i16, |n| DBString::Int(n.into())), len)(input),
+        4 => count(map(le_i32, |n| DBString::Int(n.into())), len)(input),
+        8 => count(map(le_i64, DBString::Int), len)(input),
+        other => nom_error(input, format!("Invalid intset encoding {other}")),
+    }
+}
+
+/// Quicklist nodes are either a single large element or a listpack of them.
+fn quicklist_node(input: &[u8]) -> IResult<'_, Vec<DBString>> {
+    let (input, container) = use_len(input)?;
+    match container {
+        1 => map(string, |entry| vec![entry])(input),
+        2 => encoded("listpack", listpack)(input),
+        other => nom_error(input, format!("Invalid quicklist container {other}")),
+    }
+}
+
+/// Pairs up the flattened fields and values of a compact hash.
+fn pairs(entries: Vec<DBString>) -> Option<Vec<(DBString, DBString)>> {
+    entries
+        .len()
+        .is_multiple_of(2)
+        .then(|| entries.into_iter().tuples().collect())
+}
+
+/// Pairs up the flattened members and scores of a compact sorted set.
+fn scored(entries: Vec<DBString>) -> Option<Vec<(DBString, f64)>> {
+    pairs(entries)?
+        .into_iter()
+        .map(|(member, score)| {
+            let score = match score {
+                DBString::Int(n) => n as f64,
+                DBString::Str(s) => std::str::from_utf8(&s).ok()?.parse().ok()?,
+            };
+            Some((member, score))
+        })
+        .collect()
+}
+
+/// A stream ID as two lengths, its time then its sequence number.
+fn stream_id(input: &[u8]) -> IResult<'_, StreamId> {
+    map(pair(use_len, use_len), |(ms, seq)| StreamId { ms, seq })(input)
+}
+
+/// A stream ID as 16 big endian bytes, the way the pending entries list keys them.
+fn raw_stream_id(input: &[u8]) -> IResult<'_, StreamId> {
+    map(pair(be_u64, be_u64), |(ms, seq)| StreamId { ms, seq })(input)
+}
+
+/// Streams are stored as listpacks of entries keyed by the ID their entries'
+/// IDs are relative to, then their metadata and consumer groups. Versions 2
+/// and 3 add what is needed to track how far behind groups are and when
+/// consumers were last active.
+fn stream<'a>(version: u8) -> impl FnMut(&'a [u8]) -> IResult<'a, Value> {
+    move |input: &'a [u8]| {
+        let mut stream = Stream::default();
+        let (input, nodes) = counted(pair(string, encoded("listpack", listpack)))(input)?;
+        for (master, entries) in nodes {
+            match stream_node(&master.to_bytes(), entries) {
+                Some(entries) => stream.entries.extend(entries),
+                None => return nom_error(input, "Invalid stream listpack"),
+            }
+        }
+
+        let (mut input, (length, last_id)) = pair(use_len, stream_id)(input)?;
+        stream.last_id = last_id;
+        if version >= 2 {
+            let (next, (first_id, max_deleted_id, entries_added)) =
+                tuple((stream_id, stream_id, use_len))(input)?;
+            stream.first_id = first_id;
+            stream.max_deleted_id = max_deleted_id;
+            stream.entries_added = entries_added;
+            input = next;
+        } else {
+            stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+            stream.entries_added = length;
+        }
+        if stream.entries.len() as u64 != length {
+            return nom_error(input, "Stream length does not match its entries");
+        }
+
+        let (input, groups) = counted(consumer_group(version))(input)?;
+        stream.groups = groups;
+        Ok((input, Value::Stream(stream)))
+    }
+}
+
+/// The first entry of a stream listpack is a master entry: how many entries
+/// are valid and deleted, and the fields entries share unless flagged
+/// otherwise. Each entry follows as its flags, its ID as the difference to
+/// the master ID, its fields and how many elements it took up.
+fn stream_node(master: &[u8], entries: Vec<DBString>) -> Option<Vec<(StreamId, Fields)>> {
+    const DELETED: i64 = 1;
+    const SAME_FIELDS: i64 = 2;
+
+    fn int(entries: &mut impl Iterator<Item = DBString>) -> Option<i64> {
+        match entries.next()? {
+            DBString::Int(n) => Some(n),
+            DBString::Str(s) => std::str::from_utf8(&s).ok()?.parse().ok(),
+        }
+    }
+
+    let (_, master) = raw_stream_id(master).ok()?;
+    let mut entries = entries.into_iter();
+
+    let (_count, _deleted) = (int(&mut entries)?, int(&mut entries)?);
+    let master_fields = (0..int(&mut entries)?)
+        .map(|_| entries.next().map(|field| field.to_bytes()))
+        .collect::<Option<Vec<_>>>()?;
+    // the master entry ends with a zero
+    int(&mut entries)?;
+
+    let mut parsed = Vec::new();
+    while entries.len() > 0 {
+        let flags = int(&mut entries)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add_signed(int(&mut entries)?),
+            seq: master.seq.wrapping_add_signed(int(&mut entries)?),
+        };
+        let fields = if flags & SAME_FIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Some((field.clone(), entries.next()?.to_bytes())))
+                .collect::<Option<Fields>>()?
+        } else {
+            (0..int(&mut entries)?)
+                .map(|_| Some((entries.next()?.to_bytes(), entries.next()?.to_bytes())))
+                .collect::<Option<Fields>>()?
+        };
+        // how many elements the entry took up, to walk the listpack backwards
+        int(&mut entries)?;
+        if flags & DELETED == 0 {
+            parsed.push((id, fields));
+        }
+    }
+    Some(parsed)
+}
+
+/// A group is its name, the last ID delivered to it, its pending entries and
+/// its consumers, each with the IDs of the pending entries that are theirs.
+fn consumer_group<'a>(version: u8) -> impl FnMut(&'a [u8]) -> IResult<'a, ConsumerGroup> {
+    move |input: &'a [u8]| {
+        let (mut input, (name, last_id)) = pair(string, stream_id)(input)?;
+        let mut entries_read = None;
+        if version >= 2 {
+            let (next, read) = use_len(input)?;
+            // an unknown count is stored as -1
+            entries_read = (read != u64::MAX).then_some(read);
+            input = next;
+        }
+
+        let (input, pending) = counted(tuple((raw_stream_id, le_u64, use_len)))(input)?;
+        let mut pending = pending
+            .into_iter()
+            .map(|(id, delivery_time, delivery_count)| {
+                let entry = PendingEntry {
+                    consumer: Vec::new(),
+                    delivery_time,
+                    delivery_count,
+                };
+                (id, entry)
+            })
+            .collect::<std::collections::BTreeMap<_, _>>();
+
+        let consumer = |input: &'a [u8]| {
+            let (input, (name, seen_time)) = pair(string, le_u64)(input)?;
+            let (input, active_time) = match version {
+                3 => le_u64(input)?,
+                _ => (input, seen_time),
+            };
+            let (input, ids) = counted(raw_stream_id)(input)?;
+            let consumer = Consumer {
+                name: name.to_bytes(),
+                seen_time,
+                active_time,
+            };
+            Ok((input, (consumer, ids)))
+        };
+        let (input, consumers) = counted(consumer)(input)?;
+
+        let mut group = ConsumerGroup {
+            name: name.to_bytes(),
+            last_id,
+            entries_read,
+            pending: Default::default(),
+            consumers: Vec::new(),
+        };
+        for (consumer, ids) in consumers {
+            for id in ids {
+                let Some(mut entry) = pending.remove(&id) else {
+                    return nom_error(input, "Consumer pending entry missing from its group");
+                };
+                entry.consumer.clone_from(&consumer.name);
+                group.pending.insert(id, entry);
+            }
+            group.consumers.push(consumer);
+        }
+        if !pending.is_empty() {
+            return nom_error(input, "Group pending entry without a consumer");
+        }
+        Ok((input, group))
+    }
+}
+
+/// Module values are fields, each an opcode saying how it is encoded, until
+/// an end opcode. What the fields mean is up to the module, so they are only
+/// walked over to find where the value ends.
+fn module(input: &[u8]) -> IResult<'_, Value> {
+    let (body, id) = use_len(input)?;
+    let (rest, payload) = module_fields(body)?;
+    Ok((
+        rest,
+        Value::Module {
+            id,
+            payload: payload.to_vec(),
+        },
+    ))
+}
+
+/// Walks module fields up to and including the end opcode, returning the
+/// bytes they took up.
+fn module_fields(body: &[u8]) -> IResult<'_, &[u8]> {
+    let mut rest = body;
+    loop {
+        let (next, opcode) = use_len(rest)?;
+        rest = match opcode {
+            0 => break rest = next,
+            // signed and unsigned integers
+            1 | 2 => use_len(next)?.0,
+            3 => take(4u8)(next)?.0,
+            4 => take(8u8)(next)?.0,
+            5 => string(next)?.0,
+            other => return nom_error(rest, format!("Unknown module opcode {other}")),
+        };
+    }
+    Ok((rest, &body[..body.len() - rest.len()]))
+}
+
+/// The name packed into the top 54 bits of a module type ID, 6 bits a character.
+pub fn module_type_name(id: u64) -> String {
+    const CHARSET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    (0..9)
+        .map(|i| CHARSET[(id >> (58 - 6 * i) & 63) as usize] as char)
+        .collect()
+}
+
 fn map_len<'a>(
     mut parse_fn: impl ParseRDB<'a, LenEncoded>,
-) -> impl FnMut(&'a [u8]) -> IResult<u32> {
-    move |input: &'a [u8]| -> IResult<u32> {
+) -> impl FnMut(&'a [u8]) -> IResult<u64> {
+    move |input: &'a [u8]| -> IResult<u64> {
         let (input, len) = parse_fn.parse(input)?;
         match len {
             LenEncoded::Num(num) => Ok((input, num)),
@@ -253,57 +829,31 @@ fn map_len<'a>(
     }
 }
 
-fn use_len(input: &[u8]) -> IResult<u32> {
+fn use_len(input: &[u8]) -> IResult<'_, u64> {
     map_len(len)(input)
 }
 
-fn db_number(input: &[u8]) -> IResult<u32> {
+fn db_number(input: &[u8]) -> IResult<'_, u64> {
     map_len(preceded(tag(&[0xFE]), len))(input)
 }
 
-fn resize_db(input: &[u8]) -> IResult<Option<ResizeDBAttr>> {
-    opt(preceded(
+fn resize_db(input: &[u8]) -> IResult<'_, ResizeDBAttr> {
+    preceded(
         tag([0xFB]),
         map(pair(use_len, use_len), |(l1, l2)| ResizeDBAttr {
             hash_table_size: l1,
             expire_hash_table_size: l2,
         }),
-    ))(input)
-}
-
-fn auxilliary(input: &[u8]) -> IResult<Vec<Auxilliary>> {
-    let base = map(
-        preceded(tag([0xFA]), pair(string, string)),
-        |(key, value)| Auxilliary { key, value },
-    );
-    many0(base)(input)
-}
-
-fn db(input: &[u8]) -> IResult<DB> {
-    let (input, number) = db_number(input)?;
-    let (input, resize_db) = resize_db(input)?;
-    let (_, (key_value_pairs, _)) = many_till(kv_pair, alt((tag([0xFE]), tag([0xFF]))))(input)?;
-
-    // FIXME: feels like nom should save us from doing this, but wasn't able to find a solution from its docs
-
-    let index = input
-        .iter()
-        .position(|&x| x == 0xFE_u8 || x == 0xFF_u8)
-        .unwrap();
-    Ok((
-        &input[index..],
-        DB {
-            number,
-            resize_db,
-            key_value_pairs,
-        },
-    ))
+    )(input)
 }
 
 #[cfg(test)]
 mod test {
     use std::{error::Error, fs::File, io::Read};
 
+    use nom::combinator::complete;
+    use nom::multi::many0;
+
     use super::*;
     #[test]
     fn parse_header() {
@@ -349,7 +899,7 @@ mod test {
         let tests: &[(&[u8], DBString)] = &[
             (
                 &[0xC2, 0x25, 0xD3, 0xED, 0x52], // i32 string
-                DBString::Int(634645842),
+                DBString::Int(1391317797),
             ),
             (
                 &[0xC0, 0x7D], // i8 string
@@ -361,29 +911,33 @@ mod test {
             ),
             (
                 &[0xC1, 0xDB, 0x8C], // i16 string
-                DBString::Int(-9332),
+                DBString::Int(-29477),
             ),
             (
                 &[0xC2, 0xAB, 0xAB, 0x00, 0x00], // i32 string
-                DBString::Int(-1414856704),
+                DBString::Int(43947),
             ),
             (
                 &[0xC2, 0xDB, 0x2C, 0x12, 0xF5], // i32 string
-                DBString::Int(-617868555),
+                DBString::Int(-183358245),
             ),
             (
                 &[
                     0x17, 0x50, 0x6f, 0x73, 0x69, 0x74, 0x69, 0x76, 0x65, 0x20, 0x33, 0x32, 0x20,
                     0x62, 0x69, 0x74, 0x20, 0x69, 0x6e, 0x74, 0x65, 0x67, 0x65, 0x72,
                 ],
-                DBString::Str(String::from("Positive 32 bit integer")),
+                DBString::Str("Positive 32 bit integer".into()),
             ),
             (
                 &[
                     0x16, 0x50, 0x6f, 0x73, 0x69, 0x74, 0x69, 0x76, 0x65, 0x20, 0x38, 0x20, 0x62,
                     0x69, 0x74, 0x20, 0x69, 0x6e, 0x74, 0x65, 0x67, 0x65, 0x72,
                 ],
-                DBString::Str(String::from("Positive 8 bit integer")),
+                DBString::Str("Positive 8 bit integer".into()),
+            ),
+            (
+                &[0xC3, 0x05, 0x17, 0x00, 0x61, 0xE0, 0x0D, 0x00], // lzf string
+                DBString::Str(vec![b'a'; 23]),
             ),
         ];
 
@@ -405,9 +959,11 @@ mod test {
                     0x65, 0x67, 0x65, 0x72,
                 ],
                 KVPair {
-                    value: Value::String(DBString::Str(String::from("Positive 32 bit integer"))),
+                    value: Value::String(DBString::Str("Positive 32 bit integer".into())),
                     expiration: None,
-                    key: DBString::Int(634645770),
+                    idle: None,
+                    freq: None,
+                    key: DBString::Int(183358245),
                 },
             ),
             (
@@ -416,8 +972,10 @@ mod test {
                     0x38, 0x20, 0x62, 0x69, 0x74, 0x20, 0x69, 0x6e, 0x74, 0x65, 0x67, 0x65, 0x72,
                 ],
                 KVPair {
-                    value: Value::String(DBString::Str(String::from("Positive 8 bit integer"))),
+                    value: Value::String(DBString::Str("Positive 8 bit integer".into())),
                     expiration: None,
+                    idle: None,
+                    freq: None,
                     key: DBString::Int(125),
                 },
             ),
@@ -449,9 +1007,12 @@ mod test {
             0x65, 0x67, 0x65, 0x72, 0xff,
         ];
 
-        let (input, db) = db(input)?;
+        let input = [&b"REDIS0003"[..], input].concat();
+        let (input, rdb) = parse_rdb(&input).unwrap();
+        let db = &rdb.databases[0];
 
-        assert_eq!(input, &[0xff]);
+        assert_eq!(input, &[]);
+        assert_eq!(rdb.databases.len(), 1);
         assert_eq!(db.number, 0);
         assert_eq!(db.resize_db, None);
         assert_eq!(db.key_value_pairs.len(), 6);
@@ -459,6 +1020,126 @@ mod test {
         Ok(())
     }
 
+    /// A value of `value_type` at key "k", its encoded blob stored as a string.
+    fn compact(value_type: u8, blob: &[u8]) -> Vec<u8> {
+        [&[value_type, 0x01, b'k', blob.len() as u8], blob].concat()
+    }
+
+    const ZIPLIST: &[u8] = &[
+        0x15, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x04, 0x00, // header
+        0x00, 0x03, b'a', b'b', b'c', // "abc"
+        0x05, 0xf5, // 4, in the encoding
+        0x02, 0xc0, 0xfe, 0xff, // -2 as an i16
+        0x04, 0x03, b'1', b'.', b'5', // "1.5"
+        0xff,
+    ];
+
+    const LISTPACK: &[u8] = &[
+        0x16, 0x00, 0x00, 0x00, 0x04, 0x00, // header
+        0x85, b'h', b'e', b'l', b'l', b'o', 0x06, // "hello"
+        0x07, 0x01, // 7
+        0xdf, 0xff, 0x02, // -1 as a 13 bit integer
+        0xf3, 0xa0, 0x86, 0x01, 0x00, 0x05, // 100000 as an i32
+        0xff,
+    ];
+
+    #[test]
+    fn parse_compact_encodings() {
+        use DBString::{Int, Str};
+
+        let ziplist = vec![Str("abc".into()), Int(4), Int(-2), Str("1.5".into())];
+        let listpack = vec![Str("hello".into()), Int(7), Int(-1), Int(100000)];
+        let intset = [
+            &[0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00][..],
+            &[0x01, 0x00, 0xff, 0xff, 0x00, 0x80],
+        ]
+        .concat();
+
+        let mut quicklist2 = vec![18, 0x01, b'k', 0x02, 0x02, LISTPACK.len() as u8];
+        quicklist2.extend_from_slice(LISTPACK);
+        quicklist2.extend_from_slice(&[0x01, 0x03, b'b', b'i', b'g']);
+
+        let tests: &[(Vec<u8>, Value)] = &[
+            (compact(10, ZIPLIST), Value::List(ziplist)),
+            (
+                compact(11, &intset),
+                Value::Set(vec![Int(1), Int(-1), Int(-32768)]),
+            ),
+            (
+                compact(12, ZIPLIST),
+                Value::SortedSet(vec![(Str("abc".into()), 4.0), (Int(-2), 1.5)]),
+            ),
+            (
+                compact(16, LISTPACK),
+                Value::Hash(vec![(Str("hello".into()), Int(7)), (Int(-1), Int(100000))]),
+            ),
+            (compact(20, LISTPACK), Value::Set(listpack)),
+            (
+                quicklist2,
+                Value::List(vec![
+                    Str("hello".into()),
+                    Int(7),
+                    Int(-1),
+                    Int(100000),
+                    Str("big".into()),
+                ]),
+            ),
+            (
+                vec![
+                    3, 0x01, b'k', 0x02, 0x01, b'a', 0x03, b'2', b'.', b'5', 0x01, b'b', 0xff,
+                ],
+                Value::SortedSet(vec![
+                    (Str("a".into()), 2.5),
+                    (Str("b".into()), f64::NEG_INFINITY),
+                ]),
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let (next, res) = kv_pair(input).unwrap();
+            assert_eq!(res.value, *expected);
+            assert_eq!(next.len(), 0);
+        }
+
+        // an odd number of entries cannot be a hash
+        assert!(kv_pair(&compact(13, &ZIPLIST[..ZIPLIST.len() - 6])).is_err());
+    }
+
+    #[test]
+    fn skips_module_values() {
+        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        // "ReJSON-RL" at encoding version 3
+        let id = "ReJSON-RL".bytes().fold(0, |id, c| {
+            (id << 6) | CHARSET.iter().position(|&x| x == c).unwrap() as u64
+        }) << 10
+            | 3;
+        assert_eq!(module_type_name(id), "ReJSON-RL");
+
+        let payload = [
+            &[0x01, 0x05][..],                     // a signed integer
+            &[0x03, 0x00, 0x00, 0x80, 0x3f],       // a float
+            &[0x04, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f], // a double
+            &[0x05, 0x02, b'h', b'i'],             // a string
+            &[0x00],
+        ]
+        .concat();
+        let input = [
+            &[0x07, 0x01, b'k', 0x81][..],
+            &id.to_be_bytes(),
+            &payload,
+            &[0xff],
+        ]
+        .concat();
+
+        let (rest, kv) = kv_pair(&input).unwrap();
+        assert_eq!(rest, &[0xff]);
+        assert_eq!(kv.value, Value::Module { id, payload });
+
+        // opcodes past the string one are not defined
+        let input = [&[0x07, 0x01, b'k', 0x03][..], &[0x06, 0x00]].concat();
+        assert!(kv_pair(&input).is_err());
+    }
+
     #[test]
     fn parse_aux() -> Result<(), Box<dyn Error>> {
         let input = &[
@@ -466,14 +1147,58 @@ mod test {
             100, 105, 115, 45, 118, 101, 114, 5, 55, 46, 50, 46, 48,
         ];
 
-        let (input, aux) = auxilliary(input)?;
+        let (input, aux) = many0(complete(item))(input)?;
 
         assert_eq!(input, &[]);
         assert_eq!(aux.len(), 2);
-        dbg!(aux);
+        assert!(aux.iter().all(|item| matches!(item, Item::Aux(_))));
 
         Ok(())
     }
+    #[test]
+    fn parse_every_opcode() {
+        let input = [
+            &b"REDIS0012"[..],
+            &[0xFA, 0x01, b'a', 0x01, b'1'],                   // aux
+            &[0xF5, 0x04, b'c', b'o', b'd', b'e'],             // function library
+            &[0xF7, 0x05, 0x02, 0x01, 0x02, 0x07, 0x00],       // module aux
+            &[0x00, 0x01, b'x', 0x01, b'0'],                   // a key before any SELECTDB
+            &[0xFE, 0x03, 0xFB, 0x02, 0x01],                   // database 3
+            &[0xF4, 0x7F, 0xFF, 0x01, 0x01],                   // slot info
+            &[0xF8, 0x40, 0x80, 0x00, 0x01, b'i', 0x01, b'v'], // idle for 128 seconds
+            &[0xFA, 0x01, b'b', 0x01, b'2'],                   // aux in between keys
+            &[0xFC, 0x10, 0, 0, 0, 0, 0, 0, 0],                // expiry, then frequency
+            &[0xF9, 0x05, 0x00, 0x01, b'f', 0x01, b'v'],
+            &[0xFF],
+        ]
+        .concat();
+
+        let (rest, rdb) = parse_rdb(&input).unwrap();
+        assert_eq!(rest, &[]);
+        assert_eq!(rdb.version, 12);
+        assert_eq!(rdb.auxilliary_field.len(), 2);
+        assert_eq!(rdb.functions, vec![DBString::Str("code".into())]);
+        assert_eq!(
+            rdb.databases.iter().map(|db| db.number).collect::<Vec<_>>(),
+            vec![0, 3]
+        );
+        assert_eq!(
+            rdb.get("x").next(),
+            Some(&Value::String(DBString::Str("0".into())))
+        );
+
+        let db = &rdb.databases[1];
+        assert_eq!(db.resize_db.as_ref().map(|r| r.hash_table_size), Some(2));
+        let idle = &db.key_value_pairs[0];
+        assert_eq!(
+            (idle.idle, idle.freq),
+            (Some(Duration::from_secs(128)), None)
+        );
+        let freq = &db.key_value_pairs[1];
+        assert_eq!(freq.expiration, Some(Duration::from_millis(16)));
+        assert_eq!((freq.idle, freq.freq), (None, Some(5)));
+    }
+
     #[test]
     fn db_number_parser() {
         let input: &[u8] = &[0xFE, 0x00];
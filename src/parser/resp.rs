@@ -5,10 +5,10 @@ use std::{
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until},
-    combinator::{map, map_res, opt},
-    multi::many_m_n,
-    sequence::{delimited, preceded, terminated},
+    bytes::streaming::{tag, take, take_until},
+    combinator::{map, map_res},
+    error::ErrorKind,
+    sequence::{preceded, terminated},
     IResult,
 };
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
@@ -19,7 +19,7 @@ pub struct Error {
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum BulkString {
-    String(String),
+    String(Vec<u8>),
     Empty,
     Null,
 }
@@ -40,82 +40,83 @@ pub enum Value {
     Array(Array),
 }
 
-fn simple_str(input: &str) -> IResult<&str, Value> {
-    map(
-        terminated(preceded(tag("+"), take_until("\r\n")), tag("\r\n")),
-        |res: &str| Value::String(res.to_string()),
+fn line(input: &[u8]) -> IResult<&[u8], &str> {
+    map_res(
+        terminated(take_until("\r\n"), tag("\r\n")),
+        std::str::from_utf8,
     )(input)
 }
 
-fn bulk_str(input: &str) -> IResult<&str, Value> {
-    let head = map_res(
-        delimited(tag("$"), take_until("\r\n"), tag("\r\n")),
-        |res: &str| res.parse::<isize>(),
-    )(input);
+fn simple_str(input: &[u8]) -> IResult<&[u8], Value> {
+    map(preceded(tag("+"), line), |res: &str| {
+        Value::String(res.to_string())
+    })(input)
+}
 
-    let body = opt(terminated(take_until("\r\n"), tag("\r\n")));
-    head.and_then(|(next, size)| {
-        if size == -1 {
-            return Ok((next, Value::BulkString(BulkString::Null)));
-        }
-        map(body, |res: Option<&str>| match size {
-            0 => Value::BulkString(BulkString::Empty),
-            _ => Value::BulkString(BulkString::String(res.unwrap_or_default().to_owned())),
-        })(next)
-    })
+/// Fails parsing for good on a length no value can have, rather than waiting
+/// for more input that cannot make it valid.
+fn invalid_length(input: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Failure(nom::error::Error::new(input, ErrorKind::Verify))
 }
 
-fn int(input: &str) -> IResult<&str, Value> {
-    map_res(
-        terminated(preceded(tag(":"), take_until("\r\n")), tag("\r\n")),
-        |res: &str| {
-            if res.starts_with("+") {
-                res[1..].parse::<isize>().map(Value::Int)
-            } else {
-                res.parse::<isize>().map(Value::Int)
-            }
-        },
-    )(input)
+fn bulk_str(input: &[u8]) -> IResult<&[u8], Value> {
+    let (next, size) = map_res(preceded(tag("$"), line), str::parse::<isize>)(input)?;
+
+    match size {
+        ..-1 => Err(invalid_length(input)),
+        -1 => Ok((next, Value::BulkString(BulkString::Null))),
+        0 => map(tag("\r\n"), |_| Value::BulkString(BulkString::Empty))(next),
+        // bulk strings are binary safe, so the body is read by length rather than up to a CRLF
+        _ => map(
+            terminated(take(size as usize), tag("\r\n")),
+            |res: &[u8]| Value::BulkString(BulkString::String(res.to_vec())),
+        )(next),
+    }
+}
+
+fn int(input: &[u8]) -> IResult<&[u8], Value> {
+    map_res(preceded(tag(":"), line), |res: &str| {
+        res.strip_prefix('+')
+            .unwrap_or(res)
+            .parse::<isize>()
+            .map(Value::Int)
+    })(input)
 }
 
-fn arr(input: &str) -> IResult<&str, Value> {
-    let head = map_res(
-        delimited(tag("*"), take_until("\r\n"), tag("\r\n")),
-        |res: &str| res.parse::<isize>(),
-    )(input);
-
-    head.and_then(|(next, size)| {
-        map(
-            opt(many_m_n(
-                size as usize,
-                size as usize,
-                alt((simple_str, int, bulk_str, error, arr)),
-            )),
-            |res: Option<Vec<Value>>| match size {
-                -1 => Value::Array(Array::Null),
-                0 => Value::Array(Array::Empty),
-                _ => Value::Array(Array::Items(res.unwrap_or_default())),
-            },
-        )(next)
-    })
+fn arr(input: &[u8]) -> IResult<&[u8], Value> {
+    let (mut next, size) = map_res(preceded(tag("*"), line), str::parse::<isize>)(input)?;
+
+    match size {
+        ..-1 => Err(invalid_length(input)),
+        -1 => Ok((next, Value::Array(Array::Null))),
+        0 => Ok((next, Value::Array(Array::Empty))),
+        _ => {
+            // the length comes from the client, so do not trust it with the allocation
+            let mut items = Vec::with_capacity(size.min(1024) as usize);
+            for _ in 0..size {
+                let (rest, item) = parser(next)?;
+                items.push(item);
+                next = rest;
+            }
+            Ok((next, Value::Array(Array::Items(items))))
+        }
+    }
 }
 
-pub fn parser(input: &str) -> IResult<&str, Value> {
+/// Parses a single RESP value. Returns `nom::Err::Incomplete` when the input
+/// ends before the value does, so callers can buffer more bytes and retry.
+pub fn parser(input: &[u8]) -> IResult<&[u8], Value> {
     alt((simple_str, int, bulk_str, error, arr))(input)
 }
 
-fn error(input: &str) -> IResult<&str, Value> {
-    let pattern = delimited(tag("-"), take_until("\r\n"), tag("\r\n"));
-    map(pattern, |res: &str| {
-        let entry = res
-            .split_once(" ")
-            .ok_or(format!("invalid error message {res}"));
-        match entry {
-            Ok((title, message)) => Value::Error(Error {
+fn error(input: &[u8]) -> IResult<&[u8], Value> {
+    map(preceded(tag("-"), line), |res: &str| {
+        match res.split_once(' ') {
+            Some((title, message)) => Value::Error(Error {
                 title: title.to_string(),
                 message: message.to_string(),
             }),
-            Err(_) => Value::Error(Error {
+            None => Value::Error(Error {
                 title: res.to_string(),
                 message: "".to_string(),
             }),
@@ -124,23 +125,62 @@ fn error(input: &str) -> IResult<&str, Value> {
 }
 
 impl Value {
-    pub fn reply(&self, stream: &mut TcpStream) -> io::Result<usize> {
-        stream.write(self.to_string().as_bytes())
+    pub fn reply(&self, stream: &mut TcpStream) -> io::Result<()> {
+        stream.write_all(&self.to_bytes())
+    }
+
+    pub fn ok() -> Self {
+        Value::String("OK".into())
+    }
+
+    pub fn error(title: impl Into<String>, message: impl Into<String>) -> Self {
+        Value::Error(Error {
+            title: title.into(),
+            message: message.into(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Value::String(entry) => format!("+{entry}\r\n").into_bytes(),
+            Value::BulkString(b) => b.to_bytes(),
+            Value::Error(err) => format!(
+                "-{}{}{}\r\n",
+                err.title,
+                if err.message.is_empty() { "" } else { " " },
+                err.message
+            )
+            .into_bytes(),
+            Value::Int(int) => format!(":{int}\r\n").into_bytes(),
+            Value::Array(a) => a.to_bytes(),
+        }
     }
 }
 
 impl From<&str> for BulkString {
     fn from(value: &str) -> Self {
-        BulkString::String(value.to_string())
+        BulkString::String(value.as_bytes().to_vec())
     }
 }
 
 impl From<String> for BulkString {
     fn from(value: String) -> Self {
+        BulkString::String(value.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for BulkString {
+    fn from(value: Vec<u8>) -> Self {
         BulkString::String(value)
     }
 }
 
+impl From<&[u8]> for BulkString {
+    fn from(value: &[u8]) -> Self {
+        BulkString::String(value.to_vec())
+    }
+}
+
 impl From<BulkString> for Value {
     fn from(value: BulkString) -> Self {
         Value::BulkString(value)
@@ -153,55 +193,55 @@ impl From<Array> for Value {
     }
 }
 
-impl BulkString {
-    pub fn inner(&self) -> String {
-        match self {
-            BulkString::String(inner) => inner.to_string(),
-            BulkString::Empty => "".to_string(),
-            BulkString::Null => "".to_string(),
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        if value.is_empty() {
+            Array::Empty.into()
+        } else {
+            Array::Items(value).into()
         }
     }
 }
 
-impl ToString for BulkString {
-    fn to_string(&self) -> String {
+impl BulkString {
+    /// The contents as text; bytes that are not valid UTF-8 are replaced.
+    pub fn inner(&self) -> String {
+        String::from_utf8_lossy(self.as_bytes()).into_owned()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
         match self {
-            BulkString::String(inner) => format!("${}\r\n{}\r\n", inner.len(), inner),
-            BulkString::Empty => "$0\r\n\r\n".to_string(),
-            BulkString::Null => "$-1\r\n".to_string(),
+            BulkString::String(inner) => inner,
+            BulkString::Empty | BulkString::Null => &[],
         }
     }
-}
 
-impl ToString for Value {
-    fn to_string(&self) -> String {
+    pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            Value::String(entry) => format!("+{entry}\r\n"),
-            Value::BulkString(b) => b.to_string(),
-            Value::Error(err) => format!(
-                "-{}{}{}",
-                err.title,
-                if err.message.is_empty() { "" } else { " " },
-                err.message
-            ),
-            Value::Int(int) => format!(":{}\r\n", int.to_string()),
-            Value::Array(a) => a.to_string(),
+            BulkString::String(inner) => {
+                let mut out = format!("${}\r\n", inner.len()).into_bytes();
+                out.extend_from_slice(inner);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+            BulkString::Empty => b"$0\r\n\r\n".to_vec(),
+            BulkString::Null => b"$-1\r\n".to_vec(),
         }
     }
 }
 
-impl ToString for Array {
-    fn to_string(&self) -> String {
+impl Array {
+    pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             Array::Items(arr) => {
-                format!(
-                    "*{}\r\n{}",
-                    arr.len(),
-                    arr.iter().map(ToString::to_string).collect::<String>()
-                )
+                let mut out = format!("*{}\r\n", arr.len()).into_bytes();
+                for item in arr {
+                    out.extend(item.to_bytes());
+                }
+                out
             }
-            Array::Empty => "*0\r\n".to_string(),
-            Array::Null => "*-1\r\n".to_string(),
+            Array::Empty => b"*0\r\n".to_vec(),
+            Array::Null => b"*-1\r\n".to_vec(),
         }
     }
 }
@@ -211,9 +251,9 @@ mod test {
     use super::*;
     #[test]
     fn simple_str_works() {
-        let (remaining, value) = simple_str("+OK\r\n").unwrap();
-        assert_eq!(value, Value::String("OK".into()).into());
-        assert_eq!(remaining, "");
+        let (remaining, value) = simple_str(b"+OK\r\n").unwrap();
+        assert_eq!(value, Value::String("OK".into()));
+        assert_eq!(remaining, b"");
     }
 
     #[test]
@@ -226,9 +266,9 @@ mod test {
         ];
 
         for err in errors {
-            let (remaining, value) = error(err).unwrap();
+            let (remaining, value) = error(err.as_bytes()).unwrap();
             assert!(matches!(value, Value::Error(Error { .. })));
-            assert_eq!(remaining, "");
+            assert_eq!(remaining, b"");
         }
     }
 
@@ -237,9 +277,9 @@ mod test {
         let ints = [":10\r\n", ":-1000\r\n", ":+2000\r\n"];
 
         for it in ints {
-            let (remaining, value) = int(it).unwrap();
+            let (remaining, value) = int(it.as_bytes()).unwrap();
             assert!(matches!(value, Value::Int(..)));
-            assert_eq!(remaining, "");
+            assert_eq!(remaining, b"");
         }
     }
 
@@ -248,9 +288,9 @@ mod test {
         let strs = ["$5\r\nhello\r\n", "$0\r\n\r\n", "$-1\r\n"];
 
         for s in strs {
-            let (remaining, value) = bulk_str(s).unwrap();
+            let (remaining, value) = bulk_str(s.as_bytes()).unwrap();
             assert!(matches!(value, Value::BulkString(..)));
-            assert_eq!(remaining, "");
+            assert_eq!(remaining, b"");
         }
     }
 
@@ -305,9 +345,53 @@ mod test {
         ];
 
         for (input, expected) in arrays {
-            let (remaining, value) = arr(input).unwrap();
+            let (remaining, value) = arr(input.as_bytes()).unwrap();
             assert_eq!(value, expected.into());
-            assert_eq!(remaining, "");
+            assert_eq!(remaining, b"");
         }
     }
+
+    #[test]
+    fn bulk_str_is_binary_safe() {
+        let (remaining, value) = bulk_str(b"$4\r\n\x00\r\n\xff\r\n").unwrap();
+        assert_eq!(value, BulkString::from(&b"\x00\r\n\xff"[..]).into());
+        assert_eq!(remaining, b"");
+    }
+
+    #[test]
+    fn incomplete_input_is_reported() {
+        let partials: [&[u8]; 4] = [b"*2\r\n$3\r\nGET", b"$5\r\nhel", b"*1\r\n", b":10"];
+
+        for input in partials {
+            assert!(matches!(parser(input), Err(nom::Err::Incomplete(_))));
+        }
+    }
+
+    #[test]
+    fn invalid_lengths_are_rejected() {
+        for input in [&b"$-2\r\n"[..], b"*-5\r\n", b"*1\r\n$-3\r\nabc\r\n"] {
+            assert!(matches!(parser(input), Err(nom::Err::Failure(_))));
+        }
+        // a huge length waits for its items rather than making room for them
+        assert!(matches!(
+            parser(b"*99999999999\r\n"),
+            Err(nom::Err::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value: Value = Array::Items(vec![
+            BulkString::from("hello").into(),
+            Value::Int(-3),
+            Value::error("ERR", "oops"),
+            BulkString::Null.into(),
+        ])
+        .into();
+
+        let bytes = value.to_bytes();
+        let (remaining, parsed) = parser(&bytes).unwrap();
+        assert_eq!(parsed, value);
+        assert_eq!(remaining, b"");
+    }
 }
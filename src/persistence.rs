@@ -0,0 +1,480 @@
+pub mod aof;
+pub mod crc64;
+pub mod listpack;
+pub mod lzf;
+pub mod rdb;
+
+use std::{
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::store::Databases;
+use aof::Aof;
+
+/// How long to wait before retrying a background save that failed.
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Take a snapshot once `seconds` have passed since the last one, if at least
+/// `changes` writes happened in the meantime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parses `save` rules written as `"<seconds> <changes> ..."`. An empty
+/// string means no rules, turning automatic snapshots off.
+pub fn parse_save_rules(rules: &str) -> Option<Vec<SaveRule>> {
+    let numbers = rules
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(
+        numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect(),
+    )
+}
+
+/// A snapshot being written by a background thread.
+#[derive(Debug)]
+struct BgSave {
+    started: Instant,
+    dirty_at_start: u64,
+    keys_total: usize,
+    keys_processed: Arc<AtomicUsize>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+/// The dataset being loaded from disk, while commands other than `INFO` wait.
+#[derive(Debug)]
+struct Loading {
+    started: SystemTime,
+    total_bytes: usize,
+    loaded_bytes: Arc<AtomicUsize>,
+}
+
+/// Bookkeeping for RDB snapshots, reported by `INFO persistence`.
+#[derive(Debug)]
+pub struct Persistence {
+    rules: Vec<SaveRule>,
+    /// Whether snapshots LZF compress long strings.
+    compression: bool,
+    /// Writes since the last successful save.
+    dirty: u64,
+    lastsave: SystemTime,
+    last_bgsave_try: Option<Instant>,
+    saves: usize,
+    bgsave: Option<BgSave>,
+    scheduled: bool,
+    last_bgsave_ok: bool,
+    last_bgsave_duration: Option<Duration>,
+    aof: Option<Aof>,
+    aof_last_write_ok: bool,
+    loading: Option<Loading>,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            compression: true,
+            dirty: 0,
+            lastsave: SystemTime::now(),
+            last_bgsave_try: None,
+            saves: 0,
+            bgsave: None,
+            scheduled: false,
+            last_bgsave_ok: true,
+            last_bgsave_duration: None,
+            aof: None,
+            aof_last_write_ok: true,
+            loading: None,
+        }
+    }
+}
+
+impl Persistence {
+    pub fn rules(&self) -> &[SaveRule] {
+        &self.rules
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<SaveRule>) {
+        self.rules = rules;
+    }
+
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Whether snapshots, the append only file's base included, compress strings.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compression = compress;
+        if let Some(aof) = &mut self.aof {
+            aof.set_rdb_compression(compress);
+        }
+    }
+
+    /// Marks the dataset as loading from `total_bytes` of files. The loader
+    /// counts the bytes it has read in the returned counter.
+    pub fn start_loading(&mut self, total_bytes: usize) -> Arc<AtomicUsize> {
+        let loaded_bytes = Arc::new(AtomicUsize::new(0));
+        self.loading = Some(Loading {
+            started: SystemTime::now(),
+            total_bytes,
+            loaded_bytes: Arc::clone(&loaded_bytes),
+        });
+        loaded_bytes
+    }
+
+    pub fn finish_loading(&mut self) {
+        self.loading = None;
+    }
+
+    pub fn loading(&self) -> bool {
+        self.loading.is_some()
+    }
+
+    /// Starts logging writes to `aof`.
+    pub fn set_aof(&mut self, aof: Aof) {
+        self.aof = Some(aof);
+    }
+
+    /// Records a write command that ran against database `db`: it counts
+    /// towards the `save` rules and is logged to the append only file.
+    pub fn feed(&mut self, db: usize, command: &[u8]) {
+        self.dirty += 1;
+        self.log(db, command);
+    }
+
+    /// Logs `command` to the append only file without counting it as a
+    /// change, for the `MULTI` and `EXEC` around a transaction's writes.
+    pub fn log(&mut self, db: usize, command: &[u8]) {
+        if let Some(aof) = &mut self.aof {
+            self.aof_last_write_ok = match aof.feed(db, command) {
+                Ok(()) => true,
+                Err(err) => {
+                    eprintln!("could not write to the append only file: {err}");
+                    false
+                }
+            };
+        }
+    }
+
+    /// Whether a `save` rule says it is time for a snapshot. A failed
+    /// background save is only retried after a delay.
+    fn rule_met(&self) -> bool {
+        if !self.last_bgsave_ok
+            && self
+                .last_bgsave_try
+                .is_some_and(|tried| tried.elapsed() < BGSAVE_RETRY_DELAY)
+        {
+            return false;
+        }
+        let since = self.lastsave.elapsed().unwrap_or_default().as_secs();
+        self.rules
+            .iter()
+            .any(|rule| self.dirty >= rule.changes && since >= rule.seconds)
+    }
+
+    /// Collects the result of a background save that has finished.
+    fn reap(&mut self) {
+        if !self
+            .bgsave
+            .as_ref()
+            .is_some_and(|bgsave| bgsave.handle.is_finished())
+        {
+            return;
+        }
+        let Some(bgsave) = self.bgsave.take() else {
+            return;
+        };
+
+        let result = bgsave
+            .handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("background save panicked")));
+        self.last_bgsave_duration = Some(bgsave.started.elapsed());
+        self.last_bgsave_ok = result.is_ok();
+        match result {
+            Ok(()) => {
+                self.lastsave = SystemTime::now();
+                self.saves += 1;
+                self.dirty = self.dirty.saturating_sub(bgsave.dirty_at_start);
+            }
+            Err(err) => eprintln!("background save failed: {err}"),
+        }
+    }
+
+    pub fn in_progress(&mut self) -> bool {
+        self.reap();
+        self.bgsave.is_some()
+    }
+
+    /// Notes that the append only file logged the writes up to `offset` in
+    /// the replication stream.
+    pub fn aof_written(&mut self, offset: u64) {
+        if let Some(aof) = &mut self.aof {
+            aof.written(offset);
+        }
+    }
+
+    /// How far into the replication stream the append only file is on disk,
+    /// `None` when it is disabled.
+    pub fn aof_fsynced(&self) -> Option<u64> {
+        self.aof.as_ref().map(Aof::fsynced)
+    }
+
+    pub fn aof_enabled(&self) -> bool {
+        self.aof.is_some()
+    }
+
+    pub fn aof_rewriting(&mut self) -> bool {
+        self.aof.as_mut().is_some_and(|aof| aof.rewriting())
+    }
+
+    /// Starts rewriting the append only file from `databases`, or schedules
+    /// it when a background save is going on. Returns whether it was scheduled.
+    pub fn bgrewriteaof(&mut self, databases: &Databases) -> io::Result<bool> {
+        let scheduled = self.in_progress();
+        let Some(aof) = &mut self.aof else {
+            return Err(io::Error::other("append only file is disabled"));
+        };
+        if scheduled {
+            aof.schedule_rewrite();
+        } else {
+            aof.rewrite(databases)?;
+        }
+        Ok(scheduled)
+    }
+
+    /// Saves in the foreground, blocking until the file is written.
+    pub fn save(&mut self, databases: &Databases, path: &Path) -> io::Result<()> {
+        rdb::save(databases, path, self.compression, &AtomicUsize::new(0))?;
+        self.lastsave = SystemTime::now();
+        self.saves += 1;
+        self.dirty = 0;
+        Ok(())
+    }
+
+    /// Starts writing a snapshot of `databases` on a background thread. Taking
+    /// the snapshot only clones shard pointers, so the caller is not held up.
+    pub fn bgsave(&mut self, databases: &Databases, path: PathBuf) {
+        let snapshot = databases.clone();
+        let keys_processed = Arc::new(AtomicUsize::new(0));
+        let progress = Arc::clone(&keys_processed);
+        let compress = self.compression;
+
+        self.scheduled = false;
+        self.last_bgsave_try = Some(Instant::now());
+        self.bgsave = Some(BgSave {
+            started: Instant::now(),
+            dirty_at_start: self.dirty,
+            keys_total: snapshot.iter().map(|(_, store)| store.len()).sum(),
+            keys_processed,
+            handle: thread::spawn(move || rdb::save(&snapshot, &path, compress, &progress)),
+        });
+    }
+
+    /// Asks for a background save once the one in progress is done.
+    pub fn schedule(&mut self) {
+        self.scheduled = true;
+    }
+
+    /// Run periodically to notice finished saves and rewrites, and to start
+    /// scheduled ones and those the `save` rules or file growth call for.
+    /// Like forked children in Redis, only one runs at a time.
+    pub fn cron(&mut self, databases: &Databases, path: &Path) {
+        if self.in_progress() || self.aof_rewriting() {
+            return;
+        }
+        if self.scheduled || self.rule_met() {
+            self.bgsave(databases, path.to_path_buf());
+        } else if let Some(aof) = &mut self.aof {
+            aof.cron(databases);
+        }
+    }
+
+    /// The unix time of the last successful save.
+    pub fn lastsave(&mut self) -> u64 {
+        self.reap();
+        self.lastsave
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    pub fn info(&mut self) -> String {
+        let lastsave = self.lastsave();
+        let (in_progress, current_secs, processed, total) = match &self.bgsave {
+            Some(bgsave) => (
+                1,
+                bgsave.started.elapsed().as_secs() as i64,
+                bgsave.keys_processed.load(Ordering::Relaxed),
+                bgsave.keys_total,
+            ),
+            None => (0, -1, 0, 0),
+        };
+
+        let mut info = String::from("# Persistence\r\n");
+        let _ = write!(info, "loading:{}\r\n", self.loading() as u8);
+        if let Some(loading) = &self.loading {
+            let loaded = loading.loaded_bytes.load(Ordering::Relaxed);
+            let elapsed = loading.started.elapsed().unwrap_or_default().as_secs_f64();
+            let (perc, eta) = match loading.total_bytes {
+                0 => (0.0, -1),
+                total => (
+                    loaded as f64 * 100.0 / total as f64,
+                    if loaded == 0 {
+                        -1
+                    } else {
+                        (elapsed * total.saturating_sub(loaded) as f64 / loaded as f64) as i64
+                    },
+                ),
+            };
+            let _ = write!(
+                info,
+                "loading_start_time:{}\r\n\
+                 loading_total_bytes:{}\r\n\
+                 loading_loaded_bytes:{loaded}\r\n\
+                 loading_loaded_perc:{perc:.2}\r\n\
+                 loading_eta_seconds:{eta}\r\n",
+                loading
+                    .started
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                loading.total_bytes,
+            );
+        }
+        let _ = write!(
+            info,
+            "rdb_changes_since_last_save:{}\r\n\
+             rdb_bgsave_in_progress:{in_progress}\r\n\
+             rdb_last_save_time:{lastsave}\r\n\
+             rdb_last_bgsave_status:{}\r\n\
+             rdb_last_bgsave_time_sec:{}\r\n\
+             rdb_current_bgsave_time_sec:{current_secs}\r\n\
+             rdb_saves:{}\r\n\
+             current_save_keys_processed:{processed}\r\n\
+             current_save_keys_total:{total}\r\n\
+             aof_enabled:{}\r\n\
+             aof_last_write_status:{}\r\n",
+            self.dirty,
+            if self.last_bgsave_ok { "ok" } else { "err" },
+            self.last_bgsave_duration
+                .map_or(-1, |duration| duration.as_secs() as i64),
+            self.saves,
+            self.aof.is_some() as u8,
+            if self.aof_last_write_ok { "ok" } else { "err" },
+        );
+        if let Some(aof) = &mut self.aof {
+            info.push_str(&aof.info());
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, process};
+
+    use super::*;
+    use crate::parser::rdb::parse_rdb;
+    use crate::store::{DurableValue, Object};
+
+    #[test]
+    fn bgsave_writes_a_point_in_time_snapshot() {
+        let dir = std::env::temp_dir().join(format!("bgsave-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.rdb");
+
+        let mut databases = Databases::new(1);
+        for key in ["a", "b"] {
+            databases.db(0).insert(
+                key.into(),
+                DurableValue::new(Object::String(b"old".to_vec())),
+            );
+        }
+        let mut persistence = Persistence::default();
+        persistence.bgsave(&databases, path.clone());
+
+        // writes after the snapshot was taken must not show up in the file
+        *databases.db(0).string_mut("a").unwrap() = b"new".to_vec();
+        databases.db(0).remove("b");
+        databases
+            .db(0)
+            .insert("c".into(), DurableValue::new(Object::String(vec![])));
+
+        while persistence.in_progress() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(persistence.info().contains("rdb_last_bgsave_status:ok"));
+        assert!(persistence.info().contains("rdb_saves:1"));
+
+        let bytes = fs::read(&path).unwrap();
+        let (_, rdb) = parse_rdb(&bytes).unwrap();
+        let mut keys = rdb.keys().map(ToString::to_string).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(rdb.get("a").next().unwrap(), "old");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_rules() {
+        assert_eq!(
+            parse_save_rules("3600 1  300 100"),
+            Some(vec![
+                SaveRule {
+                    seconds: 3600,
+                    changes: 1
+                },
+                SaveRule {
+                    seconds: 300,
+                    changes: 100
+                },
+            ])
+        );
+        assert_eq!(parse_save_rules(""), Some(vec![]));
+        assert_eq!(parse_save_rules("60"), None);
+        assert_eq!(parse_save_rules("60 x"), None);
+    }
+
+    #[test]
+    fn rules_need_both_time_and_changes() {
+        let mut persistence = Persistence::default();
+        persistence.set_rules(vec![SaveRule {
+            seconds: 0,
+            changes: 2,
+        }]);
+        persistence.feed(0, b"");
+        assert!(!persistence.rule_met());
+        persistence.feed(0, b"");
+        assert!(persistence.rule_met());
+
+        persistence.set_rules(vec![SaveRule {
+            seconds: 60,
+            changes: 1,
+        }]);
+        assert!(!persistence.rule_met());
+        persistence.lastsave -= Duration::from_secs(60);
+        assert!(persistence.rule_met());
+    }
+}
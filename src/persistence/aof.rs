@@ -0,0 +1,775 @@
+//! The append only file: every write command, logged in the RESP form it was
+//! sent in. It is kept as a multi part set in its own directory: a base file
+//! holding an RDB snapshot, the incremental files with the commands written
+//! since, and a manifest listing them in the order they are loaded.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::rdb;
+use crate::client::Client;
+use crate::commands;
+use crate::message::RespMessage;
+use crate::parser::resp::{parser, BulkString, Value};
+use crate::server::Server;
+use crate::store::Databases;
+
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When logged commands are flushed to disk, the `appendfsync` setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    /// After every write command, before it is acknowledged.
+    Always,
+    /// Once a second, from a background thread.
+    EverySec,
+    /// Whenever the operating system gets to it.
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where the append only file lives and how it is maintained.
+#[derive(Debug, Clone)]
+pub struct AofConfig {
+    /// The `appenddirname` directory, already joined to `dir`.
+    pub dir: PathBuf,
+    /// The `appendfilename` every file in the set is named after.
+    pub name: String,
+    pub fsync: Fsync,
+    /// Rewrite once the file grew by this percentage since the last rewrite, 0 to never.
+    pub auto_rewrite_percentage: u64,
+    /// Never rewrite automatically below this size, in bytes.
+    pub auto_rewrite_min_size: u64,
+    /// Whether strings in the base file are LZF compressed, like `rdbcompression`.
+    pub rdb_compression: bool,
+}
+
+impl AofConfig {
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileKind {
+    Base,
+    Incr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    kind: FileKind,
+}
+
+/// The files making up the append only file, base first.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Manifest {
+    files: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// Parses lines of the form `file <name> seq <seq> type <b|i|h>`.
+    /// History files, left over from past rewrites, are not loaded.
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut files = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("invalid manifest line: {line}");
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in fields.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse().ok(),
+                    "type" => kind = Some(pair[1]),
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(invalid());
+            };
+            let kind = match kind {
+                "b" => FileKind::Base,
+                "i" => FileKind::Incr,
+                "h" => continue,
+                _ => return Err(invalid()),
+            };
+            files.push(AofFile { name, seq, kind });
+        }
+
+        files.sort_by_key(|file| (file.kind == FileKind::Incr, file.seq));
+        if files
+            .iter()
+            .filter(|file| file.kind == FileKind::Base)
+            .count()
+            > 1
+        {
+            return Err("the manifest lists more than one base file".into());
+        }
+        Ok(Self { files })
+    }
+
+    fn to_text(&self) -> String {
+        self.files
+            .iter()
+            .map(|file| {
+                let kind = match file.kind {
+                    FileKind::Base => 'b',
+                    FileKind::Incr => 'i',
+                };
+                format!("file {} seq {} type {kind}\n", file.name, file.seq)
+            })
+            .collect()
+    }
+
+    fn last_seq(&self, kind: FileKind) -> u64 {
+        self.files
+            .iter()
+            .filter(|file| file.kind == kind)
+            .map(|file| file.seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Writes the manifest to a temporary file and renames it into place.
+    fn persist(&self, config: &AofConfig) -> io::Result<()> {
+        let temp = config.dir.join(format!("temp-{}.manifest", config.name));
+        let mut file = File::create(&temp)?;
+        file.write_all(self.to_text().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(temp, config.manifest_path())
+    }
+}
+
+/// How far into the replication stream the logged writes go, and how much
+/// of that is known to be on disk, for `WAITAOF`.
+#[derive(Debug, Default)]
+struct Offsets {
+    written: AtomicU64,
+    fsynced: AtomicU64,
+}
+
+/// The incremental file commands are currently appended to.
+#[derive(Debug)]
+struct Incr {
+    file: Arc<File>,
+    /// The database the logged commands currently apply to.
+    selected_db: Option<usize>,
+}
+
+impl Incr {
+    fn open(path: &Path, fsync: Fsync, offsets: &Arc<Offsets>) -> io::Result<Self> {
+        let file = Arc::new(OpenOptions::new().create(true).append(true).open(path)?);
+
+        if fsync == Fsync::EverySec {
+            let file = Arc::downgrade(&file);
+            let offsets = Arc::clone(offsets);
+            thread::spawn(move || loop {
+                thread::sleep(FSYNC_INTERVAL);
+                let Some(file) = file.upgrade() else {
+                    return;
+                };
+                let written = offsets.written.load(Ordering::Relaxed);
+                match file.sync_data() {
+                    Ok(()) => {
+                        offsets.fsynced.fetch_max(written, Ordering::Relaxed);
+                    }
+                    Err(err) => eprintln!("could not fsync the append only file: {err}"),
+                }
+            });
+        }
+
+        Ok(Self {
+            file,
+            selected_db: None,
+        })
+    }
+}
+
+/// A new base file being written by a background thread.
+#[derive(Debug)]
+struct Rewrite {
+    started: Instant,
+    base: AofFile,
+    handle: JoinHandle<io::Result<u64>>,
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    config: AofConfig,
+    manifest: Manifest,
+    incr: Incr,
+    offsets: Arc<Offsets>,
+    /// The size of every file in the manifest.
+    current_size: u64,
+    /// What `current_size` was right after the last rewrite.
+    base_size: u64,
+    rewrite: Option<Rewrite>,
+    rewrite_scheduled: bool,
+    last_rewrite_ok: bool,
+    last_rewrite_duration: Option<Duration>,
+}
+
+impl Aof {
+    pub fn set_rdb_compression(&mut self, compress: bool) {
+        self.config.rdb_compression = compress;
+    }
+
+    /// Opens the append only file described by `config`, creating it with a
+    /// base holding `databases` when there is no manifest yet.
+    pub fn open(config: AofConfig, databases: &Databases) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let manifest_path = config.manifest_path();
+
+        let mut manifest = if manifest_path.exists() {
+            Manifest::parse(&fs::read_to_string(&manifest_path)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        } else {
+            let base = AofFile {
+                name: format!("{}.1.base.rdb", config.name),
+                seq: 1,
+                kind: FileKind::Base,
+            };
+            rdb::save(
+                databases,
+                &config.dir.join(&base.name),
+                config.rdb_compression,
+                &AtomicUsize::new(0),
+            )?;
+            Manifest { files: vec![base] }
+        };
+
+        let incr = match manifest.files.last() {
+            Some(file) if file.kind == FileKind::Incr => file.clone(),
+            _ => {
+                let seq = manifest.last_seq(FileKind::Incr) + 1;
+                let incr = AofFile {
+                    name: format!("{}.{seq}.incr.aof", config.name),
+                    seq,
+                    kind: FileKind::Incr,
+                };
+                manifest.files.push(incr.clone());
+                incr
+            }
+        };
+        let offsets = Arc::default();
+        let incr = Incr::open(&config.dir.join(&incr.name), config.fsync, &offsets)?;
+        manifest.persist(&config)?;
+
+        let mut current_size = 0;
+        for file in &manifest.files {
+            current_size += fs::metadata(config.dir.join(&file.name))?.len();
+        }
+
+        Ok(Self {
+            config,
+            manifest,
+            incr,
+            offsets,
+            current_size,
+            base_size: current_size,
+            rewrite: None,
+            rewrite_scheduled: false,
+            last_rewrite_ok: true,
+            last_rewrite_duration: None,
+        })
+    }
+
+    /// Logs `command`, which ran against database `db`.
+    pub fn feed(&mut self, db: usize, command: &[u8]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(command.len());
+        if self.incr.selected_db != Some(db) {
+            let select: Value = vec![
+                BulkString::from("SELECT").into(),
+                BulkString::from(db.to_string()).into(),
+            ]
+            .into();
+            out.extend(select.to_bytes());
+        }
+        out.extend_from_slice(command);
+
+        (&*self.incr.file).write_all(&out)?;
+        self.incr.selected_db = Some(db);
+        self.current_size += out.len() as u64;
+        if self.config.fsync == Fsync::Always {
+            self.incr.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Notes that the logged writes go up to `offset` in the replication
+    /// stream. Without `everysec` they are as good as on disk already: after
+    /// every write they were fsynced, or it is left to the OS.
+    pub fn written(&mut self, offset: u64) {
+        self.offsets.written.store(offset, Ordering::Relaxed);
+        if self.config.fsync != Fsync::EverySec {
+            self.offsets.fsynced.store(offset, Ordering::Relaxed);
+        }
+    }
+
+    /// How far into the replication stream the logged writes are on disk.
+    pub fn fsynced(&self) -> u64 {
+        self.offsets.fsynced.load(Ordering::Relaxed)
+    }
+
+    /// Collects the result of a rewrite that has finished, switching the
+    /// manifest over to the new base and deleting the files it replaced.
+    fn reap(&mut self) {
+        if !self
+            .rewrite
+            .as_ref()
+            .is_some_and(|rewrite| rewrite.handle.is_finished())
+        {
+            return;
+        }
+        let Some(rewrite) = self.rewrite.take() else {
+            return;
+        };
+
+        let result = rewrite
+            .handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("rewrite panicked")))
+            .and_then(|base_size| {
+                // only the incremental file opened when the rewrite started,
+                // holding the writes the new base does not, is still needed
+                let incr = self.manifest.files.last().cloned();
+                let manifest = Manifest {
+                    files: std::iter::once(rewrite.base.clone()).chain(incr).collect(),
+                };
+                manifest.persist(&self.config)?;
+                Ok((std::mem::replace(&mut self.manifest, manifest), base_size))
+            });
+
+        self.last_rewrite_duration = Some(rewrite.started.elapsed());
+        self.last_rewrite_ok = result.is_ok();
+        match result {
+            Ok((old, base_size)) => {
+                for file in old
+                    .files
+                    .iter()
+                    .filter(|file| !self.manifest.files.contains(file))
+                {
+                    let _ = fs::remove_file(self.config.dir.join(&file.name));
+                }
+                let incr_size = self
+                    .manifest
+                    .files
+                    .last()
+                    .and_then(|file| fs::metadata(self.config.dir.join(&file.name)).ok())
+                    .map_or(0, |metadata| metadata.len());
+                self.current_size = base_size + incr_size;
+                self.base_size = self.current_size;
+            }
+            Err(err) => {
+                let _ = fs::remove_file(self.config.dir.join(&rewrite.base.name));
+                eprintln!("append only file rewrite failed: {err}");
+            }
+        }
+    }
+
+    pub fn rewriting(&mut self) -> bool {
+        self.reap();
+        self.rewrite.is_some()
+    }
+
+    /// Asks for a rewrite once the background save in progress is done.
+    pub fn schedule_rewrite(&mut self) {
+        self.rewrite_scheduled = true;
+    }
+
+    /// Whether a rewrite was scheduled or the file grew enough since the last one.
+    fn rewrite_due(&self) -> bool {
+        let config = &self.config;
+        self.rewrite_scheduled
+            || (config.auto_rewrite_percentage > 0
+                && self.current_size > config.auto_rewrite_min_size
+                && (self.current_size * 100 / self.base_size.max(1)).saturating_sub(100)
+                    >= config.auto_rewrite_percentage)
+    }
+
+    /// Starts compacting the log into a new base file written from a snapshot
+    /// of `databases`. Commands from now on go to a new incremental file,
+    /// which is listed in the manifest straight away so none are lost if the
+    /// server stops before the rewrite is done.
+    pub fn rewrite(&mut self, databases: &Databases) -> io::Result<()> {
+        let seq = self.manifest.last_seq(FileKind::Incr) + 1;
+        let incr = AofFile {
+            name: format!("{}.{seq}.incr.aof", self.config.name),
+            seq,
+            kind: FileKind::Incr,
+        };
+        self.incr = Incr::open(
+            &self.config.dir.join(&incr.name),
+            self.config.fsync,
+            &self.offsets,
+        )?;
+        self.manifest.files.push(incr);
+        self.manifest.persist(&self.config)?;
+
+        let seq = self.manifest.last_seq(FileKind::Base) + 1;
+        let base = AofFile {
+            name: format!("{}.{seq}.base.rdb", self.config.name),
+            seq,
+            kind: FileKind::Base,
+        };
+        let path = self.config.dir.join(&base.name);
+        let snapshot = databases.clone();
+        let compress = self.config.rdb_compression;
+
+        self.rewrite_scheduled = false;
+        self.rewrite = Some(Rewrite {
+            started: Instant::now(),
+            base,
+            handle: thread::spawn(move || {
+                rdb::save(&snapshot, &path, compress, &AtomicUsize::new(0))?;
+                Ok(fs::metadata(&path)?.len())
+            }),
+        });
+        Ok(())
+    }
+
+    /// Run periodically, when no background save is going on, to start
+    /// scheduled and automatic rewrites.
+    pub fn cron(&mut self, databases: &Databases) {
+        if !self.rewriting() && self.rewrite_due() {
+            if let Err(err) = self.rewrite(databases) {
+                self.last_rewrite_ok = false;
+                eprintln!("could not start the append only file rewrite: {err}");
+            }
+        }
+    }
+
+    pub fn info(&mut self) -> String {
+        let rewriting = self.rewriting();
+        let current_secs = self
+            .rewrite
+            .as_ref()
+            .map_or(-1, |rewrite| rewrite.started.elapsed().as_secs() as i64);
+        format!(
+            "aof_rewrite_in_progress:{}\r\n\
+             aof_rewrite_scheduled:{}\r\n\
+             aof_last_rewrite_time_sec:{}\r\n\
+             aof_current_rewrite_time_sec:{current_secs}\r\n\
+             aof_last_bgrewrite_status:{}\r\n\
+             aof_current_size:{}\r\n\
+             aof_base_size:{}\r\n",
+            rewriting as u8,
+            self.rewrite_scheduled as u8,
+            self.last_rewrite_duration
+                .map_or(-1, |duration| duration.as_secs() as i64),
+            if self.last_rewrite_ok { "ok" } else { "err" },
+            self.current_size,
+            self.base_size,
+        )
+    }
+}
+
+/// Runs the commands logged in `bytes` against `server`, returning how many
+/// bytes held complete commands. Anything after that is a truncated command.
+/// A file starting with an RDB snapshot has it loaded first.
+pub fn replay(server: &mut Server, bytes: &[u8]) -> Result<usize, String> {
+    let mut rest = bytes;
+    if bytes.starts_with(b"REDIS") {
+        let preamble = rdb::load(&mut server.databases, bytes).map_err(|err| err.to_string())?;
+        rest = &bytes[preamble..];
+    }
+
+    let mut client = Client::default();
+    let mut multi_at = None;
+    while !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        let (next, value) = match parser(rest) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Incomplete(_)) => break,
+            Err(_) => return Err(format!("bad file format at offset {offset}")),
+        };
+        let message = RespMessage::try_from(value)
+            .map_err(|err| format!("invalid command at offset {offset}: {err}"))?;
+        if matches!(message, RespMessage::Multi) && client.transaction.is_none() {
+            multi_at = Some(offset);
+        }
+        commands::execute(
+            server,
+            &mut client,
+            message,
+            &rest[..rest.len() - next.len()],
+        );
+        rest = next;
+    }
+
+    // what was replayed is already on disk
+    server.persistence.dirty = 0;
+    // a transaction cut off before its EXEC was never applied, so it is as
+    // truncated as a cut off command
+    match multi_at.filter(|_| client.transaction.is_some()) {
+        Some(offset) => Ok(offset),
+        None => Ok(bytes.len() - rest.len()),
+    }
+}
+
+/// Loads the single file at `path` into `server`. A truncated last command
+/// is cut off the file when `truncated_ok`, and is an error otherwise.
+pub fn load_file(server: &mut Server, path: &Path, truncated_ok: bool) -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let valid = replay(server, &bytes)?;
+    if valid == bytes.len() {
+        return Ok(());
+    }
+    if !truncated_ok {
+        return Err(format!(
+            "{} is truncated at offset {valid}, \
+             start with --aof-load-truncated yes to load what precedes it",
+            path.display()
+        ));
+    }
+
+    eprintln!(
+        "{} is truncated, dropping the last {} bytes",
+        path.display(),
+        bytes.len() - valid
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(valid as u64))
+        .map_err(|err| err.to_string())
+}
+
+/// Loads every file the manifest lists, in order. Only the last one may end
+/// in a truncated command.
+pub fn load(server: &mut Server, config: &AofConfig, truncated_ok: bool) -> Result<(), String> {
+    let text = fs::read_to_string(config.manifest_path()).map_err(|err| err.to_string())?;
+    let manifest = Manifest::parse(&text)?;
+
+    let last = manifest.files.len().saturating_sub(1);
+    for (index, file) in manifest.files.iter().enumerate() {
+        load_file(
+            server,
+            &config.dir.join(&file.name),
+            truncated_ok && index == last,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::process;
+
+    use super::*;
+    use crate::store::{DurableValue, Object};
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let value: Value = args
+            .iter()
+            .map(|arg| BulkString::from(*arg).into())
+            .collect::<Vec<Value>>()
+            .into();
+        value.to_bytes()
+    }
+
+    fn config(test: &str) -> AofConfig {
+        let dir = std::env::temp_dir().join(format!("aof-{test}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        AofConfig {
+            dir,
+            name: "appendonly.aof".into(),
+            fsync: Fsync::Always,
+            auto_rewrite_percentage: 100,
+            auto_rewrite_min_size: 64 * 1024 * 1024,
+            rdb_compression: true,
+        }
+    }
+
+    fn string(value: &str) -> DurableValue {
+        DurableValue::new(Object::String(value.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let text = "file appendonly.aof.2.incr.aof seq 2 type i\n\
+                    file appendonly.aof.1.base.rdb seq 1 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type h\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n";
+        let manifest = Manifest::parse(text).unwrap();
+
+        assert_eq!(
+            manifest.to_text(),
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n"
+        );
+        assert!(Manifest::parse("file a seq x type b").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b").is_err());
+    }
+
+    #[test]
+    fn logs_select_only_when_the_database_changes() {
+        let config = config("feed");
+        let mut aof = Aof::open(config.clone(), &Databases::new(4)).unwrap();
+        aof.feed(0, &command(&["SET", "a", "1"])).unwrap();
+        aof.feed(0, &command(&["SET", "b", "2"])).unwrap();
+        aof.feed(3, &command(&["DEL", "a"])).unwrap();
+
+        let expected = [
+            command(&["SELECT", "0"]),
+            command(&["SET", "a", "1"]),
+            command(&["SET", "b", "2"]),
+            command(&["SELECT", "3"]),
+            command(&["DEL", "a"]),
+        ]
+        .concat();
+        let incr = config.dir.join("appendonly.aof.1.incr.aof");
+        assert_eq!(fs::read(incr).unwrap(), expected);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn tracks_how_far_writes_are_fsynced() {
+        let mut config = config("fsynced");
+        let mut aof = Aof::open(config.clone(), &Databases::new(1)).unwrap();
+        aof.feed(0, &command(&["SET", "a", "1"])).unwrap();
+        aof.written(42);
+        assert_eq!(aof.fsynced(), 42);
+        fs::remove_dir_all(&config.dir).unwrap();
+
+        // once a second a thread catches up with what was written
+        config.fsync = Fsync::EverySec;
+        let mut aof = Aof::open(config.clone(), &Databases::new(1)).unwrap();
+        aof.feed(0, &command(&["SET", "a", "1"])).unwrap();
+        aof.written(42);
+        assert_eq!(aof.fsynced(), 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while aof.fsynced() < 42 {
+            assert!(Instant::now() < deadline, "never fsynced");
+            thread::sleep(Duration::from_millis(50));
+        }
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn replays_after_the_preamble_and_stops_at_a_truncated_command() {
+        let mut databases = Databases::new(2);
+        databases.db(0).insert("base".into(), string("1"));
+        let mut preamble = Vec::new();
+        rdb::write(&databases, &mut preamble, true, &AtomicUsize::new(0)).unwrap();
+
+        let mut bytes = [
+            preamble,
+            command(&["SELECT", "1"]),
+            command(&["SET", "k", "v"]),
+        ]
+        .concat();
+        let complete = bytes.len();
+        bytes.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nx");
+
+        let mut loaded = Server::new(Databases::new(2));
+        assert_eq!(replay(&mut loaded, &bytes), Ok(complete));
+        assert_eq!(
+            loaded.databases.db(0).get_string("base"),
+            Ok(Some(&b"1".to_vec()))
+        );
+        assert_eq!(
+            loaded.databases.db(1).get_string("k"),
+            Ok(Some(&b"v".to_vec()))
+        );
+        assert!(replay(&mut loaded, b"*1\r\n$4\r\nNOPE\r\n").is_err());
+    }
+
+    #[test]
+    fn transactions_without_their_exec_are_truncated() {
+        let transaction = [
+            command(&["SET", "a", "1"]),
+            command(&["MULTI"]),
+            command(&["SET", "b", "2"]),
+            command(&["SET", "c", "3"]),
+        ]
+        .concat();
+        let multi_at = command(&["SET", "a", "1"]).len();
+
+        let mut loaded = Server::new(Databases::new(1));
+        assert_eq!(replay(&mut loaded, &transaction), Ok(multi_at));
+        assert!(loaded.databases.db(0).contains("a"));
+        assert!(!loaded.databases.db(0).contains("b"));
+
+        let complete = [transaction, command(&["EXEC"])].concat();
+        let mut loaded = Server::new(Databases::new(1));
+        assert_eq!(replay(&mut loaded, &complete), Ok(complete.len()));
+        assert!(loaded.databases.db(0).contains("c"));
+    }
+
+    #[test]
+    fn rewrite_compacts_into_a_new_base() {
+        let config = config("rewrite");
+        let mut databases = Databases::new(1);
+        databases.db(0).insert("before".into(), string("1"));
+
+        let mut aof = Aof::open(config.clone(), &databases).unwrap();
+        aof.feed(0, &command(&["SET", "a", "1"])).unwrap();
+        databases.db(0).insert("a".into(), string("1"));
+
+        aof.rewrite(&databases).unwrap();
+        aof.feed(0, &command(&["SET", "after", "2"])).unwrap();
+        while aof.rewriting() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(aof.last_rewrite_ok);
+
+        let manifest = fs::read_to_string(config.manifest_path()).unwrap();
+        assert_eq!(
+            manifest,
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        let mut files = fs::read_dir(&config.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "appendonly.aof.2.base.rdb",
+                "appendonly.aof.2.incr.aof",
+                "appendonly.aof.manifest"
+            ]
+        );
+
+        let mut loaded = Server::new(Databases::new(1));
+        load(&mut loaded, &config, false).unwrap();
+        let mut keys = loaded.databases.db(0).keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "after", "before"]);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
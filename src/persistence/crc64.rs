@@ -0,0 +1,43 @@
+//! The Jones variant of CRC-64 that Redis checksums RDB files with.
+
+/// The Jones polynomial, bit reflected.
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Extends `crc` with `bytes`. A checksum starts from zero.
+pub fn update(crc: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(update(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(update(update(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(update(0, b""), 0);
+    }
+}
@@ -0,0 +1,90 @@
+//! Builds listpacks, the compact encoding RDB files store stream nodes in.
+//!
+//! A listpack is its total size and element count, then the elements, each
+//! an encoding byte, its data and the size of both, then a 0xFF byte.
+
+const HEADER_LEN: usize = 6;
+const EOF: u8 = 0xFF;
+
+#[derive(Debug, Default)]
+pub struct Listpack {
+    elements: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn push_int(&mut self, n: i64) {
+        let mut element = Vec::with_capacity(9);
+        match n {
+            0..=127 => element.push(n as u8),
+            -4096..=4095 => {
+                // a 13 bit signed integer
+                let n = n as u16 & 0x1FFF;
+                element.extend_from_slice(&[0xC0 | (n >> 8) as u8, n as u8]);
+            }
+            -32768..=32767 => {
+                element.push(0xF1);
+                element.extend_from_slice(&(n as i16).to_le_bytes());
+            }
+            -8388608..=8388607 => {
+                element.push(0xF2);
+                element.extend_from_slice(&(n as i32).to_le_bytes()[..3]);
+            }
+            -2147483648..=2147483647 => {
+                element.push(0xF3);
+                element.extend_from_slice(&(n as i32).to_le_bytes());
+            }
+            _ => {
+                element.push(0xF4);
+                element.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+        self.push_element(element);
+    }
+
+    pub fn push_str(&mut self, bytes: &[u8]) {
+        let mut element = Vec::with_capacity(bytes.len() + 5);
+        match bytes.len() {
+            len @ 0..=63 => element.push(0x80 | len as u8),
+            len @ 64..=4095 => element.extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]),
+            len => {
+                element.push(0xF0);
+                element.extend_from_slice(&(len as u32).to_le_bytes());
+            }
+        }
+        element.extend_from_slice(bytes);
+        self.push_element(element);
+    }
+
+    /// Appends `element` followed by its size, written so it can be read
+    /// backwards: 7 bits a byte, the most significant first, every byte but
+    /// the first with its top bit set.
+    fn push_element(&mut self, element: Vec<u8>) {
+        let size = element.len();
+        self.elements.extend_from_slice(&element);
+        let bytes = match size {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        for i in (0..bytes).rev() {
+            let group = (size >> (7 * i)) as u8 & 0x7F;
+            let continued = if i == bytes - 1 { 0 } else { 0x80 };
+            self.elements.push(group | continued);
+        }
+        self.len += 1;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let total = HEADER_LEN + self.elements.len() + 1;
+        let mut bytes = Vec::with_capacity(total);
+        bytes.extend_from_slice(&(total as u32).to_le_bytes());
+        // counts that do not fit are left for readers to work out
+        bytes.extend_from_slice(&(self.len.min(u16::MAX as usize) as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.elements);
+        bytes.push(EOF);
+        bytes
+    }
+}
@@ -0,0 +1,150 @@
+//! The LZF format RDB files compress long strings with.
+//!
+//! A compressed stream is a sequence of chunks, each starting with a control
+//! byte. Below 32 it is followed by that many plus one literal bytes. Otherwise
+//! its top 3 bits are a length and the rest the high bits of an offset back
+//! into the output, with a length of 7 meaning another length byte follows
+//! and the low offset byte always following.
+
+use std::collections::HashMap;
+
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+
+/// Decompresses `input` into exactly `len` bytes, `None` if it is corrupt.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // the length comes from the file, so it cannot size the allocation alone
+    let mut out = Vec::with_capacity(len.min(input.len().max(1024)));
+    let mut ip = 0;
+
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+
+        if ctrl < MAX_LITERAL {
+            let literal = input.get(ip..ip + ctrl + 1)?;
+            if out.len() + literal.len() > len {
+                return None;
+            }
+            out.extend_from_slice(literal);
+            ip += ctrl + 1;
+            continue;
+        }
+
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(ip)? as usize;
+            ip += 1;
+        }
+        let offset = ((ctrl & 0x1f) << 8) + *input.get(ip)? as usize + 1;
+        ip += 1;
+
+        let start = out.len().checked_sub(offset)?;
+        if out.len() + run + 2 > len {
+            return None;
+        }
+        // the reference may overlap the bytes it produces, so copy one at a time
+        for i in start..start + run + 2 {
+            out.push(out[i]);
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERAL) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Compresses `input`, finding earlier occurrences of each three byte
+/// sequence through a table of where it was last seen.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut seen = HashMap::new();
+    let mut literal_start = 0;
+    let mut ip = 0;
+
+    while ip + 2 < input.len() {
+        let key = [input[ip], input[ip + 1], input[ip + 2]];
+        let Some(reference) = seen.insert(key, ip) else {
+            ip += 1;
+            continue;
+        };
+        let offset = ip - reference - 1;
+        if offset >= MAX_OFFSET {
+            ip += 1;
+            continue;
+        }
+
+        let max = MAX_REF.min(input.len() - ip);
+        let mut run = 3;
+        while run < max && input[reference + run] == input[ip + run] {
+            run += 1;
+        }
+
+        flush_literals(&mut out, &input[literal_start..ip]);
+        let code = run - 2;
+        if code < 7 {
+            out.push(((code << 5) | (offset >> 8)) as u8);
+        } else {
+            out.push(((7 << 5) | (offset >> 8)) as u8);
+            out.push((code - 7) as u8);
+        }
+        out.push(offset as u8);
+
+        ip += run;
+        literal_start = ip;
+    }
+
+    flush_literals(&mut out, &input[literal_start..]);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decompresses_overlapping_references() {
+        // a literal "a", then 22 bytes copied from one byte back
+        let compressed = [0x00, 0x61, 0xe0, 0x0d, 0x00];
+        assert_eq!(decompress(&compressed, 23), Some(vec![b'a'; 23]));
+        assert_eq!(decompress(&compressed, 24), None);
+        assert_eq!(decompress(&[0x05, 0x61], 6), None);
+        assert_eq!(decompress(&[0x20, 0x05], 3), None);
+    }
+
+    #[test]
+    fn stops_at_the_length_given() {
+        let compressed = compress(&[b'x'; 1000]);
+        assert_eq!(decompress(&compressed, 10), None);
+        assert_eq!(decompress(&compressed, usize::MAX), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs: [&[u8]; 4] = [
+            b"",
+            b"ab",
+            &[b'x'; 1000],
+            b"the quick brown fox jumps over the lazy dog, the quick brown fox",
+        ];
+        for input in inputs {
+            assert_eq!(
+                decompress(&compress(input), input.len()).as_deref(),
+                Some(input)
+            );
+        }
+
+        let long = (0..10_000u32)
+            .flat_map(|i| (i % 300).to_string().into_bytes())
+            .collect::<Vec<_>>();
+        let compressed = compress(&long);
+        assert!(compressed.len() < long.len() / 2);
+        assert_eq!(decompress(&compressed, long.len()), Some(long));
+    }
+}
@@ -0,0 +1,942 @@
+//! Serializes the keyspace in the RDB format, and loads what `parser::rdb` reads back.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nom::{combinator::map, number::streaming::le_u64};
+use thiserror::Error;
+
+use super::{crc64, listpack::Listpack, lzf};
+use crate::parser::rdb::{header, item, IResult, Item, KVPair, RdbError};
+use crate::store::{Databases, DurableValue, Expiration, Object, Stream, StreamId, Usage};
+
+const VERSION: u32 = 11;
+/// The newest format this server knows every opcode and type of.
+const MAX_LOAD_VERSION: u32 = 12;
+/// Checksums were added in version 5.
+const CHECKSUM_VERSION: u32 = 5;
+/// How much of a file loading reads at a time.
+const LOAD_CHUNK: usize = 64 * 1024;
+
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Entries per stream listpack, Redis's default `stream-node-max-entries`.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+const STREAM_ITEM_SAME_FIELDS: i64 = 2;
+
+const ENC_LZF: u8 = 0xC3;
+/// Shorter strings rarely compress well enough to be worth it.
+const LZF_MIN_LEN: usize = 20;
+
+/// Keeps a running checksum of everything written through it.
+struct Checksummed<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc = crc64::update(self.crc, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_len(out: &mut impl Write, len: impl TryInto<u64>) -> io::Result<()> {
+    let len = len
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "length too large"))?;
+    match len {
+        0..=0x3F => out.write_all(&[len as u8]),
+        0x40..=0x3FFF => out.write_all(&[0x40 | (len >> 8) as u8, len as u8]),
+        0x4000..=0xFFFF_FFFF => {
+            out.write_all(&[0x80])?;
+            out.write_all(&(len as u32).to_be_bytes())
+        }
+        _ => {
+            out.write_all(&[0x81])?;
+            out.write_all(&len.to_be_bytes())
+        }
+    }
+}
+
+fn write_string(out: &mut impl Write, bytes: &[u8], compress: bool) -> io::Result<()> {
+    if compress && bytes.len() > LZF_MIN_LEN {
+        let compressed = lzf::compress(bytes);
+        // not worth the lengths it costs to store unless it saves a few bytes
+        if compressed.len() + 4 < bytes.len() {
+            out.write_all(&[ENC_LZF])?;
+            write_len(out, compressed.len())?;
+            write_len(out, bytes.len())?;
+            return out.write_all(&compressed);
+        }
+    }
+    write_len(out, bytes.len())?;
+    out.write_all(bytes)
+}
+
+fn write_aux(out: &mut impl Write, key: &str, value: &str) -> io::Result<()> {
+    out.write_all(&[OPCODE_AUX])?;
+    write_string(out, key.as_bytes(), false)?;
+    write_string(out, value.as_bytes(), false)
+}
+
+fn write_stream_id(out: &mut impl Write, id: StreamId) -> io::Result<()> {
+    write_len(out, id.ms)?;
+    write_len(out, id.seq)
+}
+
+/// Stream IDs keying listpacks and pending entries are 16 big endian bytes.
+fn raw_stream_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// Writes the entries as listpacks of up to `STREAM_NODE_MAX_ENTRIES`, each
+/// keyed by its first entry's ID and relative to it, then the stream's
+/// metadata and consumer groups, as version 3 of the stream encoding.
+fn write_stream(out: &mut impl Write, stream: &Stream, compress: bool) -> io::Result<()> {
+    let entries = stream.entries.iter().collect::<Vec<_>>();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+    write_len(out, nodes.len())?;
+    for node in nodes {
+        let (master, master_fields) = node[0];
+        let mut listpack = Listpack::default();
+        listpack.push_int(node.len() as i64);
+        // no deleted entries
+        listpack.push_int(0);
+        listpack.push_int(master_fields.len() as i64);
+        for (field, _) in master_fields {
+            listpack.push_str(field);
+        }
+        listpack.push_int(0);
+
+        for (id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields
+                    .iter()
+                    .zip(master_fields)
+                    .all(|((field, _), (master, _))| field == master);
+            listpack.push_int(if same_fields {
+                STREAM_ITEM_SAME_FIELDS
+            } else {
+                0
+            });
+            listpack.push_int(id.ms.wrapping_sub(master.ms) as i64);
+            listpack.push_int(id.seq.wrapping_sub(master.seq) as i64);
+            if same_fields {
+                for (_, value) in fields.iter() {
+                    listpack.push_str(value);
+                }
+                listpack.push_int(fields.len() as i64 + 3);
+            } else {
+                listpack.push_int(fields.len() as i64);
+                for (field, value) in fields.iter() {
+                    listpack.push_str(field);
+                    listpack.push_str(value);
+                }
+                listpack.push_int(fields.len() as i64 * 2 + 4);
+            }
+        }
+
+        write_string(out, &raw_stream_id(*master), false)?;
+        write_string(out, &listpack.into_bytes(), compress)?;
+    }
+
+    write_len(out, stream.len())?;
+    write_stream_id(out, stream.last_id)?;
+    write_stream_id(out, stream.first_id)?;
+    write_stream_id(out, stream.max_deleted_id)?;
+    write_len(out, stream.entries_added)?;
+
+    write_len(out, stream.groups.len())?;
+    for group in &stream.groups {
+        write_string(out, &group.name, compress)?;
+        write_stream_id(out, group.last_id)?;
+        // an unknown count is stored as -1
+        write_len(out, group.entries_read.unwrap_or(u64::MAX))?;
+
+        write_len(out, group.pending.len())?;
+        for (id, entry) in &group.pending {
+            out.write_all(&raw_stream_id(*id))?;
+            out.write_all(&entry.delivery_time.to_le_bytes())?;
+            write_len(out, entry.delivery_count)?;
+        }
+
+        write_len(out, group.consumers.len())?;
+        for consumer in &group.consumers {
+            write_string(out, &consumer.name, compress)?;
+            out.write_all(&consumer.seen_time.to_le_bytes())?;
+            out.write_all(&consumer.active_time.to_le_bytes())?;
+            let owned = group
+                .pending
+                .iter()
+                .filter(|(_, entry)| entry.consumer == consumer.name)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            write_len(out, owned.len())?;
+            for id in owned {
+                out.write_all(&raw_stream_id(id))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_entry(
+    out: &mut impl Write,
+    key: &str,
+    entry: &DurableValue,
+    compress: bool,
+) -> io::Result<()> {
+    if let Some(deadline) = entry.expiration.deadline() {
+        let millis = deadline
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        out.write_all(&[OPCODE_EXPIRETIME_MS])?;
+        out.write_all(&millis.to_le_bytes())?;
+    }
+    match entry.usage {
+        Usage::Unknown => (),
+        Usage::Lru(used) => {
+            let idle = used.elapsed().unwrap_or_default().as_secs();
+            out.write_all(&[OPCODE_IDLE])?;
+            write_len(out, idle)?;
+        }
+        Usage::Lfu(counter) => out.write_all(&[OPCODE_FREQ, counter])?,
+    }
+
+    match &entry.val {
+        Object::String(bytes) => {
+            out.write_all(&[TYPE_STRING])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_string(out, bytes, compress)
+        }
+        Object::List(list) => {
+            out.write_all(&[TYPE_LIST])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_len(out, list.len())?;
+            for item in list {
+                write_string(out, item, compress)?;
+            }
+            Ok(())
+        }
+        Object::Set(set) => {
+            out.write_all(&[TYPE_SET])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_len(out, set.len())?;
+            for member in set {
+                write_string(out, member, compress)?;
+            }
+            Ok(())
+        }
+        Object::ZSet(set) => {
+            out.write_all(&[TYPE_ZSET_2])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_len(out, set.len())?;
+            for (member, score) in set.iter() {
+                write_string(out, member, compress)?;
+                out.write_all(&score.to_le_bytes())?;
+            }
+            Ok(())
+        }
+        Object::Stream(stream) => {
+            out.write_all(&[TYPE_STREAM_LISTPACKS_3])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_stream(out, stream, compress)
+        }
+        // module values are written back exactly as they were read
+        Object::Module(value) => {
+            out.write_all(&[TYPE_MODULE_2])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_len(out, value.id)?;
+            out.write_all(&value.payload)
+        }
+        Object::Hash(hash) => {
+            out.write_all(&[TYPE_HASH])?;
+            write_string(out, key.as_bytes(), compress)?;
+            write_len(out, hash.len())?;
+            for (field, value) in hash {
+                write_string(out, field, compress)?;
+                write_string(out, value, compress)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Writes every database to `out`, ending with the checksum of the file,
+/// LZF compressing long strings when `compress` is set. `progress` counts the
+/// keys written so far.
+pub fn write(
+    databases: &Databases,
+    out: impl Write,
+    compress: bool,
+    progress: &AtomicUsize,
+) -> io::Result<()> {
+    let mut out = Checksummed { inner: out, crc: 0 };
+
+    write!(out, "REDIS{VERSION:04}")?;
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    write_aux(&mut out, "redis-ver", "7.2.0")?;
+    write_aux(&mut out, "redis-bits", "64")?;
+    write_aux(&mut out, "ctime", &ctime.to_string())?;
+    write_aux(&mut out, "aof-base", "0")?;
+
+    for (index, store) in databases.iter() {
+        let entries = store.iter().collect::<Vec<_>>();
+        if entries.is_empty() {
+            continue;
+        }
+        let expires = entries
+            .iter()
+            .filter(|(_, entry)| entry.expiration.deadline().is_some())
+            .count();
+
+        out.write_all(&[OPCODE_SELECTDB])?;
+        write_len(&mut out, index)?;
+        out.write_all(&[OPCODE_RESIZEDB])?;
+        write_len(&mut out, entries.len())?;
+        write_len(&mut out, expires)?;
+
+        for (key, entry) in entries {
+            write_entry(&mut out, key, entry, compress)?;
+            progress.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    out.write_all(&[OPCODE_EOF])?;
+    let crc = out.crc;
+    out.inner.write_all(&crc.to_le_bytes())?;
+    out.inner.flush()
+}
+
+/// Writes the snapshot to a temporary file next to `path` and renames it into
+/// place, so a crash mid-save never leaves a truncated file behind.
+pub fn save(
+    databases: &Databases,
+    path: &Path,
+    compress: bool,
+    progress: &AtomicUsize,
+) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", process::id()));
+
+    let result = File::create(&temp).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        write(databases, &mut out, compress, progress)?;
+        out.into_inner()?.sync_all()
+    });
+    match result.and_then(|_| fs::rename(&temp, path)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+/// Why an RDB file could not be loaded. Nothing is loaded from a file that
+/// fails any check.
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("Wrong signature trying to load DB from file")]
+    Signature,
+    #[error("Can't handle RDB format version {0}")]
+    Version(u32),
+    #[error("{reason} at offset {offset}{}{}",
+        .opcode.map(|opcode| format!(", opcode {opcode:#04x}")).unwrap_or_default(),
+        .key.as_ref().map(|key| format!(", key '{key}'")).unwrap_or_default())]
+    Corrupt {
+        offset: usize,
+        opcode: Option<u8>,
+        key: Option<String>,
+        reason: String,
+    },
+    #[error("Wrong RDB checksum expected: {expected:016x} got: {computed:016x}")]
+    Checksum { expected: u64, computed: u64 },
+    #[error("The RDB file uses database {index} but only {count} are configured")]
+    Database { index: u64, count: usize },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl LoadError {
+    /// The parse error `err`, for input starting `offset` bytes into the file.
+    fn corrupt(offset: usize, input: &[u8], err: nom::Err<RdbError>) -> Self {
+        match err {
+            nom::Err::Error(err) | nom::Err::Failure(err) => LoadError::Corrupt {
+                offset: offset + input.len().saturating_sub(err.input.len()),
+                opcode: err.opcode,
+                key: err.key,
+                reason: err.reason,
+            },
+            nom::Err::Incomplete(_) => LoadError::Corrupt {
+                offset,
+                opcode: None,
+                key: None,
+                reason: "Unexpected end of file".into(),
+            },
+        }
+    }
+}
+
+/// Reads an RDB file a chunk at a time, so only the item being parsed has
+/// to be buffered rather than the whole file.
+struct Chunks<'p, R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// Where the unparsed part of `buf` starts.
+    start: usize,
+    /// How many bytes were parsed, which `progress` is kept up to date with.
+    offset: usize,
+    progress: &'p AtomicUsize,
+    eof: bool,
+    crc: u64,
+}
+
+impl<'p, R: Read> Chunks<'p, R> {
+    fn new(reader: R, progress: &'p AtomicUsize) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            offset: 0,
+            progress,
+            eof: false,
+            crc: 0,
+        }
+    }
+
+    /// Runs `parser` on what follows, reading more of the file for as long
+    /// as it needs more and there is more to read. Anything else it fails
+    /// on is corrupt, however much of the file is left.
+    fn parse<T>(&mut self, parser: impl Fn(&[u8]) -> IResult<'_, T>) -> Result<T, LoadError> {
+        loop {
+            let input = &self.buf[self.start..];
+            match parser(input) {
+                Ok((rest, value)) => {
+                    let used = input.len() - rest.len();
+                    self.crc = crc64::update(self.crc, &input[..used]);
+                    self.start += used;
+                    self.offset += used;
+                    self.progress.store(self.offset, Ordering::Relaxed);
+                    return Ok(value);
+                }
+                Err(nom::Err::Incomplete(_)) if !self.eof => self.fill()?,
+                Err(err) => return Err(LoadError::corrupt(self.offset, input, err)),
+            }
+        }
+    }
+
+    /// Reads at least as much as is buffered, so an item spanning many
+    /// chunks is only parsed again a logarithmic number of times.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + LOAD_CHUNK.max(len), 0);
+        let mut filled = len;
+        while filled < self.buf.len() {
+            match self.reader.read(&mut self.buf[filled..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.buf.truncate(filled);
+        Ok(())
+    }
+}
+
+fn durable_value(kv: &KVPair) -> DurableValue {
+    let usage = match (kv.idle, kv.freq) {
+        (_, Some(counter)) => Usage::Lfu(counter),
+        // a corrupt file can claim a key was idle since before time began
+        (Some(idle), None) => SystemTime::now()
+            .checked_sub(idle)
+            .map_or(Usage::Unknown, Usage::Lru),
+        (None, None) => Usage::Unknown,
+    };
+    DurableValue {
+        val: Object::from(&kv.value),
+        expiration: kv
+            .expiration
+            .map(|exp| Expiration::Date(UNIX_EPOCH + exp))
+            .unwrap_or_default(),
+        usage,
+    }
+}
+
+/// Loads the RDB file at the start of `bytes` into `databases`, returning how
+/// many bytes it took up, checksum included.
+pub fn load(databases: &mut Databases, bytes: &[u8]) -> Result<usize, LoadError> {
+    load_from(databases, bytes, &AtomicUsize::new(0))
+}
+
+/// Replaces what `databases` hold with the RDB file `reader` starts with,
+/// inserting keys as they are read, and returns how many bytes the file took
+/// up. `progress` counts the bytes read so far. The checksum is only skipped
+/// when the file was saved without one, which leaves it zero.
+pub fn load_from(
+    databases: &mut Databases,
+    reader: impl Read,
+    progress: &AtomicUsize,
+) -> Result<usize, LoadError> {
+    let mut chunks = Chunks::new(reader, progress);
+    let version = chunks.parse(header).map_err(|err| match err {
+        LoadError::Io(err) => LoadError::Io(err),
+        _ => LoadError::Signature,
+    })?;
+    if !(1..=MAX_LOAD_VERSION).contains(&version) {
+        return Err(LoadError::Version(version));
+    }
+
+    // keys go somewhere else until the whole file checks out
+    let mut loaded = Databases::new(databases.len());
+    let mut db = 0;
+    while let Some(item) = chunks.parse(|input| match input.first() {
+        Some(&OPCODE_EOF) => Ok((&input[1..], None)),
+        _ => map(item, Some)(input),
+    })? {
+        match item {
+            Item::SelectDb(index) if index >= databases.len() as u64 => {
+                return Err(LoadError::Database {
+                    index,
+                    count: databases.len(),
+                })
+            }
+            Item::SelectDb(index) => db = index as usize,
+            Item::Entry(kv) => {
+                let value = durable_value(&kv);
+                loaded.db(db).insert(kv.key.to_string(), value);
+            }
+            Item::Aux(_) | Item::ResizeDb(_) | Item::Function(_) | Item::Skipped => (),
+        }
+    }
+
+    if version >= CHECKSUM_VERSION {
+        let computed = chunks.crc;
+        let expected = chunks
+            .parse(|input| le_u64(input))
+            .map_err(|err| match err {
+                LoadError::Corrupt { offset, .. } => LoadError::Corrupt {
+                    offset,
+                    opcode: None,
+                    key: None,
+                    reason: "Unexpected end of file reading the checksum".into(),
+                },
+                err => err,
+            })?;
+        if expected != 0 && expected != computed {
+            return Err(LoadError::Checksum { expected, computed });
+        }
+    }
+    *databases = loaded;
+    Ok(chunks.offset)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::parser::rdb::{self, parse_rdb, DBString};
+    use crate::store::{Consumer, ConsumerGroup, ModuleValue, PendingEntry, SortedSet, StreamId};
+
+    #[test]
+    fn round_trips_through_the_parser() {
+        let mut databases = Databases::new(4);
+        let deadline = UNIX_EPOCH + Duration::from_millis(4_000_000_000_000);
+        databases.db(0).insert(
+            "binary".into(),
+            DurableValue::new(Object::String(vec![0xFE, 0xFF, 0x00])),
+        );
+        databases.db(0).insert(
+            "expiring".into(),
+            DurableValue {
+                expiration: Expiration::Date(deadline),
+                ..DurableValue::new(Object::String(b"soon".to_vec()))
+            },
+        );
+        databases.db(2).insert(
+            "zset".into(),
+            DurableValue::new(Object::ZSet(SortedSet::from_iter([
+                (b"a".to_vec(), 1.5),
+                (vec![b'x'; 100], -2.0),
+            ]))),
+        );
+        databases.db(2).insert(
+            "list".into(),
+            DurableValue::new(Object::List(VecDeque::from([b"a".to_vec(), vec![]]))),
+        );
+        databases.db(2).insert(
+            "set".into(),
+            DurableValue::new(Object::Set(HashSet::from([b"a".to_vec(), b"b".to_vec()]))),
+        );
+        databases.db(3).insert(
+            "hot".into(),
+            DurableValue {
+                usage: Usage::Lfu(7),
+                ..DurableValue::new(Object::String(b"often".to_vec()))
+            },
+        );
+        databases.db(3).insert(
+            "hash".into(),
+            DurableValue::new(Object::Hash(HashMap::from([(
+                b"field".to_vec(),
+                vec![b'v'; 50],
+            )]))),
+        );
+
+        let mut bytes = Vec::new();
+        let progress = AtomicUsize::new(0);
+        write(&databases, &mut bytes, true, &progress).unwrap();
+        assert_eq!(progress.into_inner(), 7);
+
+        let mut uncompressed = Vec::new();
+        write(&databases, &mut uncompressed, false, &AtomicUsize::new(0)).unwrap();
+        assert!(bytes.len() + 50 < uncompressed.len());
+
+        let (rest, rdb) = parse_rdb(&bytes).unwrap();
+        let (body, checksum) = bytes.split_at(bytes.len() - 8);
+        assert_eq!(rest, checksum);
+        assert_eq!(
+            u64::from_le_bytes(checksum.try_into().unwrap()),
+            crc64::update(0, body)
+        );
+        assert_eq!(rdb.version, VERSION);
+        assert_eq!(
+            rdb.databases.iter().map(|db| db.number).collect::<Vec<_>>(),
+            vec![0, 2, 3]
+        );
+
+        let expiring = rdb.entries().find(|kv| kv.key.to_string() == "expiring");
+        assert_eq!(
+            expiring.unwrap().expiration,
+            Some(Duration::from_millis(4_000_000_000_000))
+        );
+        assert_eq!(
+            rdb.get("binary").next(),
+            Some(&rdb::Value::String(DBString::Str(vec![0xFE, 0xFF, 0x00])))
+        );
+        assert_eq!(
+            rdb.get("zset").next(),
+            Some(&rdb::Value::SortedSet(vec![
+                (DBString::Str(vec![b'x'; 100]), -2.0),
+                (DBString::Str(b"a".to_vec()), 1.5),
+            ]))
+        );
+
+        let mut loaded = Databases::new(4);
+        assert_eq!(load(&mut loaded, &bytes).unwrap(), bytes.len());
+        for (index, store) in databases.iter() {
+            for (key, entry) in store.iter() {
+                assert_eq!(loaded.db(index).get(key), Some(entry));
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_streams_and_module_values() {
+        let id = |ms, seq| StreamId { ms, seq };
+        let mut stream = Stream::default();
+        // enough entries for several listpacks, not all with the same fields
+        for i in 0..250u64 {
+            let mut fields = vec![(b"sensor".to_vec(), i.to_string().into_bytes())];
+            if i % 7 == 0 {
+                fields.push((b"extra".to_vec(), vec![b'e'; 70]));
+            }
+            stream
+                .entries
+                .insert(id(1_700_000_000_000 + i / 3, i % 3), fields);
+        }
+        stream.last_id = id(1_700_000_000_100, 0);
+        stream.first_id = id(1_700_000_000_000, 0);
+        stream.max_deleted_id = id(1_700_000_000_090, 1);
+        stream.entries_added = 260;
+        stream.groups.push(ConsumerGroup {
+            name: b"readers".to_vec(),
+            last_id: id(1_700_000_000_002, 1),
+            entries_read: Some(8),
+            pending: [
+                (id(1_700_000_000_001, 0), "alice"),
+                (id(1_700_000_000_002, 1), "bob"),
+            ]
+            .into_iter()
+            .map(|(id, consumer)| {
+                let entry = PendingEntry {
+                    consumer: consumer.into(),
+                    delivery_time: 1_700_000_001_000,
+                    delivery_count: 2,
+                };
+                (id, entry)
+            })
+            .collect(),
+            consumers: ["alice", "bob", "carol"]
+                .into_iter()
+                .map(|name| Consumer {
+                    name: name.into(),
+                    seen_time: 1_700_000_002_000,
+                    active_time: 1_700_000_001_000,
+                })
+                .collect(),
+        });
+        stream.groups.push(ConsumerGroup {
+            name: b"idle".to_vec(),
+            last_id: StreamId::default(),
+            entries_read: None,
+            pending: Default::default(),
+            consumers: Vec::new(),
+        });
+
+        let mut databases = Databases::new(1);
+        databases
+            .db(0)
+            .insert("stream".into(), DurableValue::new(Object::Stream(stream)));
+        databases.db(0).insert(
+            "empty".into(),
+            DurableValue::new(Object::Stream(Stream::default())),
+        );
+        databases.db(0).insert(
+            "module".into(),
+            DurableValue::new(Object::Module(ModuleValue {
+                name: rdb::module_type_name(0x1234_5678_9abc_d003),
+                id: 0x1234_5678_9abc_d003,
+                // an unsigned integer, a string, then the end opcode
+                payload: vec![0x02, 0x05, 0x05, 0x02, b'h', b'i', 0x00],
+            })),
+        );
+
+        let mut bytes = Vec::new();
+        write(&databases, &mut bytes, true, &AtomicUsize::new(0)).unwrap();
+        let mut loaded = Databases::new(1);
+        assert_eq!(load(&mut loaded, &bytes).unwrap(), bytes.len());
+        for (key, entry) in databases.db(0).iter() {
+            assert_eq!(loaded.db(0).get(key), Some(entry), "{key}");
+        }
+    }
+
+    fn saved(key: &str, value: Object) -> Vec<u8> {
+        let mut databases = Databases::new(1);
+        databases.db(0).insert(key.into(), DurableValue::new(value));
+        let mut bytes = Vec::new();
+        write(&databases, &mut bytes, false, &AtomicUsize::new(0)).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn verifies_the_checksum() {
+        let mut bytes = saved("key", Object::String(b"value".to_vec()));
+        let len = bytes.len();
+        assert_eq!(load(&mut Databases::new(1), &bytes).unwrap(), len);
+
+        // a file saved without a checksum leaves it zero
+        let mut unchecked = bytes.clone();
+        unchecked[len - 8..].fill(0);
+        unchecked[len - 10] ^= 1;
+        assert_eq!(load(&mut Databases::new(1), &unchecked).unwrap(), len);
+
+        bytes[len - 10] ^= 1;
+        let mut databases = Databases::new(1);
+        assert!(matches!(
+            load(&mut databases, &bytes),
+            Err(LoadError::Checksum { .. })
+        ));
+        assert_eq!(databases.db(0).len(), 0);
+
+        assert!(matches!(
+            load(&mut Databases::new(1), &bytes[..len - 3]),
+            Err(LoadError::Corrupt { offset, .. }) if offset == len - 8
+        ));
+    }
+
+    #[test]
+    fn checks_the_version() {
+        let mut bytes = saved("key", Object::String(b"value".to_vec()));
+        bytes[5..9].copy_from_slice(b"0099");
+        assert!(matches!(
+            load(&mut Databases::new(1), &bytes),
+            Err(LoadError::Version(99))
+        ));
+        assert!(matches!(
+            load(&mut Databases::new(1), b"NOTRDB0011"),
+            Err(LoadError::Signature)
+        ));
+    }
+
+    #[test]
+    fn reports_where_a_value_is_corrupt() {
+        let mut databases = Databases::new(2);
+        let list = Object::List(VecDeque::from([b"a".to_vec()]));
+        databases
+            .db(0)
+            .insert("broken".into(), DurableValue::new(list));
+        // plenty more keys follow it
+        for i in 0..20_000 {
+            databases.db(1).insert(
+                format!("key:{i}"),
+                DurableValue::new(Object::String(vec![b'x'; 100])),
+            );
+        }
+        let mut bytes = Vec::new();
+        write(&databases, &mut bytes, false, &AtomicUsize::new(0)).unwrap();
+
+        // the list's only element claims an encoding that does not exist
+        let at = bytes.windows(6).position(|w| w == b"broken").unwrap() + 7;
+        bytes[at] = 0xC5;
+        let mut reader = &bytes[..];
+        let err = load_from(&mut Databases::new(2), &mut reader, &AtomicUsize::new(0)).unwrap_err();
+        let LoadError::Corrupt {
+            offset,
+            opcode,
+            key,
+            ..
+        } = &err
+        else {
+            panic!("unexpected error {err}");
+        };
+        assert!(*offset <= at + 1);
+        assert_eq!(*opcode, Some(TYPE_LIST));
+        assert_eq!(key.as_deref(), Some("broken"));
+        assert!(err.to_string().contains("key 'broken'"));
+        // without reading the rest of the file
+        assert_eq!(bytes.len() - reader.len(), LOAD_CHUNK);
+
+        // a file cut off is corrupt where the item it cut off starts
+        let bytes = saved("cut", Object::List(VecDeque::from([b"a".to_vec()])));
+        let end = bytes.len() - 10;
+        let start = bytes.windows(3).position(|w| w == b"cut").unwrap() - 2;
+        assert!(matches!(
+            load(&mut Databases::new(1), &bytes[..end]),
+            Err(LoadError::Corrupt { offset, .. }) if offset == start
+        ));
+    }
+
+    #[test]
+    fn ignores_idle_times_out_of_range() {
+        let kv = KVPair {
+            key: DBString::Str(b"idle".to_vec()),
+            value: rdb::Value::String(DBString::Str(b"v".to_vec())),
+            expiration: None,
+            idle: Some(Duration::MAX),
+            freq: None,
+        };
+        assert_eq!(durable_value(&kv).usage, Usage::Unknown);
+    }
+
+    #[test]
+    fn rejects_compressed_strings_claiming_huge_lengths() {
+        let mut databases = Databases::new(1);
+        let value = Object::String(vec![b'x'; 1000]);
+        databases
+            .db(0)
+            .insert("big".into(), DurableValue::new(value));
+        let mut bytes = Vec::new();
+        write(&databases, &mut bytes, true, &AtomicUsize::new(0)).unwrap();
+
+        // the uncompressed length 1000 becomes one of nearly 4GB
+        let at = bytes
+            .windows(4)
+            .position(|window| window[0] == 0xC3 && window[2..] == [0x43, 0xE8])
+            .unwrap();
+        bytes.splice(at + 2..at + 4, [0x80, 0xFF, 0xFF, 0xFF, 0xF0]);
+        let err = load(&mut Databases::new(1), &bytes).unwrap_err();
+        assert!(matches!(err, LoadError::Corrupt { .. }), "{err}");
+    }
+
+    /// Hands out a few bytes at a time, like a slow disk or socket.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(1000);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn loads_a_chunk_at_a_time() {
+        let mut databases = Databases::new(2);
+        for i in 0..1000 {
+            databases.db(i % 2).insert(
+                format!("key:{i}"),
+                DurableValue::new(Object::String(i.to_string().into_bytes())),
+            );
+        }
+        // a value spanning several chunks
+        let big: Vec<u8> = (0..3 * LOAD_CHUNK).map(|i| (i % 251) as u8).collect();
+        databases
+            .db(1)
+            .insert("big".into(), DurableValue::new(Object::String(big.clone())));
+        let mut bytes = Vec::new();
+        write(&databases, &mut bytes, false, &AtomicUsize::new(0)).unwrap();
+        // what follows the file is left alone
+        let mut padded = bytes.clone();
+        padded.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+
+        let mut loaded = Databases::new(2);
+        let progress = AtomicUsize::new(0);
+        let len = load_from(&mut loaded, Trickle(&padded), &progress).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(progress.into_inner(), bytes.len());
+        assert_eq!(loaded.db(0).len(), 500);
+        assert_eq!(loaded.db(1).len(), 501);
+        assert_eq!(loaded.db(1).get_string("big").unwrap(), Some(&big));
+    }
+
+    #[test]
+    fn save_replaces_the_file() {
+        let dir = std::env::temp_dir().join(format!("rdb-save-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.rdb");
+        fs::write(&path, b"stale").unwrap();
+
+        let mut databases = Databases::new(1);
+        databases
+            .db(0)
+            .insert("k".into(), DurableValue::new(Object::String(b"v".to_vec())));
+        save(&databases, &path, true, &AtomicUsize::new(0)).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"REDIS0011"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
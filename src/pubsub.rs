@@ -0,0 +1,792 @@
+//! Publish/subscribe: connections subscribe to channels, by name or by
+//! glob-style pattern, and are sent whatever is published to them.
+
+pub mod notify;
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use itertools::Itertools;
+
+use crate::client::Client;
+use crate::message::RespMessage;
+use crate::parser::resp::{BulkString, Value};
+use crate::server::Server;
+pub use notify::{EventClass, KeyEvent};
+
+/// Numbers connections that subscribe, so they can be told apart.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Sends to a subscribed connection, whose thread writes it out.
+#[derive(Debug, Clone)]
+pub struct Push {
+    id: u64,
+    sender: Sender<Arc<[u8]>>,
+}
+
+impl Push {
+    /// Queues `bytes`, returning whether the connection is still there to
+    /// write them.
+    pub fn send(&self, bytes: Arc<[u8]>) -> bool {
+        self.sender.send(bytes).is_ok()
+    }
+}
+
+/// Starts writing what is pushed to `stream` from a thread of its own, so
+/// publishing never waits on a slow subscriber.
+pub fn writer(mut stream: TcpStream) -> Push {
+    let (sender, receiver) = mpsc::channel::<Arc<[u8]>>();
+    thread::spawn(move || {
+        for bytes in receiver {
+            if stream.write_all(&bytes).is_err() {
+                return;
+            }
+        }
+    });
+    Push {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        sender,
+    }
+}
+
+/// The three kinds of subscription, each with names of its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Channel,
+    Pattern,
+    /// A channel bound to the hash slot of its name, so in a cluster its
+    /// messages stay within the shard owning the slot.
+    Shard,
+}
+
+impl Kind {
+    /// How confirmations name the command.
+    fn command(self, subscribing: bool) -> &'static str {
+        match (self, subscribing) {
+            (Kind::Channel, true) => "subscribe",
+            (Kind::Channel, false) => "unsubscribe",
+            (Kind::Pattern, true) => "psubscribe",
+            (Kind::Pattern, false) => "punsubscribe",
+            (Kind::Shard, true) => "ssubscribe",
+            (Kind::Shard, false) => "sunsubscribe",
+        }
+    }
+}
+
+/// What a client subscribed to, in the order it did.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    /// Where the client's messages go, and then its replies too so they stay
+    /// in order. The connection sets it up before the first subscription.
+    pub push: Option<Push>,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    shard_channels: Vec<String>,
+}
+
+impl Subscriptions {
+    /// How many subscriptions of any kind there are.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    fn names(&mut self, kind: Kind) -> &mut Vec<String> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// The count confirmations report: shard channels are counted apart
+    /// from channels and patterns.
+    fn confirmed(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Channel | Kind::Pattern => self.channels.len() + self.patterns.len(),
+            Kind::Shard => self.shard_channels.len(),
+        }
+    }
+}
+
+/// Every subscription on the server, by channel, by pattern and by shard
+/// channel.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: HashMap<String, Vec<Push>>,
+    patterns: HashMap<String, Vec<Push>>,
+    shard_channels: HashMap<String, Vec<Push>>,
+    /// Which keyspace notifications are published, as parsed by
+    /// `notify::parse_flags`.
+    notify_flags: u16,
+}
+
+/// The confirmation sent for each channel or pattern a client subscribes to
+/// or unsubscribes from.
+fn confirmation(kind: &str, name: Option<&str>, count: usize) -> Value {
+    vec![
+        BulkString::from(kind).into(),
+        name.map_or(BulkString::Null, BulkString::from).into(),
+        Value::Int(count as isize),
+    ]
+    .into()
+}
+
+fn add(subscribers: &mut HashMap<String, Vec<Push>>, name: &str, push: &Push) {
+    subscribers
+        .entry(name.to_string())
+        .or_default()
+        .push(push.clone());
+}
+
+fn remove(subscribers: &mut HashMap<String, Vec<Push>>, name: &str, push: &Push) {
+    if let Some(pushes) = subscribers.get_mut(name) {
+        pushes.retain(|other| other.id != push.id);
+        if pushes.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+/// Sends `message` as `kind`, say `message`, to each of `pushes`, returning
+/// how many are still there to get it.
+fn send(pushes: &[Push], kind: &str, names: &[&str], message: &[u8]) -> usize {
+    let mut items: Vec<Value> = vec![BulkString::from(kind).into()];
+    items.extend(names.iter().map(|name| BulkString::from(*name).into()));
+    items.push(BulkString::from(message).into());
+    let bytes: Arc<[u8]> = Value::from(items).to_bytes().into();
+    pushes
+        .iter()
+        .filter(|push| push.send(Arc::clone(&bytes)))
+        .count()
+}
+
+/// The channels in `subscribers`, those matching `pattern` when one is given.
+fn active<'a>(subscribers: &'a HashMap<String, Vec<Push>>, pattern: Option<&str>) -> Vec<&'a str> {
+    subscribers
+        .keys()
+        .filter(|channel| {
+            pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+impl PubSub {
+    fn subscribers(&mut self, kind: Kind) -> &mut HashMap<String, Vec<Push>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of the patterns
+    /// matching it, returning how many it was sent to.
+    pub fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let mut receivers = self
+            .channels
+            .get(channel)
+            .map_or(0, |pushes| send(pushes, "message", &[channel], message));
+        for (pattern, pushes) in &self.patterns {
+            if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                receivers += send(pushes, "pmessage", &[pattern, channel], message);
+            }
+        }
+        receivers
+    }
+
+    /// Sends `message` to the subscribers of the shard channel `channel`,
+    /// returning how many it was sent to.
+    pub fn spublish(&self, channel: &str, message: &[u8]) -> usize {
+        self.shard_channels
+            .get(channel)
+            .map_or(0, |pushes| send(pushes, "smessage", &[channel], message))
+    }
+
+    pub fn notify_flags(&self) -> u16 {
+        self.notify_flags
+    }
+
+    pub fn set_notify_flags(&mut self, flags: u16) {
+        self.notify_flags = flags;
+    }
+
+    /// Publishes the keyspace notifications for `events`, each made in the
+    /// database alongside it.
+    pub fn notify(&self, events: &[(usize, KeyEvent)]) {
+        for (db, event) in events {
+            for (channel, message) in notify::channels(self.notify_flags, *db, event) {
+                self.publish(&channel, message.as_bytes());
+            }
+        }
+    }
+
+    /// The channels with at least one subscriber, those matching `pattern`
+    /// when one is given.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<&str> {
+        active(&self.channels, pattern)
+    }
+
+    /// Like `channels`, for shard channels.
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<&str> {
+        active(&self.shard_channels, pattern)
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, Vec::len)
+    }
+
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        self.shard_channels.get(channel).map_or(0, Vec::len)
+    }
+
+    /// How many distinct patterns are subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Drops every subscription of `client`, without confirming any.
+    pub fn unsubscribe_all(&mut self, client: &mut Client) {
+        let subscriptions = &mut client.subscriptions;
+        let Some(push) = subscriptions.push.clone() else {
+            return;
+        };
+        for kind in [Kind::Channel, Kind::Pattern, Kind::Shard] {
+            for name in subscriptions.names(kind).drain(..) {
+                remove(self.subscribers(kind), &name, &push);
+            }
+        }
+    }
+}
+
+/// The hash slot of `key`, CRC16 of it modulo 16384. Only what is between
+/// the first `{` and the `}` after it counts when that is not empty, so
+/// related keys can be put in the same slot.
+fn key_hash_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|&byte| byte == b'{').and_then(|open| {
+        let tag = &key[open + 1..];
+        let close = tag.iter().position(|&byte| byte == b'}')?;
+        (close > 0).then(|| &tag[..close])
+    });
+    crc16(tagged.unwrap_or(key)) % 16384
+}
+
+/// CRC16-CCITT in its XMODEM variant, as cluster slots use.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Runs one of the subscribe and unsubscribe commands for `client`, whose
+/// `push` is set up, returning a confirmation for each name.
+pub fn subscriptions(server: &mut Server, client: &mut Client, message: RespMessage) -> Vec<Value> {
+    let (kind, subscribing, names) = match message {
+        RespMessage::Subscribe(names) => (Kind::Channel, true, names),
+        RespMessage::Unsubscribe(names) => (Kind::Channel, false, names),
+        RespMessage::PSubscribe(names) => (Kind::Pattern, true, names),
+        RespMessage::PUnsubscribe(names) => (Kind::Pattern, false, names),
+        RespMessage::SSubscribe(names) => (Kind::Shard, true, names),
+        RespMessage::SUnsubscribe(names) => (Kind::Shard, false, names),
+        _ => unreachable!("only subscriptions are run here"),
+    };
+    // one shard owns all of them, or none can be served together
+    if kind == Kind::Shard
+        && !names
+            .iter()
+            .map(|name| key_hash_slot(name.as_bytes()))
+            .all_equal()
+    {
+        return vec![Value::error(
+            "CROSSSLOT",
+            "Keys in request don't hash to the same slot",
+        )];
+    }
+
+    let subscriptions = &mut client.subscriptions;
+    let push = subscriptions
+        .push
+        .clone()
+        .expect("connections set up a push before subscribing");
+    let subscribers = server.pubsub.subscribers(kind);
+    let command = kind.command(subscribing);
+    // unsubscribing from nothing in particular is from everything
+    let names = if names.is_empty() {
+        subscriptions.names(kind).clone()
+    } else {
+        names
+    };
+
+    let mut replies = Vec::new();
+    for name in &names {
+        let mine = subscriptions.names(kind);
+        if !subscribing {
+            mine.retain(|other| other != name);
+            remove(subscribers, name, &push);
+        } else if !mine.contains(name) {
+            mine.push(name.clone());
+            add(subscribers, name, &push);
+        }
+        replies.push(confirmation(
+            command,
+            Some(name),
+            subscriptions.confirmed(kind),
+        ));
+    }
+    if replies.is_empty() {
+        replies.push(confirmation(command, None, subscriptions.confirmed(kind)));
+    }
+    replies
+}
+
+/// Whether `string` matches the glob-style `pattern`: `*` matches anything,
+/// `?` any one byte, `[...]` one of a class with ranges and `^` negation,
+/// and `\` escapes the byte that follows.
+///
+/// Only the last `*` is ever backtracked to, since whatever an earlier one
+/// could match instead the later one can too, which keeps matching linear in
+/// the pattern times the string however many stars there are.
+pub fn glob_match(mut pattern: &[u8], mut string: &[u8]) -> bool {
+    // the pattern after the last `*`, and where in the string it is tried next
+    let mut star: Option<(&[u8], &[u8])> = None;
+    loop {
+        let step = match pattern {
+            [] if string.is_empty() => return true,
+            [b'*', rest @ ..] => {
+                star = Some((rest, string));
+                pattern = rest;
+                continue;
+            }
+            [] => None,
+            [b'?', rest @ ..] => string.split_first().map(|(_, tail)| (rest, tail)),
+            [b'[', class @ ..] => string.split_first().and_then(|(&byte, tail)| {
+                let (matched, rest) = match_class(class, byte);
+                matched.then_some((rest, tail))
+            }),
+            [b'\\', escaped, rest @ ..] | [escaped, rest @ ..] => match string.split_first() {
+                Some((byte, tail)) if byte == escaped => Some((rest, tail)),
+                _ => None,
+            },
+        };
+        (pattern, string) = match (step, star) {
+            (Some(step), _) => step,
+            // the last `*` takes one more byte
+            (None, Some((rest, [_, tail @ ..]))) => {
+                star = Some((rest, tail));
+                (rest, tail)
+            }
+            (None, _) => return false,
+        };
+    }
+}
+
+/// Matches `byte` against the class `pattern` starts with, just after its
+/// `[`, returning whether it matched and the pattern after the closing `]`.
+/// A class left open runs to the end of the pattern.
+fn match_class(pattern: &[u8], byte: u8) -> (bool, &[u8]) {
+    let (negated, mut pattern) = match pattern {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        pattern = match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == byte;
+                rest
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end {
+                    (start, end)
+                } else {
+                    (end, start)
+                };
+                matched |= (*low..=*high).contains(&byte);
+                rest
+            }
+            [other, rest @ ..] => {
+                matched |= *other == byte;
+                rest
+            }
+        };
+    }
+    (matched != negated, pattern)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::Receiver;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use super::*;
+    use crate::commands;
+    use crate::store::{Databases, DurableValue, Expiration, Object};
+
+    fn subscriber() -> (Client, Receiver<Arc<[u8]>>) {
+        let (sender, receiver) = mpsc::channel();
+        let mut client = Client::default();
+        client.subscriptions.push = Some(Push {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            sender,
+        });
+        (client, receiver)
+    }
+
+    fn message(args: &[&str]) -> RespMessage {
+        let value: Value = args
+            .iter()
+            .map(|arg| BulkString::from(*arg).into())
+            .collect::<Vec<Value>>()
+            .into();
+        RespMessage::try_from(value).unwrap()
+    }
+
+    fn run(server: &mut Server, client: &mut Client, args: &[&str]) -> Value {
+        commands::execute(server, client, message(args), b"")
+    }
+
+    fn received(receiver: &Receiver<Arc<[u8]>>) -> Vec<u8> {
+        receiver
+            .try_iter()
+            .flat_map(|bytes| bytes.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn publishes_keyspace_notifications() {
+        let mut server = Server::new(Databases::new(2));
+        let (mut listener, messages) = subscriber();
+        subscriptions(
+            &mut server,
+            &mut listener,
+            message(&["psubscribe", "__key*__:*"]),
+        );
+        let pmessage = |pattern: &str, channel: &str, message: &str| {
+            Value::from(vec![
+                BulkString::from("pmessage").into(),
+                BulkString::from(pattern).into(),
+                BulkString::from(channel).into(),
+                BulkString::from(message).into(),
+            ])
+            .to_bytes()
+        };
+
+        let mut client = Client::default();
+        run(&mut server, &mut client, &["set", "k", "v"]);
+        assert!(received(&messages).is_empty());
+
+        assert_eq!(
+            run(
+                &mut server,
+                &mut client,
+                &["config", "set", "notify-keyspace-events", "KEg$"]
+            ),
+            Value::ok()
+        );
+        run(&mut server, &mut client, &["set", "k", "v"]);
+        run(&mut server, &mut client, &["del", "k", "missing"]);
+        let expected = [
+            pmessage("__key*__:*", "__keyspace@0__:k", "set"),
+            pmessage("__key*__:*", "__keyevent@0__:set", "k"),
+            pmessage("__key*__:*", "__keyspace@0__:k", "del"),
+            pmessage("__key*__:*", "__keyevent@0__:del", "k"),
+        ];
+        assert_eq!(received(&messages), expected.concat());
+
+        // expired keys found by a lookup are only published with `x`
+        let expired = || DurableValue {
+            expiration: Expiration::Date(UNIX_EPOCH),
+            ..DurableValue::new(Object::String(b"v".to_vec()))
+        };
+        server.databases.db(1).insert("old".into(), expired());
+        run(&mut server, &mut client, &["select", "1"]);
+        run(&mut server, &mut client, &["get", "old"]);
+        assert!(received(&messages).is_empty());
+
+        run(
+            &mut server,
+            &mut client,
+            &["config", "set", "notify-keyspace-events", "Ex"],
+        );
+        assert_eq!(
+            run(
+                &mut server,
+                &mut client,
+                &["config", "get", "notify-keyspace-events"]
+            ),
+            vec![
+                BulkString::from("notify-keyspace-events").into(),
+                BulkString::from("xE").into(),
+            ]
+            .into()
+        );
+        server.databases.db(1).insert("old".into(), expired());
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "old"]),
+            BulkString::Null.into()
+        );
+        assert_eq!(
+            received(&messages),
+            pmessage("__key*__:*", "__keyevent@1__:expired", "old")
+        );
+    }
+
+    #[test]
+    fn publishes_to_channels_and_patterns() {
+        let mut server = Server::new(Databases::new(1));
+        let (mut news, news_messages) = subscriber();
+        let (mut all, all_messages) = subscriber();
+
+        let replies = subscriptions(
+            &mut server,
+            &mut news,
+            message(&["subscribe", "news", "sport"]),
+        );
+        assert_eq!(
+            replies,
+            [
+                confirmation("subscribe", Some("news"), 1),
+                confirmation("subscribe", Some("sport"), 2),
+            ]
+        );
+        subscriptions(&mut server, &mut all, message(&["psubscribe", "n*"]));
+
+        let mut publisher = Client::default();
+        assert_eq!(
+            run(&mut server, &mut publisher, &["publish", "news", "hi"]),
+            Value::Int(2)
+        );
+        assert_eq!(
+            run(&mut server, &mut publisher, &["publish", "weather", "rain"]),
+            Value::Int(0)
+        );
+        assert_eq!(
+            received(&news_messages),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            received(&all_messages),
+            b"*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+
+        let mut channels = run(&mut server, &mut publisher, &["pubsub", "channels"]).to_bytes();
+        channels.sort();
+        let mut expected = Value::from(vec![
+            BulkString::from("news").into(),
+            BulkString::from("sport").into(),
+        ])
+        .to_bytes();
+        expected.sort();
+        assert_eq!(channels, expected);
+        assert_eq!(
+            run(
+                &mut server,
+                &mut publisher,
+                &["pubsub", "numsub", "news", "none"]
+            ),
+            vec![
+                BulkString::from("news").into(),
+                Value::Int(1),
+                BulkString::from("none").into(),
+                Value::Int(0),
+            ]
+            .into()
+        );
+        assert_eq!(
+            run(&mut server, &mut publisher, &["pubsub", "numpat"]),
+            Value::Int(1)
+        );
+
+        // unsubscribing from everything confirms each channel
+        assert_eq!(
+            subscriptions(&mut server, &mut news, message(&["unsubscribe"])),
+            [
+                confirmation("unsubscribe", Some("news"), 1),
+                confirmation("unsubscribe", Some("sport"), 0),
+            ]
+        );
+        assert_eq!(
+            subscriptions(&mut server, &mut news, message(&["unsubscribe"])),
+            [confirmation("unsubscribe", None, 0)]
+        );
+        assert_eq!(
+            run(&mut server, &mut publisher, &["pubsub", "numsub", "news"]),
+            vec![BulkString::from("news").into(), Value::Int(0)].into()
+        );
+    }
+
+    #[test]
+    fn subscribed_connections_only_manage_subscriptions() {
+        let mut server = Server::new(Databases::new(1));
+        let (mut client, _messages) = subscriber();
+        subscriptions(&mut server, &mut client, message(&["subscribe", "news"]));
+
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            Value::error(
+                "ERR",
+                "Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET \
+                 are allowed in this context"
+            )
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["ping"]),
+            vec![BulkString::from("pong").into(), BulkString::Empty.into()].into()
+        );
+
+        assert_eq!(
+            run(&mut server, &mut client, &["reset"]),
+            Value::String("RESET".into())
+        );
+        assert_eq!(server.pubsub.numsub("news"), 0);
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            BulkString::Null.into()
+        );
+    }
+
+    #[test]
+    fn shard_channels_are_apart_from_channels() {
+        let mut server = Server::new(Databases::new(1));
+        let (mut client, messages) = subscriber();
+        subscriptions(&mut server, &mut client, message(&["subscribe", "orders"]));
+        assert_eq!(
+            subscriptions(
+                &mut server,
+                &mut client,
+                message(&["ssubscribe", "{user1}.orders", "{user1}.cart"])
+            ),
+            [
+                confirmation("ssubscribe", Some("{user1}.orders"), 1),
+                confirmation("ssubscribe", Some("{user1}.cart"), 2),
+            ]
+        );
+        assert_eq!(
+            subscriptions(&mut server, &mut client, message(&["ssubscribe", "a", "b"])),
+            [Value::error(
+                "CROSSSLOT",
+                "Keys in request don't hash to the same slot"
+            )]
+        );
+
+        let mut publisher = Client::default();
+        assert_eq!(
+            run(&mut server, &mut publisher, &["spublish", "orders", "x"]),
+            Value::Int(0)
+        );
+        assert_eq!(
+            run(
+                &mut server,
+                &mut publisher,
+                &["spublish", "{user1}.cart", "x"]
+            ),
+            Value::Int(1)
+        );
+        assert_eq!(
+            received(&messages),
+            b"*3\r\n$8\r\nsmessage\r\n$12\r\n{user1}.cart\r\n$1\r\nx\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut server,
+                &mut publisher,
+                &["pubsub", "shardchannels", "*.cart"]
+            ),
+            vec![BulkString::from("{user1}.cart").into()].into()
+        );
+        assert_eq!(
+            run(
+                &mut server,
+                &mut publisher,
+                &["pubsub", "shardnumsub", "orders"]
+            ),
+            vec![BulkString::from("orders").into(), Value::Int(0)].into()
+        );
+
+        assert_eq!(
+            subscriptions(&mut server, &mut client, message(&["sunsubscribe"])),
+            [
+                confirmation("sunsubscribe", Some("{user1}.orders"), 1),
+                confirmation("sunsubscribe", Some("{user1}.cart"), 0),
+            ]
+        );
+        assert_eq!(client.subscriptions.count(), 1);
+    }
+
+    #[test]
+    fn hashes_keys_to_cluster_slots() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"{foo}.bar"), 12182);
+        assert_eq!(key_hash_slot(b"{}foo"), crc16(b"{}foo") % 16384);
+        assert_eq!(key_hash_slot(b"foo{"), crc16(b"foo{") % 16384);
+    }
+
+    #[test]
+    fn matches_glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("news.*", "news.sport", true),
+            ("news.*", "weather", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*o", "heeello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[c-a]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("a**", "abc", true),
+            ("*a", "ba", true),
+            ("*a", "ab", false),
+            ("*?c", "abc", true),
+            ("*[bc]d", "abcd", true),
+            ("\\", "\\", true),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                *expected,
+                "{pattern} against {string}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_many_stars_quickly() {
+        let pattern = "*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b";
+        let channel = "a".repeat(10_000);
+        let started = Instant::now();
+        assert!(!glob_match(pattern.as_bytes(), channel.as_bytes()));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
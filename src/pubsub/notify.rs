@@ -0,0 +1,154 @@
+//! Keyspace notifications: what commands do to keys, published to
+//! `__keyspace@<db>__:<key>` and `__keyevent@<db>__:<event>` channels for
+//! the classes of events `notify-keyspace-events` turns on.
+
+/// Publish to `__keyspace@<db>__:<key>`, with the event as the message.
+const KEYSPACE: u16 = 1;
+/// Publish to `__keyevent@<db>__:<event>`, with the key as the message.
+const KEYEVENT: u16 = 1 << 1;
+const GENERIC: u16 = 1 << 2;
+const STRING: u16 = 1 << 3;
+const LIST: u16 = 1 << 4;
+const SET: u16 = 1 << 5;
+const HASH: u16 = 1 << 6;
+const ZSET: u16 = 1 << 7;
+const EXPIRED: u16 = 1 << 8;
+const EVICTED: u16 = 1 << 9;
+const STREAM: u16 = 1 << 10;
+const KEY_MISS: u16 = 1 << 11;
+const MODULE: u16 = 1 << 12;
+const NEW: u16 = 1 << 13;
+/// What `A` stands for, which leaves out key misses and new keys.
+const ALL: u16 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+/// The classes, in the order their letters are listed back.
+const CLASSES: &[(char, u16)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+    ('n', NEW),
+];
+
+/// The class of an event, which decides whether it is published.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventClass {
+    /// Not specific to a type, such as `del` or `rename_from`.
+    Generic,
+    String,
+    ZSet,
+    /// A key found expired and removed.
+    Expired,
+}
+
+impl EventClass {
+    fn flag(self) -> u16 {
+        match self {
+            EventClass::Generic => GENERIC,
+            EventClass::String => STRING,
+            EventClass::ZSet => ZSET,
+            EventClass::Expired => EXPIRED,
+        }
+    }
+}
+
+/// Something a command did to a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyEvent {
+    pub class: EventClass,
+    /// Named as Redis names it, say `set` or `expired`.
+    pub event: &'static str,
+    pub key: String,
+}
+
+/// Parses `notify-keyspace-events`, such as `KEA` or `Kx`. Nothing is
+/// published unless `K` or `E` is there with at least one class.
+pub fn parse_flags(flags: &str) -> Option<u16> {
+    let mut parsed = 0;
+    for letter in flags.chars() {
+        parsed |= match letter {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            _ => CLASSES.iter().find(|(class, _)| *class == letter)?.1,
+        };
+    }
+    Some(parsed)
+}
+
+/// Lists `flags` back the way Redis does, classes first.
+pub fn flags_to_string(flags: u16) -> String {
+    let mut listed = String::new();
+    if flags & ALL == ALL {
+        listed.push('A');
+    }
+    for &(letter, class) in CLASSES {
+        if flags & class != 0 && (flags & ALL != ALL || class & ALL == 0) {
+            listed.push(letter);
+        }
+    }
+    for (letter, flag) in [('K', KEYSPACE), ('E', KEYEVENT), ('m', KEY_MISS)] {
+        if flags & flag != 0 {
+            listed.push(letter);
+        }
+    }
+    listed
+}
+
+/// The channels `event` in database `db` is published to under `flags`,
+/// each with the message to publish there.
+pub fn channels(flags: u16, db: usize, event: &KeyEvent) -> Vec<(String, &str)> {
+    let mut channels = Vec::new();
+    if flags & event.class.flag() == 0 {
+        return channels;
+    }
+    if flags & KEYSPACE != 0 {
+        channels.push((format!("__keyspace@{db}__:{}", event.key), event.event));
+    }
+    if flags & KEYEVENT != 0 {
+        channels.push((format!("__keyevent@{db}__:{}", event.event), &event.key[..]));
+    }
+    channels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_lists_flags() {
+        assert_eq!(parse_flags(""), Some(0));
+        assert_eq!(parse_flags("KEA"), Some(KEYSPACE | KEYEVENT | ALL));
+        assert_eq!(parse_flags("Ex$"), Some(KEYEVENT | EXPIRED | STRING));
+        assert_eq!(parse_flags("Kw"), None);
+
+        assert_eq!(flags_to_string(parse_flags("EAK").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("Ex$").unwrap()), "$xE");
+        assert_eq!(flags_to_string(parse_flags("KnAm").unwrap()), "AnKm");
+    }
+
+    #[test]
+    fn publishes_enabled_classes_to_their_channels() {
+        let event = KeyEvent {
+            class: EventClass::String,
+            event: "set",
+            key: "k".into(),
+        };
+        assert_eq!(
+            channels(parse_flags("KE$").unwrap(), 3, &event),
+            [
+                ("__keyspace@3__:k".to_string(), "set"),
+                ("__keyevent@3__:set".to_string(), "k"),
+            ]
+        );
+        assert!(channels(parse_flags("KEg").unwrap(), 3, &event).is_empty());
+        assert!(channels(parse_flags("$").unwrap(), 3, &event).is_empty());
+    }
+}
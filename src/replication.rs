@@ -0,0 +1,1502 @@
+//! Replication: a replica starts from a snapshot of its master's dataset, then
+//! runs every write the master runs, which the master streams to it.
+
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fmt::Write as _,
+    fs::{self, File},
+    hash::{BuildHasher, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+    net::{IpAddr, Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::AtomicUsize,
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::client::Client;
+use crate::commands;
+use crate::message::{CommandError, RespMessage};
+use crate::parser::resp::{parser, BulkString, Value};
+use crate::persistence::rdb;
+use crate::server::Server;
+use crate::store::Databases;
+
+/// How long a replica waits before connecting again to a master it lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How often a replica tells its master how far it got.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often `WAITAOF` checks the local fsync, which does not wake it.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Woken whenever a replica acknowledges, for clients blocked in `WAIT`.
+static ACKS: Condvar = Condvar::new();
+/// How many bytes of writes the backlog keeps, Redis's default `repl-backlog-size`.
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+/// Reported for the secondary ID when there is none.
+const NO_REPLID: &str = "0000000000000000000000000000000000000000";
+/// How long the marker ending a snapshot sent without its length is.
+const EOF_MARK_LEN: usize = 40;
+
+/// A 40 character hexadecimal ID naming a history of writes.
+fn new_replid() -> String {
+    let mut replid = String::with_capacity(48);
+    for _ in 0..3 {
+        replid.push_str(&format!(
+            "{:016x}",
+            RandomState::new().build_hasher().finish()
+        ));
+    }
+    replid.truncate(40);
+    replid
+}
+
+/// `args` as a command is sent.
+pub fn command(args: &[&str]) -> Vec<u8> {
+    let command: Value = args
+        .iter()
+        .map(|arg| BulkString::from(*arg).into())
+        .collect::<Vec<Value>>()
+        .into();
+    command.to_bytes()
+}
+
+/// A replica attached to this server, sent the writes it runs.
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    ip: Option<IpAddr>,
+    listening_port: Option<u16>,
+    /// Whether it has its snapshot and is being sent writes.
+    online: bool,
+    /// How far into the stream it says it got, and got fsynced.
+    ack: u64,
+    fack: u64,
+    /// Shut down when the replica is detached, so its threads stop.
+    stream: TcpStream,
+    sender: Sender<Arc<[u8]>>,
+}
+
+/// How far a replica got in syncing with its master.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkState {
+    /// Waiting to connect, at the given time.
+    Connect(Instant),
+    Connecting,
+    /// Receiving the master's snapshot.
+    Sync,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Master {
+    host: String,
+    port: u16,
+    /// Tells successive links apart, so a thread whose link was replaced stops.
+    link: u64,
+    state: LinkState,
+    stream: Option<TcpStream>,
+    last_ack: Instant,
+}
+
+/// The latest writes streamed to replicas, so one that reconnects can pick
+/// up where it left off rather than sync again from scratch.
+#[derive(Debug)]
+struct Backlog {
+    bytes: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+        if self.bytes.len() > self.size {
+            self.bytes.drain(..self.bytes.len() - self.size);
+        }
+    }
+}
+
+/// When a replica loads its master's snapshot as it arrives, the
+/// `repl-diskless-load` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DisklessLoad {
+    /// Never: it is saved as the dump file first, then loaded from there.
+    #[default]
+    Disabled,
+    /// Only while the dataset is empty, so there is nothing to lose.
+    OnEmptyDb,
+    /// Always, keeping the dataset until the snapshot loaded.
+    Swapdb,
+}
+
+impl FromStr for DisklessLoad {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "disabled" => Ok(DisklessLoad::Disabled),
+            "on-empty-db" => Ok(DisklessLoad::OnEmptyDb),
+            "swapdb" => Ok(DisklessLoad::Swapdb),
+            _ => Err(()),
+        }
+    }
+}
+
+/// This server's replicas, and the master it replicates when it is a replica.
+#[derive(Debug)]
+pub struct Replication {
+    /// Names the history of writes `offset` counts the bytes of. Replicas
+    /// take on their master's.
+    replid: String,
+    /// How many bytes of writes were streamed, by this server or its master.
+    offset: u64,
+    /// The ID this server's history went by before a promotion, and the
+    /// offset following the last write under it.
+    replid2: Option<(String, u64)>,
+    backlog: Option<Backlog>,
+    backlog_size: usize,
+    /// Whether snapshots are streamed to replicas as they are serialized.
+    diskless_sync: bool,
+    diskless_load: DisklessLoad,
+    /// Whether a replica answers clients while its link with the master is
+    /// down, or while it syncs.
+    serve_stale_data: bool,
+    /// The database the stream last selected, `None` when the next write
+    /// has to select one.
+    selected_db: Option<usize>,
+    replicas: Vec<Replica>,
+    /// Numbers both replicas and links with masters.
+    next_id: u64,
+    master: Option<Master>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            replid: new_replid(),
+            offset: 0,
+            replid2: None,
+            backlog: None,
+            backlog_size: DEFAULT_BACKLOG_SIZE,
+            diskless_sync: false,
+            diskless_load: DisklessLoad::Disabled,
+            serve_stale_data: true,
+            selected_db: None,
+            replicas: Vec::new(),
+            next_id: 0,
+            master: None,
+        }
+    }
+}
+
+impl Replication {
+    pub fn set_backlog_size(&mut self, size: usize) {
+        self.backlog_size = size;
+        if let Some(backlog) = &mut self.backlog {
+            backlog.size = size;
+            backlog.push(&[]);
+        }
+    }
+
+    pub fn set_diskless_sync(&mut self, diskless: bool) {
+        self.diskless_sync = diskless;
+    }
+
+    pub fn set_diskless_load(&mut self, mode: DisklessLoad) {
+        self.diskless_load = mode;
+    }
+
+    pub fn serve_stale_data(&self) -> bool {
+        self.serve_stale_data
+    }
+
+    pub fn set_serve_stale_data(&mut self, serve: bool) {
+        self.serve_stale_data = serve;
+    }
+
+    /// Streams `command`, which ran against database `db`, to every replica.
+    /// Nothing is kept until the first replica attaches, but the offset
+    /// counts every write so `WAITAOF` can tell how far fsyncs got.
+    pub fn feed(&mut self, db: usize, command: &[u8]) {
+        let mut out = Vec::with_capacity(command.len());
+        if self.selected_db != Some(db) {
+            out.extend(self::command(&["SELECT", &db.to_string()]));
+        }
+        out.extend_from_slice(command);
+        self.selected_db = Some(db);
+        self.stream(&out);
+    }
+
+    /// Streams what the master sent, as it came, so this server's replicas
+    /// and backlog stay byte for byte the same as the master's.
+    fn proxy(&mut self, bytes: &[u8]) {
+        self.create_backlog();
+        self.stream(bytes);
+        self.selected_db = None;
+    }
+
+    fn create_backlog(&mut self) {
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog {
+                bytes: VecDeque::new(),
+                size: self.backlog_size,
+            });
+        }
+    }
+
+    fn stream(&mut self, bytes: &[u8]) {
+        if let Some(backlog) = &mut self.backlog {
+            backlog.push(bytes);
+        }
+        self.offset += bytes.len() as u64;
+
+        let bytes: Arc<[u8]> = bytes.into();
+        self.replicas
+            .retain(|replica| replica.sender.send(Arc::clone(&bytes)).is_ok());
+    }
+
+    /// The writes a replica that has the history `replid` up to `offset`
+    /// is missing, when the backlog still holds them.
+    fn since(&self, replid: &str, offset: i64) -> Option<Vec<u8>> {
+        let offset = u64::try_from(offset).ok()?;
+        let known = replid == self.replid
+            || self
+                .replid2
+                .as_ref()
+                .is_some_and(|(replid2, until)| replid == replid2 && offset <= *until);
+        let backlog = self.backlog.as_ref()?;
+        let first = self.offset + 1 - backlog.bytes.len() as u64;
+        if !known || offset < first || offset > self.offset + 1 {
+            return None;
+        }
+        Some(
+            backlog
+                .bytes
+                .range((offset - first) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// Takes on a new history, remembering the current one so replicas that
+    /// were following it can carry on.
+    fn shift_replid(&mut self, replid: String) {
+        let old = std::mem::replace(&mut self.replid, replid);
+        self.replid2 = Some((old, self.offset + 1));
+    }
+
+    /// Disconnects every replica, so they sync again.
+    fn disconnect_replicas(&mut self) {
+        for replica in self.replicas.drain(..) {
+            let _ = replica.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Starts sending writes to a replica reachable over `stream`.
+    fn attach(
+        &mut self,
+        stream: TcpStream,
+        listening_port: Option<u16>,
+    ) -> (u64, Receiver<Arc<[u8]>>) {
+        self.create_backlog();
+        let (sender, receiver) = mpsc::channel();
+        self.next_id += 1;
+        self.replicas.push(Replica {
+            id: self.next_id,
+            ip: stream.peer_addr().ok().map(|addr| addr.ip()),
+            listening_port,
+            online: false,
+            ack: 0,
+            fack: 0,
+            stream,
+            sender,
+        });
+        // the snapshot it starts from says nothing of the selected database
+        self.selected_db = None;
+        (self.next_id, receiver)
+    }
+
+    fn detach(&mut self, id: u64) {
+        self.replicas.retain(|replica| {
+            if replica.id == id {
+                let _ = replica.stream.shutdown(Shutdown::Both);
+            }
+            replica.id != id
+        });
+    }
+
+    fn online(&mut self, id: u64) {
+        if let Some(replica) = self.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.online = true;
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Asks every replica how far it got, which they answer with `REPLCONF ACK`.
+    fn request_acks(&mut self) {
+        if !self.replicas.is_empty() {
+            self.stream(&command(&["REPLCONF", "GETACK", "*"]));
+        }
+    }
+
+    fn acknowledged(&mut self, id: u64, ack: u64, fack: Option<u64>) {
+        if let Some(replica) = self.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack = ack;
+            replica.fack = fack.unwrap_or(replica.fack);
+        }
+    }
+
+    /// How many replicas acknowledged `offset`, and how many have it fsynced.
+    fn acked(&self, offset: u64) -> (usize, usize) {
+        let acked = self.replicas.iter().filter(|replica| replica.ack >= offset);
+        let fsynced = self
+            .replicas
+            .iter()
+            .filter(|replica| replica.fack >= offset);
+        (acked.count(), fsynced.count())
+    }
+
+    /// Tells the master how far this server got, and got fsynced when it has
+    /// an append only file.
+    fn send_ack(&mut self, fsynced: Option<u64>) {
+        let offset = self.offset.to_string();
+        let Some(master) = &mut self.master else {
+            return;
+        };
+        let Some(stream) = master
+            .stream
+            .as_ref()
+            .filter(|_| master.state == LinkState::Connected)
+        else {
+            return;
+        };
+        let ack = match fsynced {
+            Some(fsynced) => command(&["REPLCONF", "ACK", &offset, "FACK", &fsynced.to_string()]),
+            None => command(&["REPLCONF", "ACK", &offset]),
+        };
+        if let Err(err) = (&*stream).write_all(&ack) {
+            eprintln!("could not acknowledge the master: {err}");
+        }
+        master.last_ack = Instant::now();
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Whether this is a replica whose data may be out of date, because it
+    /// is not connected to its master or is still syncing.
+    pub fn is_stale(&self) -> bool {
+        self.master
+            .as_ref()
+            .is_some_and(|master| master.state != LinkState::Connected)
+    }
+
+    /// The master this server replicates.
+    pub fn master(&self) -> Option<(&str, u16)> {
+        self.master
+            .as_ref()
+            .map(|master| (master.host.as_str(), master.port))
+    }
+
+    /// Starts replicating `master`, or stops replicating with `None`.
+    pub fn set_master(&mut self, master: Option<(String, u16)>) {
+        if let Some(stream) = self.master.take().and_then(|old| old.stream) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        match master {
+            Some((host, port)) => {
+                self.next_id += 1;
+                self.master = Some(Master {
+                    host,
+                    port,
+                    link: self.next_id,
+                    state: LinkState::Connect(Instant::now()),
+                    stream: None,
+                    last_ack: Instant::now(),
+                });
+                // they sync again, with what this server is about to load
+                self.disconnect_replicas();
+            }
+            // the writes that follow are a new history
+            None => self.shift_replid(new_replid()),
+        }
+    }
+
+    /// The master to connect to, when it is time to.
+    fn connect_due(&mut self) -> Option<(String, u16, u64)> {
+        let master = self.master.as_mut()?;
+        match master.state {
+            LinkState::Connect(at) if at <= Instant::now() => {
+                master.state = LinkState::Connecting;
+                Some((master.host.clone(), master.port, master.link))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn info(&self) -> String {
+        let mut info = String::from("# Replication\r\n");
+        match &self.master {
+            Some(master) => {
+                let _ = write!(
+                    info,
+                    "role:slave\r\n\
+                     master_host:{}\r\n\
+                     master_port:{}\r\n\
+                     master_link_status:{}\r\n\
+                     master_sync_in_progress:{}\r\n\
+                     slave_repl_offset:{}\r\n",
+                    master.host,
+                    master.port,
+                    if master.state == LinkState::Connected {
+                        "up"
+                    } else {
+                        "down"
+                    },
+                    (master.state == LinkState::Sync) as u8,
+                    self.offset,
+                );
+            }
+            None => info.push_str("role:master\r\n"),
+        }
+        let _ = write!(info, "connected_slaves:{}\r\n", self.replicas.len());
+        for (index, replica) in self.replicas.iter().enumerate() {
+            let _ = write!(
+                info,
+                "slave{index}:ip={},port={},state={},offset={}\r\n",
+                replica
+                    .ip
+                    .map_or_else(|| "?".to_string(), |ip| ip.to_string()),
+                replica.listening_port.unwrap_or(0),
+                if replica.online {
+                    "online"
+                } else {
+                    "send_bulk"
+                },
+                replica.ack,
+            );
+        }
+        let (replid2, second_offset) = match &self.replid2 {
+            Some((replid2, until)) => (replid2.as_str(), *until as i64),
+            None => (NO_REPLID, -1),
+        };
+        let (active, first_byte, histlen) = match &self.backlog {
+            Some(backlog) => (
+                1,
+                self.offset + 1 - backlog.bytes.len() as u64,
+                backlog.bytes.len(),
+            ),
+            None => (0, 0, 0),
+        };
+        let _ = write!(
+            info,
+            "master_replid:{}\r\n\
+             master_replid2:{replid2}\r\n\
+             master_repl_offset:{}\r\n\
+             second_repl_offset:{second_offset}\r\n\
+             repl_backlog_active:{active}\r\n\
+             repl_backlog_size:{}\r\n\
+             repl_backlog_first_byte_offset:{first_byte}\r\n\
+             repl_backlog_histlen:{histlen}\r\n",
+            self.replid, self.offset, self.backlog_size,
+        );
+        info
+    }
+
+    /// The master of `link`, unless it was replaced since.
+    fn link(&mut self, link: u64) -> Option<&mut Master> {
+        self.master.as_mut().filter(|master| master.link == link)
+    }
+}
+
+/// Run periodically to connect to the master, when this server is a replica
+/// that is not connected to it.
+pub fn cron(server: &Arc<Mutex<Server>>, listening_port: u16, rdb_path: &Path) {
+    let due = {
+        let server = &mut *server.lock().unwrap();
+        let replication = &mut server.replication;
+        if replication
+            .master
+            .as_ref()
+            .is_some_and(|master| master.last_ack.elapsed() >= ACK_INTERVAL)
+        {
+            replication.send_ack(server.persistence.aof_fsynced());
+        }
+        replication.connect_due()
+    };
+    let Some((host, port, link)) = due else {
+        return;
+    };
+    let server = Arc::clone(server);
+    let rdb_path = rdb_path.to_path_buf();
+    thread::spawn(move || {
+        let result = follow(&server, &host, port, link, listening_port, rdb_path);
+        // links that were replaced were closed on purpose
+        if let Some(master) = server.lock().unwrap().replication.link(link) {
+            if let Err(err) = result {
+                eprintln!("lost the link with master {host}:{port}: {err}");
+            }
+            master.state = LinkState::Connect(Instant::now() + RECONNECT_DELAY);
+            master.stream = None;
+        }
+    });
+}
+
+/// Sends `args` to the master and reads its one line reply.
+fn request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    args: &[&str],
+) -> io::Result<String> {
+    writer.write_all(&command(args))?;
+    let reply = read_line(reader)?;
+    if reply.starts_with('-') {
+        return Err(io::Error::other(format!("{} replied {reply}", args[0])));
+    }
+    Ok(reply)
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Reads a snapshot streamed with an EOF marker, up to the marker and no
+/// further, so the writes that follow are left to read.
+struct UntilMark<'r, R> {
+    reader: &'r mut R,
+    mark: Vec<u8>,
+    /// Read but not returned yet, holding back what may start the marker.
+    held: Vec<u8>,
+    found: bool,
+}
+
+impl<R: BufRead> Read for UntilMark<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let keep = if self.found { 0 } else { self.mark.len() - 1 };
+            if self.found || self.held.len() > keep {
+                let len = buf.len().min(self.held.len() - keep);
+                buf[..len].copy_from_slice(&self.held[..len]);
+                self.held.drain(..len);
+                return Ok(len);
+            }
+
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let read = available.len();
+            let before = self.held.len();
+            self.held.extend_from_slice(available);
+            let from = before.saturating_sub(self.mark.len() - 1);
+            let consumed = match self.held[from..]
+                .windows(self.mark.len())
+                .position(|window| window == self.mark)
+            {
+                Some(at) => {
+                    self.held.truncate(from + at);
+                    self.found = true;
+                    from + at + self.mark.len() - before
+                }
+                None => read,
+            };
+            self.reader.consume(consumed);
+        }
+    }
+}
+
+/// Writes a snapshot to a temporary file that then replaces the dump file
+/// at `path`, like saves do, and opens it to load.
+fn receive(mut snapshot: impl Read, path: &Path) -> io::Result<File> {
+    let temp = path.with_file_name(format!("temp-sync-{}.rdb", process::id()));
+
+    let result = File::create(&temp).and_then(|mut file| {
+        io::copy(&mut snapshot, &mut file)?;
+        file.sync_all()
+    });
+    match result.and_then(|_| fs::rename(&temp, path)) {
+        Ok(()) => File::open(path),
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+/// Loads the snapshot that follows `+FULLRESYNC` in place of the dataset,
+/// returning whether the link is still this server's.
+fn full_sync(
+    server: &Mutex<Server>,
+    reader: &mut impl BufRead,
+    link: u64,
+    replid: &str,
+    offset: u64,
+    rdb_path: &Path,
+) -> io::Result<bool> {
+    // the snapshot is sent as a bulk string without the final newline,
+    // which masters may precede with newlines while they are writing it
+    let mut header = String::new();
+    while header.is_empty() {
+        header = read_line(reader)?;
+    }
+    // or, when it is streamed as it is serialized, ended by a marker
+    let (len, mut snapshot): (u64, Box<dyn Read + '_>) = match header.strip_prefix("$EOF:") {
+        Some(mark) if mark.len() == EOF_MARK_LEN => (
+            0,
+            Box::new(UntilMark {
+                reader,
+                mark: mark.as_bytes().to_vec(),
+                held: Vec::new(),
+                found: false,
+            }),
+        ),
+        _ => {
+            let len = header
+                .strip_prefix('$')
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| io::Error::other(format!("unexpected snapshot header: {header}")))?;
+            (len, Box::new(reader.take(len)))
+        }
+    };
+
+    let (count, progress, diskless) = {
+        let server = &mut *server.lock().unwrap();
+        let empty = server.databases.iter().all(|(_, store)| store.len() == 0);
+        let diskless = match server.replication.diskless_load {
+            DisklessLoad::Disabled => false,
+            DisklessLoad::OnEmptyDb => empty,
+            DisklessLoad::Swapdb => true,
+        };
+        let Some(master) = server.replication.link(link) else {
+            return Ok(false);
+        };
+        master.state = LinkState::Sync;
+        (
+            server.databases.len(),
+            server.persistence.start_loading(len as usize),
+            diskless,
+        )
+    };
+    let mut databases = Databases::new(count);
+    let loaded = if diskless {
+        rdb::load_from(&mut databases, &mut snapshot, &progress)
+    } else {
+        receive(&mut snapshot, rdb_path)
+            .map_err(rdb::LoadError::from)
+            .and_then(|file| rdb::load_from(&mut databases, file, &progress))
+    };
+    // the writes that follow start after the whole of it
+    if loaded.is_ok() {
+        io::copy(&mut snapshot, &mut io::sink())?;
+    }
+
+    let server = &mut *server.lock().unwrap();
+    server.persistence.finish_loading();
+    loaded.map_err(io::Error::other)?;
+    let Some(master) = server.replication.link(link) else {
+        return Ok(false);
+    };
+    master.state = LinkState::Connected;
+    server.databases = databases;
+
+    // the master's history is this server's from now on
+    let replication = &mut server.replication;
+    replication.replid = replid.to_string();
+    replication.offset = offset;
+    replication.replid2 = None;
+    replication.backlog = None;
+    replication.disconnect_replicas();
+    // the append only file has to start over from the new dataset
+    if server.persistence.aof_enabled() {
+        if let Err(err) = server.persistence.bgrewriteaof(&server.databases) {
+            eprintln!("could not rewrite the append only file after syncing: {err}");
+        }
+    }
+    // masters that streamed the snapshot wait for this to stream writes
+    server
+        .replication
+        .send_ack(server.persistence.aof_fsynced());
+    Ok(true)
+}
+
+/// Syncs with the master of `link` and then runs the writes it streams,
+/// for as long as the link is this server's.
+fn follow(
+    server: &Mutex<Server>,
+    host: &str,
+    port: u16,
+    link: u64,
+    listening_port: u16,
+    rdb_path: PathBuf,
+) -> io::Result<()> {
+    let stream = TcpStream::connect((host, port))?;
+    match server.lock().unwrap().replication.link(link) {
+        Some(master) => master.stream = Some(stream.try_clone()?),
+        None => return Ok(()),
+    }
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    request(&mut reader, &mut writer, &["PING"])?;
+    request(
+        &mut reader,
+        &mut writer,
+        &["REPLCONF", "listening-port", &listening_port.to_string()],
+    )?;
+    request(
+        &mut reader,
+        &mut writer,
+        &["REPLCONF", "capa", "eof", "capa", "psync2"],
+    )?;
+
+    // ask to carry on from what this server has, which the master can
+    // only do when it still holds the writes that followed
+    let (replid, offset) = {
+        let replication = &server.lock().unwrap().replication;
+        (replication.replid.clone(), replication.offset)
+    };
+    let reply = request(
+        &mut reader,
+        &mut writer,
+        &["PSYNC", &replid, &(offset + 1).to_string()],
+    )?;
+    let mut words = reply.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("+CONTINUE"), new_replid, None) => {
+            let server = &mut *server.lock().unwrap();
+            let Some(master) = server.replication.link(link) else {
+                return Ok(());
+            };
+            master.state = LinkState::Connected;
+            if let Some(new_replid) = new_replid.filter(|new_replid| *new_replid != replid) {
+                server.replication.shift_replid(new_replid.to_string());
+                // so they learn of the new ID
+                server.replication.disconnect_replicas();
+            }
+        }
+        (Some("+FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset
+                .parse()
+                .map_err(|_| io::Error::other(format!("invalid offset in {reply}")))?;
+            if !full_sync(server, &mut reader, link, replid, offset, &rdb_path)? {
+                return Ok(());
+            }
+        }
+        _ => {
+            return Err(io::Error::other(format!(
+                "unexpected reply to PSYNC: {reply}"
+            )))
+        }
+    }
+
+    let mut client = Client {
+        master: true,
+        ..Client::default()
+    };
+    let mut pending = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        pending.extend_from_slice(&buffer[..read]);
+
+        loop {
+            let (consumed, value) = match parser(&pending) {
+                Ok((rest, value)) => (pending.len() - rest.len(), value),
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(_) => return Err(io::Error::other("protocol error in the replication stream")),
+            };
+            let server = &mut *server.lock().unwrap();
+            if server.replication.link(link).is_none() {
+                return Ok(());
+            }
+            // replies go nowhere, the master does not read them
+            let mut getack = false;
+            if let Ok(message) = RespMessage::try_from(value) {
+                getack = matches!(&message, RespMessage::ReplConf(params)
+                    if params.iter().any(|(option, _)| option == "getack"));
+                commands::execute(server, &mut client, message, &pending[..consumed]);
+            }
+            // the ack stops short of the GETACK itself, as the master expects
+            if getack {
+                server
+                    .replication
+                    .send_ack(server.persistence.aof_fsynced());
+            }
+            server.replication.proxy(&pending[..consumed]);
+            server.persistence.aof_written(server.replication.offset);
+            pending.drain(..consumed);
+        }
+    }
+}
+
+/// Reads what replica `id` sends, which is only acknowledgements and gets
+/// no reply. The first is also sent to `first_ack`.
+fn read_acks(
+    mut stream: TcpStream,
+    server: &Mutex<Server>,
+    id: u64,
+    first_ack: Sender<()>,
+) -> io::Result<()> {
+    let mut first_ack = Some(first_ack);
+    let mut pending = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buffer[..read]);
+
+        loop {
+            let (consumed, value) = match parser(&pending) {
+                Ok((rest, value)) => (pending.len() - rest.len(), value),
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(_) => return Err(io::Error::other("protocol error from a replica")),
+            };
+            if let Ok(RespMessage::ReplConf(params)) = RespMessage::try_from(value) {
+                let option = |name: &str| {
+                    params
+                        .iter()
+                        .find(|(option, _)| option == name)
+                        .and_then(|(_, value)| value.parse().ok())
+                };
+                if let Some(ack) = option("ack") {
+                    server
+                        .lock()
+                        .unwrap()
+                        .replication
+                        .acknowledged(id, ack, option("fack"));
+                    ACKS.notify_all();
+                    if let Some(first_ack) = first_ack.take() {
+                        let _ = first_ack.send(());
+                    }
+                }
+            }
+            pending.drain(..consumed);
+        }
+    }
+}
+
+/// What `WAIT` or `WAITAOF` waits for: enough replicas, and for `WAITAOF`
+/// the local append only file, to have a client's last write.
+struct WaitFor {
+    numlocal: usize,
+    numreplicas: usize,
+    timeout: u64,
+    aof: bool,
+    woff: u64,
+}
+
+impl WaitFor {
+    fn new(server: &Server, client: &Client, message: RespMessage) -> Result<Self, Value> {
+        let (numlocal, numreplicas, timeout, aof) = match message {
+            RespMessage::Wait {
+                numreplicas,
+                timeout,
+            } => (0, numreplicas, timeout, false),
+            RespMessage::WaitAof {
+                numlocal,
+                numreplicas,
+                timeout,
+            } => (numlocal, numreplicas, timeout, true),
+            _ => unreachable!("only WAIT and WAITAOF wait"),
+        };
+        let name = if aof { "WAITAOF" } else { "WAIT" };
+
+        if server.replication.master.is_some() {
+            return Err(CommandError::Invalid(format!(
+                "{name} cannot be used with replica instances. \
+                 Please also note that writes to replicas are just local and are not propagated."
+            ))
+            .into());
+        }
+        if numlocal > 0 && !server.persistence.aof_enabled() {
+            return Err(CommandError::Invalid(
+                "WAITAOF cannot be used when numlocal is set but appendonly is disabled.".into(),
+            )
+            .into());
+        }
+        Ok(Self {
+            numlocal,
+            numreplicas,
+            timeout,
+            aof,
+            woff: client.woff,
+        })
+    }
+
+    /// How many local files and replicas have the write so far.
+    fn counts(&self, server: &Server) -> (usize, usize) {
+        let (acked, fsynced) = server.replication.acked(self.woff);
+        if self.aof {
+            let local = server
+                .persistence
+                .aof_fsynced()
+                .is_some_and(|at| at >= self.woff);
+            (local as usize, fsynced)
+        } else {
+            (0, acked)
+        }
+    }
+
+    fn done(&self, server: &Server) -> bool {
+        let (local, replicas) = self.counts(server);
+        local >= self.numlocal && replicas >= self.numreplicas
+    }
+
+    fn reply(&self, server: &Server) -> Value {
+        let (local, replicas) = self.counts(server);
+        if self.aof {
+            vec![Value::Int(local as isize), Value::Int(replicas as isize)].into()
+        } else {
+            Value::Int(replicas as isize)
+        }
+    }
+}
+
+/// Answers `WAIT` and `WAITAOF`, blocking until enough replicas, and the
+/// local append only file for `WAITAOF`, have `client`'s last write or the
+/// timeout passes. Replies with how many do.
+pub fn wait(server: &Mutex<Server>, client: &Client, message: RespMessage) -> Value {
+    let mut server = server.lock().unwrap();
+    let wait = match WaitFor::new(&server, client, message) {
+        Ok(wait) => wait,
+        Err(err) => return err,
+    };
+    let deadline = (wait.timeout > 0).then(|| Instant::now() + Duration::from_millis(wait.timeout));
+
+    if !wait.done(&server) {
+        server.replication.request_acks();
+    }
+    while !wait.done(&server) {
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => WAIT_POLL_INTERVAL,
+        };
+        if remaining.is_zero() {
+            break;
+        }
+        server = ACKS
+            .wait_timeout(server, remaining.min(WAIT_POLL_INTERVAL))
+            .unwrap()
+            .0;
+    }
+    wait.reply(&server)
+}
+
+/// Answers `WAIT` and `WAITAOF` with how many have `client`'s last write
+/// already, as they do inside a transaction where they must not block.
+pub fn wait_now(server: &Server, client: &Client, message: RespMessage) -> Value {
+    match WaitFor::new(server, client, message) {
+        Ok(wait) => wait.reply(server),
+        Err(err) => err,
+    }
+}
+
+/// How a replica catches up with this server.
+enum Catchup {
+    /// With the writes it missed, which the backlog still holds.
+    Partial { replid: String, missed: Vec<u8> },
+    /// From a snapshot of the dataset.
+    Full {
+        snapshot: Databases,
+        replid: String,
+        offset: u64,
+        compress: bool,
+        /// Whether it is streamed as it is serialized.
+        diskless: bool,
+    },
+}
+
+/// Answers `PSYNC` for a replica that has the history `replid` up to
+/// `offset`, then streams writes to it until it disconnects. Snapshots that
+/// are not streamed are serialized next to the dump file at `rdb_path` first.
+pub fn serve_replica(
+    mut stream: TcpStream,
+    server: &Arc<Mutex<Server>>,
+    client: &Client,
+    replid: &str,
+    offset: i64,
+    rdb_path: &Path,
+) -> io::Result<()> {
+    let (id, updates, catchup) = {
+        let server = &mut *server.lock().unwrap();
+        let replication = &mut server.replication;
+        let catchup = match replication.since(replid, offset) {
+            Some(missed) => Catchup::Partial {
+                replid: replication.replid.clone(),
+                missed,
+            },
+            None => Catchup::Full {
+                snapshot: server.databases.clone(),
+                replid: replication.replid.clone(),
+                offset: replication.offset,
+                compress: server.persistence.compression(),
+                diskless: replication.diskless_sync && client.capa_eof,
+            },
+        };
+        let (id, updates) = replication.attach(stream.try_clone()?, client.listening_port);
+        (id, updates, catchup)
+    };
+
+    let acks = stream.try_clone()?;
+    let detaching = Arc::clone(server);
+    let (first_ack, acked) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = read_acks(acks, &detaching, id, first_ack);
+        detaching.lock().unwrap().replication.detach(id);
+    });
+
+    let result = (|| {
+        match catchup {
+            Catchup::Partial { replid, missed } => {
+                stream.write_all(format!("+CONTINUE {replid}\r\n").as_bytes())?;
+                stream.write_all(&missed)?;
+            }
+            Catchup::Full {
+                snapshot,
+                replid,
+                offset,
+                compress,
+                diskless,
+            } => {
+                stream.write_all(format!("+FULLRESYNC {replid} {offset}\r\n").as_bytes())?;
+                if diskless {
+                    let mark = new_replid();
+                    stream.write_all(format!("$EOF:{mark}\r\n").as_bytes())?;
+                    let mut out = BufWriter::new(&mut stream);
+                    rdb::write(&snapshot, &mut out, compress, &AtomicUsize::new(0))?;
+                    out.write_all(mark.as_bytes())?;
+                    out.flush()?;
+                    drop(out);
+                    drop(snapshot);
+                    // replicas may not tell the marker from writes right
+                    // after it, so those wait until the snapshot loaded
+                    acked
+                        .recv()
+                        .map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                } else {
+                    // written out like a save, so its length can go first
+                    // without holding it all in memory
+                    let temp =
+                        rdb_path.with_file_name(format!("temp-replica-{}-{id}.rdb", process::id()));
+                    let sent = File::options()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&temp)
+                        .and_then(|file| {
+                            let mut out = BufWriter::new(file);
+                            rdb::write(&snapshot, &mut out, compress, &AtomicUsize::new(0))?;
+                            drop(snapshot);
+                            let mut file = out.into_inner()?;
+                            let len = file.stream_position()?;
+                            file.rewind()?;
+                            stream.write_all(format!("${len}\r\n").as_bytes())?;
+                            io::copy(&mut file, &mut stream)?;
+                            Ok(())
+                        });
+                    let _ = fs::remove_file(&temp);
+                    sent?;
+                }
+            }
+        }
+        server.lock().unwrap().replication.online(id);
+        for update in updates {
+            stream.write_all(&update)?;
+        }
+        Ok(())
+    })();
+    server.lock().unwrap().replication.detach(id);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::store::{DurableValue, Object};
+
+    fn set(key: &str, value: &str) -> Vec<u8> {
+        command(&["SET", key, value])
+    }
+
+    #[test]
+    fn feed_selects_the_database_when_it_changes() {
+        let mut replication = Replication::default();
+        replication.feed(0, &set("a", "1"));
+        let before = replication.offset;
+        assert!(replication.backlog.is_none());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_, updates) = replication.attach(stream, None);
+        replication.feed(0, &set("a", "1"));
+        replication.feed(0, &set("b", "2"));
+        replication.feed(3, &set("c", "3"));
+
+        let streamed: Vec<u8> = updates
+            .try_iter()
+            .flat_map(|update| update.to_vec())
+            .collect();
+        let expected = [
+            command(&["SELECT", "0"]),
+            set("a", "1"),
+            set("b", "2"),
+            command(&["SELECT", "3"]),
+            set("c", "3"),
+        ]
+        .concat();
+        assert_eq!(streamed, expected);
+        assert_eq!(replication.offset, before + expected.len() as u64);
+    }
+
+    #[test]
+    fn replica_syncs_and_applies_the_stream() {
+        let mut master = Server::new(Databases::new(2));
+        master.databases.db(1).insert(
+            "snapshotted".into(),
+            DurableValue::new(Object::String(b"v".to_vec())),
+        );
+        let master = Arc::new(Mutex::new(master));
+        let port = serve_replicas(&master);
+
+        let replica = Arc::new(Mutex::new(Server::new(Databases::new(2))));
+        replica
+            .lock()
+            .unwrap()
+            .replication
+            .set_master(Some(("127.0.0.1".into(), port)));
+        cron(&replica, 0, &rdb_path("follow"));
+
+        wait_for(|| {
+            replica
+                .lock()
+                .unwrap()
+                .databases
+                .db(1)
+                .contains("snapshotted")
+        });
+        master
+            .lock()
+            .unwrap()
+            .replication
+            .feed(0, &set("streamed", "x"));
+        wait_for(|| replica.lock().unwrap().databases.db(0).contains("streamed"));
+
+        let info = master.lock().unwrap().replication.info();
+        assert!(info.contains("role:master\r\nconnected_slaves:1\r\n"));
+        assert!(info.contains(",state=online,offset="));
+        let info = replica.lock().unwrap().replication.info();
+        assert!(info.contains("role:slave\r\n"));
+        assert!(info.contains("master_link_status:up\r\n"));
+        let (replid, offset) = {
+            let master = &master.lock().unwrap().replication;
+            (master.replid.clone(), master.offset)
+        };
+        assert!(info.contains(&format!("master_replid:{replid}\r\n")));
+        assert!(info.contains(&format!("slave_repl_offset:{offset}\r\n")));
+
+        // WAIT asks the replica to acknowledge and counts it once it has
+        let client = Client {
+            woff: offset,
+            ..Client::default()
+        };
+        let wait_for_replicas = |numreplicas, timeout| RespMessage::Wait {
+            numreplicas,
+            timeout,
+        };
+        assert_eq!(
+            wait(&master, &client, wait_for_replicas(1, 0)),
+            Value::Int(1)
+        );
+        assert_eq!(master.lock().unwrap().replication.replicas[0].ack, offset);
+        assert_eq!(
+            wait(&master, &client, wait_for_replicas(2, 50)),
+            Value::Int(1)
+        );
+        // the replica has no append only file, so it never acknowledges fsyncs
+        let waitaof = RespMessage::WaitAof {
+            numlocal: 0,
+            numreplicas: 1,
+            timeout: 50,
+        };
+        assert_eq!(
+            wait(&master, &client, waitaof),
+            vec![Value::Int(0), Value::Int(0)].into()
+        );
+        assert!(matches!(
+            wait(&replica, &client, wait_for_replicas(1, 0)),
+            Value::Error(_)
+        ));
+
+        // after a blip the replica gets what it missed, not a new snapshot
+        let link = replica
+            .lock()
+            .unwrap()
+            .replication
+            .master
+            .as_mut()
+            .unwrap()
+            .stream
+            .take();
+        link.unwrap().shutdown(Shutdown::Both).unwrap();
+        wait_for(|| master.lock().unwrap().replication.replicas.is_empty());
+        {
+            let master = &mut *master.lock().unwrap();
+            master.replication.feed(0, &set("missed", "y"));
+            master.databases.db(0).insert(
+                "unreplicated".into(),
+                DurableValue::new(Object::String(b"z".to_vec())),
+            );
+        }
+        wait_for(|| {
+            cron(&replica, 0, &rdb_path("follow"));
+            replica.lock().unwrap().databases.db(0).contains("missed")
+        });
+        assert!(!replica
+            .lock()
+            .unwrap()
+            .databases
+            .db(0)
+            .contains("unreplicated"));
+
+        // stopping replication closes the link
+        replica.lock().unwrap().replication.set_master(None);
+        wait_for(|| master.lock().unwrap().replication.replicas.is_empty());
+    }
+
+    /// Accepts connections for `master`, answering the handshake like a
+    /// client connection would, then handing the link over.
+    fn serve_replicas(master: &Arc<Mutex<Server>>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let serving = Arc::clone(master);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream.try_clone().unwrap();
+                let mut client = Client::default();
+                let (replid, offset) = loop {
+                    let value = read_command(&mut reader);
+                    let raw = value.to_bytes();
+                    match RespMessage::try_from(value).unwrap() {
+                        RespMessage::Psync { replid, offset } => break (replid, offset),
+                        message => {
+                            let reply = commands::execute(
+                                &mut serving.lock().unwrap(),
+                                &mut client,
+                                message,
+                                &raw,
+                            );
+                            writer.write_all(&reply.to_bytes()).unwrap();
+                        }
+                    }
+                };
+                let serving = Arc::clone(&serving);
+                thread::spawn(move || {
+                    let path = rdb_path("master");
+                    let _ = serve_replica(stream, &serving, &client, &replid, offset, &path);
+                });
+            }
+        });
+        port
+    }
+
+    fn rdb_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("replica-{test}-{}.rdb", process::id()))
+    }
+
+    #[test]
+    fn diskless_sync_streams_the_snapshot() {
+        for load in [DisklessLoad::Disabled, DisklessLoad::Swapdb] {
+            let mut master = Server::new(Databases::new(1));
+            master.replication.set_diskless_sync(true);
+            master.databases.db(0).insert(
+                "snapshotted".into(),
+                DurableValue::new(Object::String(b"v".to_vec())),
+            );
+            let master = Arc::new(Mutex::new(master));
+            let port = serve_replicas(&master);
+
+            let path = rdb_path(&format!("diskless-{load:?}"));
+            let _ = fs::remove_file(&path);
+            let replica = Arc::new(Mutex::new(Server::new(Databases::new(1))));
+            {
+                let replication = &mut replica.lock().unwrap().replication;
+                replication.set_diskless_load(load);
+                replication.set_master(Some(("127.0.0.1".into(), port)));
+            }
+            cron(&replica, 0, &path);
+            wait_for(|| {
+                replica
+                    .lock()
+                    .unwrap()
+                    .databases
+                    .db(0)
+                    .contains("snapshotted")
+            });
+            // only loading from disk leaves the snapshot behind
+            assert_eq!(path.exists(), load == DisklessLoad::Disabled);
+
+            // writes follow the marker once the replica acknowledged
+            master
+                .lock()
+                .unwrap()
+                .replication
+                .feed(0, &set("streamed", "x"));
+            wait_for(|| replica.lock().unwrap().databases.db(0).contains("streamed"));
+            replica.lock().unwrap().replication.set_master(None);
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn reads_a_snapshot_up_to_its_eof_mark() {
+        let mark = new_replid();
+        let payload = b"REDIS0011 with the mark split across reads".repeat(3);
+        let sent = [&payload[..], mark.as_bytes(), b"*1\r\n$4\r\nPING\r\n"].concat();
+        for capacity in [1, 7, 40, 41, 1024] {
+            let mut reader = BufReader::with_capacity(capacity, &sent[..]);
+            let mut snapshot = UntilMark {
+                reader: &mut reader,
+                mark: mark.as_bytes().to_vec(),
+                held: Vec::new(),
+                found: false,
+            };
+            let mut read = Vec::new();
+            snapshot.read_to_end(&mut read).unwrap();
+            assert_eq!(read, payload);
+
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, b"*1\r\n$4\r\nPING\r\n");
+        }
+
+        let mut truncated = &payload[..];
+        let mut snapshot = UntilMark {
+            reader: &mut truncated,
+            mark: mark.as_bytes().to_vec(),
+            held: Vec::new(),
+            found: false,
+        };
+        assert!(snapshot.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    fn read_command(reader: &mut impl BufRead) -> Value {
+        loop {
+            let buffered = reader.fill_buf().unwrap();
+            if let Ok((rest, value)) = parser(buffered) {
+                let consumed = buffered.len() - rest.len();
+                reader.consume(consumed);
+                return value;
+            }
+        }
+    }
+
+    #[test]
+    fn transactions_are_propagated_whole() {
+        let mut server = Server::new(Databases::new(1));
+        server.replication.create_backlog();
+        let mut client = Client::default();
+        let mut run = |args: &[&str]| {
+            let raw = command(args);
+            let (_, value) = parser(&raw).unwrap();
+            let message = RespMessage::try_from(value).unwrap();
+            commands::execute(&mut server, &mut client, message, &raw);
+        };
+
+        // nothing goes out for a transaction that changes nothing
+        run(&["multi"]);
+        run(&["get", "k"]);
+        run(&["del", "k"]);
+        run(&["exec"]);
+        run(&["multi"]);
+        run(&["get", "k"]);
+        run(&["set", "k", "v"]);
+        run(&["del", "missing"]);
+        run(&["set", "k2", "v"]);
+        run(&["exec"]);
+
+        let replid = server.replication.replid.clone();
+        assert_eq!(
+            server.replication.since(&replid, 1),
+            Some(
+                [
+                    command(&["SELECT", "0"]),
+                    command(&["MULTI"]),
+                    command(&["set", "k", "v"]),
+                    command(&["set", "k2", "v"]),
+                    command(&["EXEC"]),
+                ]
+                .concat()
+            )
+        );
+    }
+
+    #[test]
+    fn backlog_serves_replicas_that_fall_behind() {
+        let mut replication = Replication::default();
+        replication.set_backlog_size(64);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        replication.attach(stream, None);
+
+        replication.feed(0, &set("a", "1"));
+        let after_a = replication.offset;
+        replication.feed(0, &set("b", "2"));
+        let replid = replication.replid.clone();
+        assert_eq!(
+            replication.since(&replid, after_a as i64 + 1),
+            Some(set("b", "2"))
+        );
+        assert_eq!(
+            replication.since(&replid, replication.offset as i64 + 1),
+            Some(Vec::new())
+        );
+        assert_eq!(replication.since("other", after_a as i64 + 1), None);
+        assert_eq!(replication.since(&replid, -1), None);
+
+        // older writes fall out
+        replication.feed(0, &set("c", "3"));
+        assert_eq!(replication.since(&replid, 1), None);
+
+        // a promoted replica still knows the history it followed
+        replication.shift_replid(new_replid());
+        let end = replication.offset as i64 + 1;
+        replication.feed(0, &set("d", "4"));
+        assert_eq!(replication.since(&replid, end), Some(set("d", "4")));
+        assert_eq!(replication.since(&replid, end + 1), None);
+        let info = replication.info();
+        assert!(info.contains(&format!("master_replid2:{replid}\r\n")));
+        assert!(info.contains("repl_backlog_histlen:64\r\n"));
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
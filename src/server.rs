@@ -0,0 +1,24 @@
+use crate::persistence::Persistence;
+use crate::pubsub::PubSub;
+use crate::replication::Replication;
+use crate::store::Databases;
+
+/// State shared by every connection, behind a single lock.
+#[derive(Debug)]
+pub struct Server {
+    pub databases: Databases,
+    pub persistence: Persistence,
+    pub replication: Replication,
+    pub pubsub: PubSub,
+}
+
+impl Server {
+    pub fn new(databases: Databases) -> Self {
+        Self {
+            databases,
+            persistence: Persistence::default(),
+            replication: Replication::default(),
+            pubsub: PubSub::default(),
+        }
+    }
+}
@@ -0,0 +1,488 @@
+mod stream;
+mod zset;
+
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap, HashSet, VecDeque,
+    },
+    hash::{BuildHasher, Hash, Hasher},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::parser::{rdb, resp::Value};
+use crate::pubsub::{EventClass, KeyEvent};
+
+pub use stream::{Consumer, ConsumerGroup, Fields, PendingEntry, Stream, StreamId};
+pub use zset::SortedSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    ZSet(SortedSet),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Stream(Stream),
+    Module(ModuleValue),
+}
+
+/// A value of a module data type, kept in the form the module serialized it
+/// in so that it is written back unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleValue {
+    /// The name the module registered the type under.
+    pub name: String,
+    /// The type name and encoding version, packed the way RDB files store them.
+    pub id: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Object {
+    pub fn type_name(&self) -> &str {
+        match self {
+            Object::String(_) => "string",
+            Object::List(_) => "list",
+            Object::Set(_) => "set",
+            Object::ZSet(_) => "zset",
+            Object::Hash(_) => "hash",
+            Object::Stream(_) => "stream",
+            Object::Module(value) => &value.name,
+        }
+    }
+
+    /// Roughly how much work dropping the value takes, in allocations.
+    fn free_effort(&self) -> usize {
+        match self {
+            Object::String(_) => 1,
+            Object::List(list) => list.len(),
+            Object::Set(set) => set.len(),
+            Object::ZSet(set) => set.len(),
+            Object::Hash(hash) => hash.len(),
+            Object::Stream(stream) => stream.len(),
+            Object::Module(_) => 1,
+        }
+    }
+}
+
+impl From<&rdb::Value> for Object {
+    fn from(value: &rdb::Value) -> Self {
+        match value {
+            rdb::Value::String(s) => Object::String(s.to_bytes()),
+            rdb::Value::List(items) => {
+                Object::List(items.iter().map(|item| item.to_bytes()).collect())
+            }
+            rdb::Value::Set(members) => {
+                Object::Set(members.iter().map(|member| member.to_bytes()).collect())
+            }
+            rdb::Value::SortedSet(members) => Object::ZSet(
+                members
+                    .iter()
+                    .map(|(member, score)| (member.to_bytes(), *score))
+                    .collect(),
+            ),
+            rdb::Value::Hash(fields) => Object::Hash(
+                fields
+                    .iter()
+                    .map(|(field, value)| (field.to_bytes(), value.to_bytes()))
+                    .collect(),
+            ),
+            rdb::Value::Stream(stream) => Object::Stream(stream.clone()),
+            rdb::Value::Module { id, payload } => Object::Module(ModuleValue {
+                name: rdb::module_type_name(*id),
+                id: *id,
+                payload: payload.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DurableValue {
+    pub val: Object,
+    pub expiration: Expiration,
+    pub usage: Usage,
+}
+
+/// What eviction policies know about how a key is used, kept from snapshots
+/// saved under an LRU or LFU `maxmemory-policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Usage {
+    #[default]
+    Unknown,
+    /// The key was last used at this time.
+    Lru(SystemTime),
+    /// The key's logarithmic access frequency counter.
+    Lfu(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Expiration {
+    #[default]
+    Empty,
+    Date(SystemTime),
+    Period {
+        duration: Duration,
+        insert_at: Instant,
+    },
+}
+
+impl Expiration {
+    pub fn elapsed(&self) -> bool {
+        match self {
+            Expiration::Empty => false,
+            Expiration::Date(time) => SystemTime::now() >= *time,
+            Expiration::Period {
+                duration,
+                insert_at,
+            } => insert_at.elapsed() > *duration,
+        }
+    }
+
+    /// The wall clock time the key expires at, if it has an expiration.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        match self {
+            Expiration::Empty => None,
+            Expiration::Date(time) => Some(*time),
+            Expiration::Period {
+                duration,
+                insert_at,
+            } => Some(SystemTime::now() + duration.saturating_sub(insert_at.elapsed())),
+        }
+    }
+}
+
+impl DurableValue {
+    pub fn new(val: Object) -> Self {
+        Self {
+            val,
+            expiration: Expiration::Empty,
+            usage: Usage::Unknown,
+        }
+    }
+}
+
+/// Returned when a command is run against a key holding a value of another type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WrongType;
+
+impl From<WrongType> for Value {
+    fn from(_: WrongType) -> Self {
+        Value::error(
+            "WRONGTYPE",
+            "Operation against a key holding the wrong kind of value",
+        )
+    }
+}
+
+/// Values cheaper than this to drop are freed inline even when unlinked.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+static LAZYFREE: OnceLock<Mutex<Sender<Box<dyn Send>>>> = OnceLock::new();
+
+/// Drops `value` on a background thread.
+fn free_in_background(value: Box<dyn Send>) {
+    let sender = LAZYFREE.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Box<dyn Send>>();
+        thread::spawn(move || receiver.into_iter().for_each(drop));
+        Mutex::new(sender)
+    });
+    // the receiving thread lives as long as the process, so this cannot fail
+    let _ = sender.lock().unwrap().send(value);
+}
+
+/// Drops `value`, handing it to a background thread when freeing it could
+/// stall the connection that removed it. A value a snapshot still shares is
+/// freed by the snapshot instead.
+pub fn lazy_free(value: Arc<DurableValue>) {
+    if Arc::strong_count(&value) == 1 && value.val.free_effort() > LAZYFREE_THRESHOLD {
+        free_in_background(Box::new(value));
+    }
+}
+
+/// A pseudo random index below `len`, which must not be zero.
+pub fn random_index(len: usize) -> usize {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish() as usize % len
+}
+
+/// How many parts each database is split into. Parts are shared with
+/// snapshots and copied on the first write after one is taken, so a write
+/// during a background save copies a small slice of the keyspace, not all of it.
+const SHARDS: usize = 256;
+
+type Shard = HashMap<String, Arc<DurableValue>>;
+
+fn shard_index(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+/// The keyspace shared by every connection. Expired keys are removed lazily,
+/// the first time they are looked up. Cloning a store is cheap and gives a
+/// point-in-time copy of it.
+#[derive(Debug, Clone)]
+pub struct Store {
+    shards: Vec<Arc<Shard>>,
+    /// How many keys the shards hold, kept up to date as they change.
+    len: usize,
+    /// What was done to keys since they were last taken, for keyspace
+    /// notifications.
+    events: Vec<KeyEvent>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Arc::default()).collect(),
+            len: 0,
+            events: Vec::new(),
+        }
+    }
+}
+
+impl FromIterator<(String, DurableValue)> for Store {
+    fn from_iter<T: IntoIterator<Item = (String, DurableValue)>>(iter: T) -> Self {
+        let mut store = Store::default();
+        for (key, value) in iter {
+            store.insert(key, value);
+        }
+        store
+    }
+}
+
+impl Store {
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[shard_index(key)]
+    }
+
+    /// The shard holding `key`, copied first if a snapshot still shares it.
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        Arc::make_mut(&mut self.shards[shard_index(key)])
+    }
+
+    fn expire_if_needed(&mut self, key: &str) {
+        if self
+            .shard(key)
+            .get(key)
+            .is_some_and(|entry| entry.expiration.elapsed())
+        {
+            self.shard_mut(key).remove(key);
+            self.len -= 1;
+            self.notify(EventClass::Expired, "expired", key);
+        }
+    }
+
+    /// Records that `event` happened to `key`.
+    pub fn notify(&mut self, class: EventClass, event: &'static str, key: &str) {
+        self.events.push(KeyEvent {
+            class,
+            event,
+            key: key.to_string(),
+        });
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&DurableValue> {
+        self.expire_if_needed(key);
+        self.shard(key).get(key).map(|entry| &**entry)
+    }
+
+    pub fn insert(&mut self, key: String, value: impl Into<Arc<DurableValue>>) {
+        if self.shard_mut(&key).insert(key, value.into()).is_none() {
+            self.len += 1;
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Arc<DurableValue>> {
+        self.expire_if_needed(key);
+        let removed = self.shard_mut(key).remove(key);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub fn contains(&mut self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Marks `key` as just used, returning whether it exists.
+    pub fn touch(&mut self, key: &str) -> bool {
+        if !self.contains(key) {
+            return false;
+        }
+        if let Some(entry) = self.shard_mut(key).get_mut(key) {
+            let entry = Arc::make_mut(entry);
+            entry.usage = match entry.usage {
+                Usage::Lfu(counter) => Usage::Lfu(counter.saturating_add(1)),
+                Usage::Unknown | Usage::Lru(_) => Usage::Lru(SystemTime::now()),
+            };
+        }
+        true
+    }
+
+    /// How many keys there are, counting expired ones no lookup removed
+    /// yet, as Redis does.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// A key picked at random, removing the expired ones picked on the way.
+    pub fn random_key(&mut self) -> Option<String> {
+        while self.len > 0 {
+            let mut index = random_index(self.len);
+            let shard = self.shards.iter().find(|shard| {
+                let found = index < shard.len();
+                if !found {
+                    index -= shard.len();
+                }
+                found
+            })?;
+            let (key, entry) = shard.iter().nth(index)?;
+            if !entry.expiration.elapsed() {
+                return Some(key.clone());
+            }
+            let key = key.clone();
+            self.expire_if_needed(&key);
+        }
+        None
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Every key that has not expired, with its value.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &DurableValue)> + '_ {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .filter(|(_, entry)| !entry.expiration.elapsed())
+            .map(|(key, entry)| (key, &**entry))
+    }
+
+    /// The value at `key` for writing, created with `default` if the key does not exist.
+    fn value_mut(&mut self, key: &str, default: impl FnOnce() -> Object) -> &mut Object {
+        self.expire_if_needed(key);
+        if !self.shard(key).contains_key(key) {
+            self.len += 1;
+        }
+        let entry = self
+            .shard_mut(key)
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(DurableValue::new(default())));
+        &mut Arc::make_mut(entry).val
+    }
+
+    pub fn get_string(&mut self, key: &str) -> Result<Option<&Vec<u8>>, WrongType> {
+        match self.get(key).map(|entry| &entry.val) {
+            Some(Object::String(bytes)) => Ok(Some(bytes)),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// The string stored at `key`, created empty if the key does not exist.
+    pub fn string_mut(&mut self, key: &str) -> Result<&mut Vec<u8>, WrongType> {
+        match self.value_mut(key, || Object::String(Vec::new())) {
+            Object::String(bytes) => Ok(bytes),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn get_zset(&mut self, key: &str) -> Result<Option<&SortedSet>, WrongType> {
+        match self.get(key).map(|entry| &entry.val) {
+            Some(Object::ZSet(set)) => Ok(Some(set)),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// The sorted set stored at `key`, created empty if the key does not exist.
+    pub fn zset_mut(&mut self, key: &str) -> Result<&mut SortedSet, WrongType> {
+        match self.value_mut(key, || Object::ZSet(SortedSet::default())) {
+            Object::ZSet(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+}
+
+/// The numbered databases clients `SELECT` between. Cloning them is cheap
+/// and gives a point-in-time snapshot of the whole keyspace.
+#[derive(Debug, Clone)]
+pub struct Databases {
+    dbs: Vec<Store>,
+}
+
+impl Databases {
+    pub fn new(count: usize) -> Self {
+        Self {
+            dbs: (0..count).map(|_| Store::default()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    /// Every database with its index.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Store)> + '_ {
+        self.dbs.iter().enumerate()
+    }
+
+    pub fn db(&mut self, index: usize) -> &mut Store {
+        &mut self.dbs[index]
+    }
+
+    /// Two distinct databases at once, in the order asked for.
+    pub fn pair(&mut self, a: usize, b: usize) -> (&mut Store, &mut Store) {
+        assert_ne!(a, b, "a database cannot be borrowed twice");
+        if a < b {
+            let (left, right) = self.dbs.split_at_mut(b);
+            (&mut left[a], &mut right[0])
+        } else {
+            let (left, right) = self.dbs.split_at_mut(a);
+            (&mut right[0], &mut left[b])
+        }
+    }
+
+    /// The events recorded in every database since the last call, each with
+    /// the index of its database.
+    pub fn take_events(&mut self) -> Vec<(usize, KeyEvent)> {
+        let mut events = Vec::new();
+        for (index, db) in self.dbs.iter_mut().enumerate() {
+            events.extend(db.events.drain(..).map(|event| (index, event)));
+        }
+        events
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.dbs.swap(a, b);
+    }
+
+    /// Empties database `index`, dropping its keys on a background thread when `lazy`.
+    pub fn flush(&mut self, index: usize, lazy: bool) {
+        let old = std::mem::take(&mut self.dbs[index]);
+        if lazy {
+            free_in_background(Box::new(old));
+        }
+    }
+
+    pub fn flush_all(&mut self, lazy: bool) {
+        for index in 0..self.dbs.len() {
+            self.flush(index, lazy);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+/// Stream entries are ordered by a millisecond time and a sequence number
+/// telling apart entries added in the same millisecond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+/// The fields and values of an entry, in the order they were added.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An entry delivered to a consumer of a group that it has not acknowledged yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Vec<u8>,
+    /// When it was last delivered, in milliseconds since the epoch.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    pub name: Vec<u8>,
+    /// When the consumer last read or claimed entries, in milliseconds since the epoch.
+    pub seen_time: u64,
+    /// When the consumer last got entries delivered, in milliseconds since the epoch.
+    pub active_time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: Vec<u8>,
+    /// The last entry delivered to the group.
+    pub last_id: StreamId,
+    /// How many entries the group has read, when that is known.
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// An append only log of entries, with the consumer groups reading it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    /// The last ID handed out, which deleted entries may have had.
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    /// The largest ID among deleted entries.
+    pub max_deleted_id: StreamId,
+    /// How many entries were ever added, deleted ones included.
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+};
+
+/// A score ordered the way sorted sets order them, with `f64::total_cmp`.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then lexicographically, with constant time score lookups.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    /// Sets the score of `member`, returning its previous score if it was already present.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Members in ascending score order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> + '_ {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Members with `min <= score < max`, in ascending score order.
+    pub fn range(&self, min: f64, max: f64) -> impl Iterator<Item = (&[u8], f64)> + '_ {
+        self.ordered
+            .range((Bound::Included((Score(min), Vec::new())), Bound::Unbounded))
+            .take_while(move |(score, _)| score.0 < max)
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+}
+
+impl FromIterator<(Vec<u8>, f64)> for SortedSet {
+    fn from_iter<T: IntoIterator<Item = (Vec<u8>, f64)>>(iter: T) -> Self {
+        let mut set = SortedSet::default();
+        for (member, score) in iter {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn insert_replaces_scores() {
+        let mut set = SortedSet::default();
+
+        assert_eq!(set.insert(b"a".to_vec(), 2.0), None);
+        assert_eq!(set.insert(b"b".to_vec(), 1.0), None);
+        assert_eq!(set.insert(b"a".to_vec(), 0.5), Some(2.0));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.score(b"a"), Some(0.5));
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            vec![(&b"a"[..], 0.5), (&b"b"[..], 1.0)]
+        );
+    }
+
+    #[test]
+    fn range_is_half_open() {
+        let set: SortedSet = [
+            (b"a".to_vec(), 1.0),
+            (b"b".to_vec(), 2.0),
+            (b"c".to_vec(), 3.0),
+        ]
+        .into_iter()
+        .collect();
+
+        let members = |min, max| set.range(min, max).map(|(m, _)| m).collect::<Vec<_>>();
+        assert_eq!(members(1.0, 3.0), vec![&b"a"[..], &b"b"[..]]);
+        assert_eq!(members(1.5, 10.0), vec![&b"b"[..], &b"c"[..]]);
+        assert!(members(4.0, 5.0).is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+//! Transactions: the commands a client sends between `MULTI` and `EXEC` are
+//! queued, then run together with no other client's commands in between.
+
+use crate::client::Client;
+use crate::commands;
+use crate::message::{CommandError, RespMessage};
+use crate::parser::resp::Value;
+use crate::pubsub;
+use crate::replication;
+use crate::server::Server;
+
+/// How a client's writes are passed on to the append only file and replicas.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Propagation {
+    /// Each as it runs.
+    #[default]
+    Direct,
+    /// As part of the transaction `EXEC` is running, which has not written yet.
+    Pending,
+    /// After the `MULTI` sent ahead of the running transaction's first write,
+    /// to be closed with an `EXEC` once it is done.
+    Wrapped,
+}
+
+/// What a client queued since `MULTI`.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    /// Each command with the bytes it was sent as.
+    queued: Vec<(RespMessage, Vec<u8>)>,
+    /// Whether a command was refused instead of queued, which makes `EXEC`
+    /// run nothing.
+    aborted: bool,
+}
+
+impl Transaction {
+    pub fn queue(&mut self, message: RespMessage, raw: &[u8]) {
+        self.queued.push((message, raw.to_vec()));
+    }
+
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+}
+
+/// Answers `EXEC`, running what `client` queued and replying with each
+/// command's reply in turn. The caller holds the server's lock throughout.
+pub fn exec(server: &mut Server, client: &mut Client) -> Value {
+    let Some(transaction) = client.transaction.take() else {
+        return CommandError::Invalid("EXEC without MULTI".into()).into();
+    };
+    if transaction.aborted {
+        return Value::error(
+            "EXECABORT",
+            "Transaction discarded because of previous errors.",
+        );
+    }
+    client.propagation = Propagation::Pending;
+    let replies: Vec<Value> = transaction
+        .queued
+        .into_iter()
+        .map(|(message, raw)| match message {
+            // the lock is held, so they count what is there already
+            RespMessage::Wait { .. } | RespMessage::WaitAof { .. } => {
+                replication::wait_now(server, client, message)
+            }
+            message if message.is_subscription() => {
+                let mut replies = pubsub::subscriptions(server, client, message);
+                if replies.len() == 1 {
+                    replies.remove(0)
+                } else {
+                    replies.into()
+                }
+            }
+            message => commands::execute(server, client, message, &raw),
+        })
+        .collect();
+    // so the transaction's writes are applied all together or not at all
+    if client.propagation == Propagation::Wrapped {
+        let exec = replication::command(&["EXEC"]);
+        commands::propagate(server, client, client.db, &exec, false);
+    }
+    client.propagation = Propagation::Direct;
+    replies.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::resp::BulkString;
+    use crate::store::Databases;
+
+    fn run(server: &mut Server, client: &mut Client, args: &[&str]) -> Value {
+        let value: Value = args
+            .iter()
+            .map(|arg| BulkString::from(*arg).into())
+            .collect::<Vec<Value>>()
+            .into();
+        match RespMessage::try_from(value) {
+            Ok(message) => commands::execute(server, client, message, b""),
+            Err(err) => {
+                if let Some(transaction) = &mut client.transaction {
+                    transaction.abort();
+                }
+                err.into()
+            }
+        }
+    }
+
+    fn queued() -> Value {
+        Value::String("QUEUED".into())
+    }
+
+    #[test]
+    fn queues_commands_until_exec() {
+        let mut server = Server::new(Databases::new(1));
+        let mut client = Client::default();
+        let mut other = Client::default();
+
+        assert_eq!(run(&mut server, &mut client, &["multi"]), Value::ok());
+        assert_eq!(
+            run(&mut server, &mut client, &["multi"]),
+            CommandError::Invalid("MULTI calls can not be nested".into()).into()
+        );
+        assert_eq!(run(&mut server, &mut client, &["set", "k", "v"]), queued());
+        assert_eq!(run(&mut server, &mut client, &["get", "k"]), queued());
+        assert_eq!(run(&mut server, &mut client, &["wait", "1", "0"]), queued());
+        // nothing has run yet
+        assert_eq!(
+            run(&mut server, &mut other, &["get", "k"]),
+            BulkString::Null.into()
+        );
+
+        assert_eq!(
+            exec(&mut server, &mut client),
+            vec![Value::ok(), BulkString::from("v").into(), Value::Int(0)].into()
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["exec"]),
+            CommandError::Invalid("EXEC without MULTI".into()).into()
+        );
+    }
+
+    #[test]
+    fn discards_and_aborts_transactions() {
+        let mut server = Server::new(Databases::new(1));
+        let mut client = Client::default();
+
+        run(&mut server, &mut client, &["multi"]);
+        run(&mut server, &mut client, &["set", "k", "v"]);
+        assert_eq!(run(&mut server, &mut client, &["discard"]), Value::ok());
+        assert_eq!(
+            run(&mut server, &mut client, &["discard"]),
+            CommandError::Invalid("DISCARD without MULTI".into()).into()
+        );
+
+        // a command that does not parse spoils the whole transaction
+        run(&mut server, &mut client, &["multi"]);
+        run(&mut server, &mut client, &["set", "k", "v"]);
+        assert!(matches!(
+            run(&mut server, &mut client, &["get"]),
+            Value::Error(_)
+        ));
+        assert_eq!(
+            run(&mut server, &mut client, &["exec"]),
+            Value::error(
+                "EXECABORT",
+                "Transaction discarded because of previous errors."
+            )
+        );
+        assert_eq!(
+            run(&mut server, &mut client, &["get", "k"]),
+            BulkString::Null.into()
+        );
+
+        // RESET leaves the transaction too
+        run(&mut server, &mut client, &["multi"]);
+        run(&mut server, &mut client, &["reset"]);
+        assert_eq!(
+            run(&mut server, &mut client, &["set", "k", "v"]),
+            Value::ok()
+        );
+    }
+}